@@ -1,9 +1,44 @@
+use std::collections::HashMap;
+
 use prost_build::Config;
-use prost_reflect::{DescriptorPool, FieldDescriptor, MessageDescriptor};
+use prost_reflect::{DescriptorPool, FieldDescriptor, Kind, MessageDescriptor};
 
 pub struct OptionsParser<'c> {
     config: &'c mut Config,
     pub descriptor_pool: DescriptorPool,
+    /// Resources declared with `google.api.resource`, by proto file name.
+    pub resources: HashMap<String, Vec<Resource>>,
+    /// Fields with a Rust type set in `pl.api.rust_field_options`, by proto file name.
+    pub typed_fields: HashMap<String, Vec<TypedField>>,
+}
+
+/// A resource declared with the `google.api.resource` message option.
+#[derive(Debug, Clone)]
+pub struct Resource {
+    /// The resource type, e.g. `grs.patch.labs/Repository`.
+    pub type_: String,
+    /// The name patterns of the resource, e.g. `repositories/{repository}`.
+    pub patterns: Vec<String>,
+}
+
+/// A string field with a Rust type set through `pl.api.rust_field_options`.
+#[derive(Debug, Clone)]
+pub struct TypedField {
+    /// Name of the message, relative to its package, e.g. `Reference`.
+    pub message: String,
+    /// Name of the field, e.g. `repository`.
+    pub field: String,
+    /// The Rust type of the field, e.g. `RepositoryName`.
+    pub rust_type: String,
+}
+
+impl Resource {
+    /// The type name of the resource, without the service, e.g. `Repository`.
+    pub fn type_name(&self) -> &str {
+        self.type_
+            .rsplit_once('/')
+            .map_or(&*self.type_, |(_, name)| name)
+    }
 }
 
 impl<'c> OptionsParser<'c> {
@@ -11,6 +46,8 @@ impl<'c> OptionsParser<'c> {
         OptionsParser {
             config,
             descriptor_pool: DescriptorPool::new(),
+            resources: HashMap::new(),
+            typed_fields: HashMap::new(),
         }
     }
 
@@ -39,9 +76,30 @@ impl<'c> OptionsParser<'c> {
         for (ext, value) in msg.options().extensions() {
             match ext.full_name() {
                 "google.api.resource" => {
-                    value
+                    let opt_value = value
                         .as_message()
                         .expect("invalid value for option (google.api.resource)");
+
+                    let type_ = opt_value
+                        .get_field_by_name("type")
+                        .and_then(|ty| ty.as_str().map(str::to_string))
+                        .unwrap_or_default();
+                    let patterns = opt_value
+                        .get_field_by_name("pattern")
+                        .and_then(|p| {
+                            p.as_list().map(|patterns| {
+                                patterns
+                                    .iter()
+                                    .filter_map(|p| p.as_str().map(str::to_string))
+                                    .collect()
+                            })
+                        })
+                        .unwrap_or_default();
+
+                    self.resources
+                        .entry(msg.parent_file().name().to_string())
+                        .or_default()
+                        .push(Resource { type_, patterns });
                 }
                 "pl.api.rust_message_options" => {
                     let opt_value = value
@@ -75,7 +133,9 @@ impl<'c> OptionsParser<'c> {
             match ext.full_name() {
                 // For now, do nothing with this option.
                 "google.api.field_behavior" => {}
-                // We can't change field type :/
+                // We can't change field type :/, prost only accepts `String` in
+                // string fields. Typed accessors are generated through
+                // `(pl.api.rust_field_options).extern_type` instead.
                 "google.api.resource_reference" => {
                     // We don't care about the type, parse to validate value.
                     value
@@ -95,6 +155,12 @@ impl<'c> OptionsParser<'c> {
                         }
                     }
 
+                    if let Some(ext_ty) = msg.get_field_by_name("extern_type") {
+                        if let Some(ext_ty) = ext_ty.as_str().filter(|ty| !ty.is_empty()) {
+                            self.add_typed_field(field, ext_ty);
+                        }
+                    }
+
                     if let Some(boxed) = msg.get_field_by_name("boxed") {
                         if boxed.as_bool() == Some(true) {
                             self.config.boxed(&field_name);
@@ -105,4 +171,28 @@ impl<'c> OptionsParser<'c> {
             }
         }
     }
+
+    fn add_typed_field(&mut self, field: &FieldDescriptor, rust_type: &str) {
+        assert!(
+            matches!(field.kind(), Kind::String) && !field.is_list() && !field.is_map(),
+            "(pl.api.rust_field_options).extern_type is only supported in singular string fields, found in {}",
+            field.full_name()
+        );
+
+        let msg = field.containing_message();
+        let message = msg
+            .full_name()
+            .strip_prefix(msg.package_name())
+            .map_or(msg.full_name(), |name| name.trim_start_matches('.'))
+            .to_string();
+
+        self.typed_fields
+            .entry(msg.parent_file().name().to_string())
+            .or_default()
+            .push(TypedField {
+                message,
+                field: field.name().to_string(),
+                rust_type: rust_type.to_string(),
+            });
+    }
 }
@@ -1,11 +1,12 @@
 use std::{
     collections::{HashMap, HashSet},
+    fmt::Write as _,
     io::{BufWriter, Write},
     path::PathBuf,
 };
 
-use heck::ToSnakeCase;
-use pl_gen_options_parser::OptionsParser;
+use heck::{ToSnakeCase, ToUpperCamelCase};
+use pl_gen_options_parser::{OptionsParser, Resource, TypedField};
 use prost_build::{Config, Module};
 use prost_reflect::prost_types::FileDescriptorProto;
 
@@ -20,14 +21,21 @@ pub struct ProtoProcessor {
     options: ProcessorOptions,
     config: Config,
     descriptor_sets: Vec<FileDescriptorProto>,
+    resources: HashMap<String, Vec<Resource>>,
+    typed_fields: HashMap<String, Vec<TypedField>>,
 }
 
+/// Crate providing the runtime support for typed resource names.
+const RESOURCE_NAME_CRATE: &str = "pl_api_resource_name";
+
 impl ProtoProcessor {
     pub fn new(config: Config, opts: ProcessorOptions) -> Self {
         Self {
             options: opts,
             config,
             descriptor_sets: vec![],
+            resources: HashMap::new(),
+            typed_fields: HashMap::new(),
         }
     }
 
@@ -51,6 +59,8 @@ impl ProtoProcessor {
         }
 
         opts_parser.process_files();
+        self.resources = std::mem::take(&mut opts_parser.resources);
+        self.typed_fields = std::mem::take(&mut opts_parser.typed_fields);
 
         for file in opts_parser.descriptor_pool.files().skip(direct_offset) {
             let file_proto = file.file_descriptor_proto().clone();
@@ -100,16 +110,42 @@ impl ProtoProcessor {
         writeln!(output, "}}").unwrap();
         writeln!(output, "pub(crate) use self::imports::*;").unwrap();
 
+        // Typed names are only generated for libraries depending on the
+        // resource name crate, as the generated code uses it.
+        let typed_names = self
+            .options
+            .extern_crates
+            .iter()
+            .any(|c| c == RESOURCE_NAME_CRATE);
+
         let mut module_tree = ModuleTree::default();
         for file_descriptor in self.descriptor_sets {
             let module = Module::from_protobuf_package_name(file_descriptor.package());
+            let mut resources = self
+                .resources
+                .remove(file_descriptor.name())
+                .unwrap_or_default();
+            let mut typed_fields = self
+                .typed_fields
+                .remove(file_descriptor.name())
+                .unwrap_or_default();
 
             let buffers = self
                 .config
                 .generate(vec![(module, file_descriptor)])
                 .unwrap();
 
-            for (module, buf) in buffers {
+            for (module, mut buf) in buffers {
+                if typed_names {
+                    for resource in resources.drain(..) {
+                        write_typed_name(&mut buf, &resource);
+                    }
+                }
+
+                for field in typed_fields.drain(..) {
+                    write_typed_field(&mut buf, &field);
+                }
+
                 module_tree.insert_module_buf(&module, buf);
             }
         }
@@ -120,6 +156,218 @@ impl ProtoProcessor {
     }
 }
 
+/// Write a typed name struct for a resource, e.g. `RepositoryName { repository }`
+/// for the `repositories/{repository}` pattern.
+///
+/// # Panics
+///
+/// Panics if the resource doesn't have exactly one pattern, as a typed name
+/// can't represent several of them.
+fn write_typed_name(buf: &mut String, resource: &Resource) {
+    let [pattern] = &resource.patterns[..] else {
+        panic!(
+            "resource `{}` has {} name patterns, typed names require exactly one",
+            resource.type_,
+            resource.patterns.len()
+        );
+    };
+
+    let name = format!("{}Name", resource.type_name());
+    let parts = pattern
+        .split('/')
+        .map(
+            |part| match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                Some(variable) => Err(variable),
+                None => Ok(part),
+            },
+        )
+        .collect::<Vec<_>>();
+    let variables = parts.iter().filter_map(|p| p.err()).collect::<Vec<_>>();
+    let fields = variables
+        .iter()
+        .copied()
+        .map(field_ident)
+        .collect::<Vec<_>>();
+
+    let krate = RESOURCE_NAME_CRATE;
+    let type_ = &resource.type_;
+
+    let _ = writeln!(buf, "/// The name of a `{type_}` resource.");
+    let _ = writeln!(buf, "///");
+    let _ = writeln!(
+        buf,
+        "/// Pattern: `{pattern}`. Variables hold unescaped IDs."
+    );
+    let _ = writeln!(
+        buf,
+        "#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]"
+    );
+    let _ = writeln!(buf, "pub struct {name} {{");
+    for field in &fields {
+        let _ = writeln!(buf, "    pub {field}: ::std::string::String,");
+    }
+    let _ = writeln!(buf, "}}");
+
+    let _ = writeln!(buf, "impl ::{krate}::TypedResourceName for {name} {{");
+    let _ = writeln!(buf, "    const RESOURCE_TYPE: &'static str = {type_:?};");
+    let _ = writeln!(buf, "    const PATTERN: &'static str = {pattern:?};");
+    let _ = writeln!(
+        buf,
+        "    fn from_resource_name(name: &::{krate}::ResourceName) -> ::std::option::Option<Self> {{"
+    );
+    let _ = writeln!(buf, "        let mut parts = name.as_ref().split('/');");
+    let mut fields_iter = fields.iter();
+    for part in &parts {
+        match part {
+            Ok(literal) => {
+                let _ = writeln!(buf, "        if parts.next()? != {literal:?} {{");
+                let _ = writeln!(buf, "            return ::std::option::Option::None;");
+                let _ = writeln!(buf, "        }}");
+            }
+            Err(_) => {
+                let field = fields_iter.next().unwrap();
+                let _ = writeln!(
+                    buf,
+                    "        let {field} = ::{krate}::unescape_id(parts.next()?).into_owned();"
+                );
+            }
+        }
+    }
+    let _ = writeln!(buf, "        if parts.next().is_some() {{");
+    let _ = writeln!(buf, "            return ::std::option::Option::None;");
+    let _ = writeln!(buf, "        }}");
+    let _ = writeln!(
+        buf,
+        "        ::std::option::Option::Some(Self {{ {} }})",
+        fields.join(", ")
+    );
+    let _ = writeln!(buf, "    }}");
+    let _ = writeln!(
+        buf,
+        "    fn variables(&self) -> ::std::vec::Vec<(&'static str, &str)> {{"
+    );
+    let _ = write!(buf, "        ::std::vec![");
+    for (variable, field) in variables.iter().zip(&fields) {
+        let _ = write!(buf, "({variable:?}, &self.{field}), ");
+    }
+    let _ = writeln!(buf, "]");
+    let _ = writeln!(buf, "    }}");
+    let _ = writeln!(buf, "}}");
+
+    let format = parts
+        .iter()
+        .map(|part| match part {
+            Ok(literal) => literal.replace('{', "{{").replace('}', "}}"),
+            Err(_) => "{}".to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    let _ = writeln!(buf, "impl ::std::fmt::Display for {name} {{");
+    let _ = writeln!(
+        buf,
+        "    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {{"
+    );
+    let _ = write!(buf, "        ::std::write!(f, {format:?}");
+    for field in &fields {
+        let _ = write!(buf, ", ::{krate}::escape_id(&self.{field})");
+    }
+    let _ = writeln!(buf, ")");
+    let _ = writeln!(buf, "    }}");
+    let _ = writeln!(buf, "}}");
+
+    let _ = writeln!(buf, "impl ::std::str::FromStr for {name} {{");
+    let _ = writeln!(
+        buf,
+        "    type Err = <::{krate}::ResourceName as ::std::str::FromStr>::Err;"
+    );
+    let _ = writeln!(
+        buf,
+        "    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {{"
+    );
+    let _ = writeln!(
+        buf,
+        "        <Self as ::{krate}::TypedResourceName>::parse(\"name\", s)"
+    );
+    let _ = writeln!(buf, "    }}");
+    let _ = writeln!(buf, "}}");
+
+    let _ = writeln!(buf, "impl ::{krate}::ErrorResource for {name} {{");
+    let _ = writeln!(
+        buf,
+        "    fn resource_type(&self) -> ::std::string::String {{"
+    );
+    let _ = writeln!(
+        buf,
+        "        <Self as ::{krate}::TypedResourceName>::RESOURCE_TYPE.to_string()"
+    );
+    let _ = writeln!(buf, "    }}");
+    let _ = writeln!(
+        buf,
+        "    fn resource_name(&self) -> ::std::string::String {{"
+    );
+    let _ = writeln!(buf, "        ::std::string::ToString::to_string(self)");
+    let _ = writeln!(buf, "    }}");
+    let _ = writeln!(buf, "}}");
+}
+
+/// Get the identifier of the field holding a pattern variable, escaping Rust
+/// keywords, e.g. `r#type` for `{type}`.
+fn field_ident(variable: &str) -> String {
+    match variable {
+        // These can't be raw identifiers, add a suffix like prost does.
+        "crate" | "self" | "super" => format!("{variable}_"),
+        "abstract" | "as" | "async" | "await" | "become" | "box" | "break" | "const"
+        | "continue" | "do" | "dyn" | "else" | "enum" | "extern" | "false" | "final" | "fn"
+        | "for" | "if" | "impl" | "in" | "let" | "loop" | "macro" | "match" | "mod" | "move"
+        | "mut" | "override" | "priv" | "pub" | "ref" | "return" | "static" | "struct"
+        | "trait" | "true" | "try" | "type" | "typeof" | "unsafe" | "unsized" | "use"
+        | "virtual" | "where" | "while" | "yield" => format!("r#{variable}"),
+        _ => variable.to_string(),
+    }
+}
+
+/// Write the accessors of a string field with a Rust type, e.g. `parse_repository`
+/// and `set_repository` for a `repository` field of type `RepositoryName`.
+///
+/// The field itself is kept as a `String`: prost's derive decodes string fields
+/// through `&mut String`, and `ResourceName`'s message encoding is the one of a
+/// `google.protobuf.StringValue`, not of a string field.
+fn write_typed_field(buf: &mut String, field: &TypedField) {
+    let mut path = field.message.split('.').collect::<Vec<_>>();
+    let message = path.pop().unwrap_or_default().to_upper_camel_case();
+    let message = path
+        .iter()
+        .map(|module| module.to_snake_case())
+        .chain(std::iter::once(message))
+        .collect::<Vec<_>>()
+        .join("::");
+
+    let krate = RESOURCE_NAME_CRATE;
+    let ty = &field.rust_type;
+    let proto_name = &field.field;
+    let field = proto_name.to_snake_case();
+
+    let _ = writeln!(buf, "impl {message} {{");
+    let _ = writeln!(buf, "    /// Parse the `{proto_name}` field as a `{ty}`.");
+    let _ = writeln!(
+        buf,
+        "    pub fn parse_{field}(&self) -> ::std::result::Result<{ty}, <::{krate}::ResourceName as ::std::str::FromStr>::Err> {{"
+    );
+    let _ = writeln!(
+        buf,
+        "        <{ty} as ::{krate}::ParseResourceName>::parse_field({proto_name:?}, &self.{field})"
+    );
+    let _ = writeln!(buf, "    }}");
+    let _ = writeln!(buf, "    /// Set the `{proto_name}` field from a `{ty}`.");
+    let _ = writeln!(buf, "    pub fn set_{field}(&mut self, value: &{ty}) {{");
+    let _ = writeln!(
+        buf,
+        "        self.{field} = ::std::string::ToString::to_string(value);"
+    );
+    let _ = writeln!(buf, "    }}");
+    let _ = writeln!(buf, "}}");
+}
+
 #[derive(Default)]
 struct ModuleTree {
     roots: HashMap<String, ModuleTreeNode>,
@@ -164,3 +412,45 @@ struct ModuleTreeNode {
     bufs: Vec<String>,
     children: ModuleTree,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_name() {
+        let mut buf = String::new();
+        write_typed_name(
+            &mut buf,
+            &Resource {
+                type_: "grs.patch.labs/Branch".to_string(),
+                patterns: vec!["repositories/{repository}/branches/{branch}".to_string()],
+            },
+        );
+        write_typed_name(
+            &mut buf,
+            &Resource {
+                type_: "grs.patch.labs/Hook".to_string(),
+                patterns: vec!["types/{type}/hooks/{self}".to_string()],
+            },
+        );
+
+        assert_eq!(buf, include_str!("testdata/typed_name.golden"));
+    }
+
+    #[test]
+    #[should_panic(expected = "resource `grs.patch.labs/Repository` has 2 name patterns")]
+    fn test_multi_pattern() {
+        let mut buf = String::new();
+        write_typed_name(
+            &mut buf,
+            &Resource {
+                type_: "grs.patch.labs/Repository".to_string(),
+                patterns: vec![
+                    "users/{user}/repos/{repo}".to_string(),
+                    "orgs/{org}/repos/{repo}".to_string(),
+                ],
+            },
+        );
+    }
+}
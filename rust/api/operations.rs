@@ -0,0 +1,633 @@
+//! # Long-running operations
+//!
+//! Methods that take a long time to finish, e.g. `DeleteRepository`, return a
+//! `google.longrunning.Operation` instead of waiting for their work. This crate
+//! stores these operations, and implements the `google.longrunning.Operations`
+//! service, used by clients to check on them.
+//!
+//! Operations are created in the transaction of the request that started them,
+//! usually together with an item in a background queue. The worker processing
+//! the item then reports its progress by updating the operation metadata, and
+//! finishes it with a response or an error.
+use std::{sync::Arc, time::Duration};
+
+use foundationdb::{KeySelector, RangeOption};
+use pl_api_status::{Status, StatusOr};
+use pl_database::{Db, DbError, DbResult, Tx, Watch};
+use pl_database_layers_collection::{Collection, CollectionError};
+use prost_types::Any;
+use protos_google_longrunning_grpc::{
+    operation, operations_server::Operations, CancelOperationRequest, DeleteOperationRequest,
+    GetOperationRequest, ListOperationsRequest, ListOperationsResponse, Operation,
+    WaitOperationRequest,
+};
+use tonic::{Request, Response};
+
+/// Prefix of the names of all operations.
+const NAME_PREFIX: &str = "operations/";
+
+/// Number of operations returned by `ListOperations` if the page size isn't set.
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+/// Maximum number of operations returned by `ListOperations`.
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// How long `WaitOperation` waits if the request doesn't have a timeout.
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum time `WaitOperation` waits, regardless of the request timeout.
+const MAX_WAIT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// A store of long-running operations.
+///
+/// Operations are keyed by their ID, the last segment of their name.
+pub struct OperationStore {
+    operations: Collection<Operation>,
+}
+
+impl OperationStore {
+    /// Create a new store with a static name.
+    ///
+    /// See [`Collection::from_static`] for the constraints of the name.
+    pub fn from_static(name: &'static str) -> Self {
+        Self {
+            operations: Collection::from_static(name),
+        }
+    }
+
+    /// Create a new operation, with a random name.
+    ///
+    /// The operation is only visible after the transaction commits. Return it
+    /// from the method that started the operation.
+    pub async fn create(&self, tx: &mut Tx, metadata: Any) -> DbResult<Operation, CollectionError> {
+        let id = format!("{:032x}", rand::random::<u128>());
+        let operation = Operation {
+            name: format!("{NAME_PREFIX}{id}"),
+            metadata: Some(metadata),
+            done: false,
+            result: None,
+        };
+
+        self.operations.set(tx, &id.as_str(), &operation).await?;
+
+        Ok(operation)
+    }
+
+    /// Get an operation by its name, returning `None` if it doesn't exist.
+    pub async fn get(&self, tx: &Tx, name: &str) -> DbResult<Option<Operation>, CollectionError> {
+        match operation_id(name) {
+            Some(id) => self.operations.get(tx, &id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the metadata of an operation, e.g. to report its progress.
+    ///
+    /// Returns `false` if the operation doesn't exist or is done, e.g. because it
+    /// was cancelled. Workers should stop processing the operation in this case.
+    pub async fn update_metadata(
+        &self,
+        tx: &mut Tx,
+        name: &str,
+        metadata: Any,
+    ) -> DbResult<bool, CollectionError> {
+        self.update_pending(tx, name, |operation| operation.metadata = Some(metadata))
+            .await
+    }
+
+    /// Finish an operation with its response or error.
+    ///
+    /// Returns `false` if the operation doesn't exist or is already done.
+    pub async fn finish(
+        &self,
+        tx: &mut Tx,
+        name: &str,
+        result: Result<Any, Status>,
+    ) -> DbResult<bool, CollectionError> {
+        self.update_pending(tx, name, |operation| {
+            operation.done = true;
+            operation.result = Some(match result {
+                Ok(response) => operation::Result::Response(response),
+                Err(status) => operation::Result::Error(status.into()),
+            });
+        })
+        .await
+    }
+
+    /// Request the cancellation of an operation.
+    ///
+    /// The operation is finished with a `CANCELLED` error, which workers notice
+    /// on their next update. Returns `false` if the operation doesn't exist or
+    /// is already done.
+    pub async fn cancel(&self, tx: &mut Tx, name: &str) -> DbResult<bool, CollectionError> {
+        self.finish(
+            tx,
+            name,
+            Err(Status::cancelled("the operation was cancelled")),
+        )
+        .await
+    }
+
+    /// Delete an operation, returning `false` if it doesn't exist.
+    ///
+    /// This doesn't cancel the operation, but its result is lost.
+    pub async fn delete(&self, tx: &mut Tx, name: &str) -> DbResult<bool, CollectionError> {
+        let Some(id) = operation_id(name) else {
+            return Ok(false);
+        };
+
+        if self.operations.get(tx, &id).await?.is_none() {
+            return Ok(false);
+        }
+
+        self.operations.clear(tx, &id).await?;
+        Ok(true)
+    }
+
+    /// List up to `limit` operations, ordered by name, starting after the
+    /// operation named `after`.
+    pub async fn list(
+        &self,
+        tx: &Tx,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<Operation>, CollectionError> {
+        let subspace = self.operations.subspace();
+        let (begin, end) = subspace.range();
+
+        let begin = match after {
+            Some(name) => KeySelector::first_greater_than(subspace.pack(&name_key(name))),
+            None => KeySelector::first_greater_or_equal(begin),
+        };
+        let opts = RangeOption {
+            begin,
+            end: KeySelector::first_greater_or_equal(end),
+            limit: Some(limit),
+            ..RangeOption::default()
+        };
+
+        self.operations.range(tx, opts).await
+    }
+
+    /// Watch an operation for changes.
+    ///
+    /// See [`Tx::watch`] for how to use the returned future.
+    pub fn watch(&self, tx: &Tx, name: &str) -> Watch {
+        self.operations.watch(tx, &name_key(name))
+    }
+
+    /// Apply `f` to an operation that isn't done yet.
+    async fn update_pending(
+        &self,
+        tx: &mut Tx,
+        name: &str,
+        f: impl FnOnce(&mut Operation),
+    ) -> DbResult<bool, CollectionError> {
+        let Some(id) = operation_id(name) else {
+            return Ok(false);
+        };
+
+        match self.operations.get(tx, &id).await? {
+            Some(mut operation) if !operation.done => {
+                f(&mut operation);
+                self.operations.set(tx, &id, &operation).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// The `google.longrunning.Operations` service.
+pub struct OperationsService {
+    db: Arc<Db>,
+    store: Arc<OperationStore>,
+}
+
+impl OperationsService {
+    /// Create a new service serving the operations of `store`.
+    pub fn new(db: Arc<Db>, store: Arc<OperationStore>) -> Self {
+        Self { db, store }
+    }
+
+    async fn list(&self, request: ListOperationsRequest) -> StatusOr<ListOperationsResponse> {
+        if !request.name.is_empty() && request.name != NAME_PREFIX.trim_end_matches('/') {
+            return Err(Status::invalid_argument(format!(
+                "operations are not children of '{}'",
+                request.name
+            )));
+        }
+        if !request.filter.is_empty() {
+            return Err(Status::invalid_argument("filters are not supported"));
+        }
+
+        let page_size = match usize::try_from(request.page_size) {
+            Ok(0) => DEFAULT_PAGE_SIZE,
+            Ok(size) => size.min(MAX_PAGE_SIZE),
+            Err(_) => return Err(Status::invalid_argument("page size must not be negative")),
+        };
+
+        let after = match request.page_token.as_str() {
+            "" => None,
+            token if operation_id(token).is_some() => Some(token),
+            _ => return Err(Status::invalid_argument("invalid page token")),
+        };
+
+        let store = &self.store;
+        let mut operations = self
+            .db
+            .transaction(|tx| async move {
+                // Read an extra operation to know if there is a next page.
+                let operations = store
+                    .list(&tx, after, page_size + 1)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+
+                Ok::<_, DbError<Status>>((operations, tx))
+            })
+            .await?;
+
+        let next_page_token = if operations.len() > page_size {
+            operations.truncate(page_size);
+            operations
+                .last()
+                .map(|op| op.name.clone())
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        Ok(ListOperationsResponse {
+            operations,
+            next_page_token,
+        })
+    }
+
+    async fn get(&self, name: &str) -> StatusOr<Operation> {
+        let store = &self.store;
+
+        self.db
+            .transaction(|tx| async move {
+                let operation = store
+                    .get(&tx, name)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+
+                Ok::<_, DbError<Status>>((operation, tx))
+            })
+            .await?
+            .ok_or_else(|| not_found(name))
+    }
+
+    async fn delete(&self, name: &str) -> StatusOr<()> {
+        let store = &self.store;
+
+        let deleted = self
+            .db
+            .transaction(|mut tx| async move {
+                let deleted = store
+                    .delete(&mut tx, name)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+
+                Ok::<_, DbError<Status>>((deleted, tx))
+            })
+            .await?;
+
+        deleted.then_some(()).ok_or_else(|| not_found(name))
+    }
+
+    async fn cancel(&self, name: &str) -> StatusOr<()> {
+        let store = &self.store;
+
+        let found = self
+            .db
+            .transaction(|mut tx| async move {
+                // Cancelling a finished operation does nothing.
+                let found = store
+                    .cancel(&mut tx, name)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?
+                    || store
+                        .get(&tx, name)
+                        .await
+                        .map_err(|err| err.map_abort(Status::from))?
+                        .is_some();
+
+                Ok::<_, DbError<Status>>((found, tx))
+            })
+            .await?;
+
+        found.then_some(()).ok_or_else(|| not_found(name))
+    }
+
+    async fn wait(&self, request: WaitOperationRequest) -> StatusOr<Operation> {
+        let timeout = match request.timeout {
+            Some(timeout) => Duration::try_from(timeout)
+                .map_err(|_| Status::invalid_argument("timeout must not be negative"))?
+                .min(MAX_WAIT_TIMEOUT),
+            None => DEFAULT_WAIT_TIMEOUT,
+        };
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        let (store, name) = (&self.store, request.name.as_str());
+        loop {
+            let (operation, watch) = self
+                .db
+                .transaction(|tx| async move {
+                    let operation = store
+                        .get(&tx, name)
+                        .await
+                        .map_err(|err| err.map_abort(Status::from))?;
+                    let watch = match &operation {
+                        Some(operation) if !operation.done => Some(store.watch(&tx, name)),
+                        _ => None,
+                    };
+
+                    Ok::<_, DbError<Status>>(((operation, watch), tx))
+                })
+                .await?;
+
+            let operation = operation.ok_or_else(|| not_found(name))?;
+            let Some(watch) = watch else {
+                return Ok(operation);
+            };
+
+            // Return the latest state of the operation on timeouts, as the
+            // client can't tell them apart from a finished operation anyway.
+            match tokio::time::timeout_at(deadline, watch).await {
+                Ok(Err(DbError::Storage(err))) => return Err(err.into()),
+                Ok(_) => continue,
+                Err(_) => return Ok(operation),
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Operations for OperationsService {
+    async fn list_operations(
+        &self,
+        request: Request<ListOperationsRequest>,
+    ) -> Result<Response<ListOperationsResponse>, tonic::Status> {
+        Ok(Response::new(self.list(request.into_inner()).await?))
+    }
+
+    async fn get_operation(
+        &self,
+        request: Request<GetOperationRequest>,
+    ) -> Result<Response<Operation>, tonic::Status> {
+        Ok(Response::new(self.get(&request.get_ref().name).await?))
+    }
+
+    async fn delete_operation(
+        &self,
+        request: Request<DeleteOperationRequest>,
+    ) -> Result<Response<()>, tonic::Status> {
+        Ok(Response::new(self.delete(&request.get_ref().name).await?))
+    }
+
+    async fn cancel_operation(
+        &self,
+        request: Request<CancelOperationRequest>,
+    ) -> Result<Response<()>, tonic::Status> {
+        Ok(Response::new(self.cancel(&request.get_ref().name).await?))
+    }
+
+    async fn wait_operation(
+        &self,
+        request: Request<WaitOperationRequest>,
+    ) -> Result<Response<Operation>, tonic::Status> {
+        Ok(Response::new(self.wait(request.into_inner()).await?))
+    }
+}
+
+/// Get the ID of an operation from its name.
+fn operation_id(name: &str) -> Option<&str> {
+    name.strip_prefix(NAME_PREFIX)
+        .filter(|id| !id.is_empty() && !id.contains('/'))
+}
+
+/// The key of an operation, which may not exist.
+fn name_key(name: &str) -> &str {
+    operation_id(name).unwrap_or(name)
+}
+
+fn not_found(name: &str) -> Status {
+    Status::not_found(format!("operation '{name}' not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    fn any(value: &str) -> Any {
+        Any {
+            type_url: "type.googleapis.com/test.Progress".to_owned(),
+            value: value.as_bytes().to_vec(),
+        }
+    }
+
+    fn service() -> OperationsService {
+        OperationsService::new(
+            Arc::new(Db::temporary()),
+            Arc::new(OperationStore::from_static("operations")),
+        )
+    }
+
+    async fn create(service: &OperationsService) -> Operation {
+        let store = &service.store;
+
+        service
+            .db
+            .transaction(|mut tx| async move {
+                let operation = store
+                    .create(&mut tx, any("0%"))
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+                Ok::<_, DbError<Status>>((operation, tx))
+            })
+            .await
+            .expect("failed to create operation")
+    }
+
+    /// Run a single store update in a transaction.
+    macro_rules! update {
+        ($service:expr, |$store:ident, $tx:ident| $op:expr) => {{
+            let $store = &$service.store;
+            $service
+                .db
+                .transaction(|mut $tx| async move {
+                    let res = $op.await.map_err(|err| err.map_abort(Status::from))?;
+                    Ok::<_, DbError<Status>>((res, $tx))
+                })
+                .await
+                .expect("failed to update operation")
+        }};
+    }
+
+    #[tokio::test]
+    async fn test_operation_lifecycle() {
+        let service = service();
+        let operation = create(&service).await;
+        let name = operation.name.as_str();
+        assert!(name.starts_with(NAME_PREFIX));
+        assert!(!operation.done);
+
+        let updated = update!(service, |store, tx| store.update_metadata(
+            &mut tx,
+            name,
+            any("50%")
+        ));
+        assert!(updated);
+
+        let got = service.get(name).await.unwrap();
+        assert_eq!(got.metadata, Some(any("50%")));
+
+        let finished = update!(service, |store, tx| store.finish(
+            &mut tx,
+            name,
+            Err(Status::failed_precondition("repository is locked"))
+        ));
+        assert!(finished);
+
+        let got = service.get(name).await.unwrap();
+        assert!(got.done);
+        let Some(operation::Result::Error(error)) = got.result else {
+            panic!("operation didn't finish with an error: {:?}", got.result);
+        };
+        assert_eq!(error.code, Code::FailedPrecondition as i32);
+        assert_eq!(error.message, "repository is locked");
+
+        // Done operations can't be updated.
+        let updated = update!(service, |store, tx| store.update_metadata(
+            &mut tx,
+            name,
+            any("100%")
+        ));
+        assert!(!updated);
+
+        service.delete(name).await.unwrap();
+        let err = service.get(name).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        let err = service.get("operations/missing").await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let service = service();
+        let name = create(&service).await.name;
+
+        service.cancel(&name).await.unwrap();
+        // Cancelling twice is fine.
+        service.cancel(&name).await.unwrap();
+
+        let got = service.get(&name).await.unwrap();
+        let Some(operation::Result::Error(error)) = got.result else {
+            panic!("operation wasn't cancelled: {:?}", got.result);
+        };
+        assert_eq!(error.code, Code::Cancelled as i32);
+
+        // Workers notice the cancellation when finishing the operation.
+        let name = name.as_str();
+        let finished = update!(service, |store, tx| store.finish(
+            &mut tx,
+            name,
+            Ok(any("done"))
+        ));
+        assert!(!finished);
+
+        let err = service.cancel("operations/missing").await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let service = service();
+
+        let mut names = vec![];
+        for _ in 0..5 {
+            names.push(create(&service).await.name);
+        }
+        names.sort();
+
+        let mut listed = vec![];
+        let mut page_token = String::new();
+        loop {
+            let response = service
+                .list(ListOperationsRequest {
+                    name: "operations".to_owned(),
+                    page_size: 2,
+                    page_token,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            assert!(response.operations.len() <= 2);
+            listed.extend(response.operations.into_iter().map(|op| op.name));
+
+            if response.next_page_token.is_empty() {
+                break;
+            }
+            page_token = response.next_page_token;
+        }
+
+        assert_eq!(listed, names);
+
+        let err = service
+            .list(ListOperationsRequest {
+                filter: "done = true".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let service = service();
+        let name = create(&service).await.name;
+        let name = name.as_str();
+
+        // Timeouts return the operation as is.
+        let operation = service
+            .wait(WaitOperationRequest {
+                name: name.to_owned(),
+                timeout: Some(prost_types::Duration {
+                    seconds: 0,
+                    nanos: 10_000_000,
+                }),
+            })
+            .await
+            .unwrap();
+        assert!(!operation.done);
+
+        let (operation, ()) = tokio::join!(
+            service.wait(WaitOperationRequest {
+                name: name.to_owned(),
+                timeout: None,
+            }),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                update!(service, |store, tx| store.finish(
+                    &mut tx,
+                    name,
+                    Ok(any("done"))
+                ));
+            }
+        );
+
+        let operation = operation.unwrap();
+        assert!(operation.done);
+        assert_eq!(
+            operation.result,
+            Some(operation::Result::Response(any("done")))
+        );
+    }
+}
@@ -1,4 +1,26 @@
-use std::fmt;
+use std::{borrow::Cow, fmt, io, str::FromStr};
+
+use foundationdb::tuple::{
+    PackError, PackResult, TupleDepth, TuplePack, TupleUnpack, VersionstampOffset,
+};
+pub use pl_api_status::ErrorResource;
+use pl_api_status::{Status, StatusOr};
+
+pub use self::{
+    escape::{escape_id, unescape_id},
+    full::{service_of_type, FullResourceName},
+    name_ref::ResourceNameRef,
+    pattern::{ParseResourceName, ResourceNamePattern, ResourceNamePatterns, TypedResourceName},
+};
+
+#[path = "resource_name/escape.rs"]
+mod escape;
+#[path = "resource_name/full.rs"]
+mod full;
+#[path = "resource_name/name_ref.rs"]
+mod name_ref;
+#[path = "resource_name/pattern.rs"]
+mod pattern;
 
 /// Macro to make easy to create a new resource name.
 #[macro_export]
@@ -11,9 +33,41 @@ macro_rules! resource_name {
     }
 }
 
-// We've 2 words of stack storage, as smallvec needs to store the heap pointer
-// and length.
-type Splits = smallvec::SmallVec<[u8; 2 * std::mem::size_of::<*const ()>()]>;
+/// Maximum length of a collection identifier or resource ID, in bytes.
+///
+/// This is the limit recommended by AIP-122, which keeps IDs compatible with
+/// DNS labels. Resource IDs that need escaping have a larger limit, see
+/// [`MAX_ESCAPED_ID_LEN`].
+pub const MAX_ID_LEN: usize = 63;
+
+/// Maximum length of a resource ID that needs escaping, in bytes, before
+/// escaping.
+///
+/// These IDs can't be DNS labels anyway, and some are much longer than
+/// [`MAX_ID_LEN`], e.g. git ref names. Escaping may triple their length, so
+/// names with several of them may still be longer than [`MAX_NAME_LEN`].
+pub const MAX_ESCAPED_ID_LEN: usize = 1024;
+
+/// Maximum length of a resource name, in bytes.
+///
+/// This is enough for deeply nested names with UUID IDs, while bounding the
+/// size of the keys derived from them. It must fit the `u16` split offsets.
+pub const MAX_NAME_LEN: usize = 4096;
+
+/// ID matching any resource in a collection, following AIP-159.
+pub const WILDCARD: &str = "-";
+
+/// Tuple code starting a nested tuple.
+const NESTED_CODE: u8 = 0x05;
+/// Tuple code ending a nested tuple.
+const NIL_CODE: u8 = 0x00;
+
+// We keep 16 splits inline, which is enough for names with up to 8 levels of
+// nesting, longer ones spill to the heap. This takes 4 words of stack storage,
+// twice the heap pointer and length smallvec needs.
+type Splits = smallvec::SmallVec<[u16; 16]>;
+
+const _: () = assert!(MAX_NAME_LEN <= u16::MAX as usize);
 
 /// A resource name.
 ///
@@ -41,6 +95,22 @@ impl AsRef<str> for ResourceName {
     }
 }
 
+impl FromStr for ResourceName {
+    type Err = Status;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse("name", s.to_string())
+    }
+}
+
+impl TryFrom<String> for ResourceName {
+    type Error = Status;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse("name", value)
+    }
+}
+
 impl prost::Message for ResourceName {
     fn encode_raw<B>(&self, buf: &mut B)
     where
@@ -62,6 +132,11 @@ impl prost::Message for ResourceName {
         Self: Sized,
     {
         self.inner.merge_field(tag, wire_type, buf, ctx)?;
+
+        // An empty name is the same as an unset one, leave it to the services.
+        if !self.inner.is_empty() {
+            validate(&self.inner).map_err(prost::DecodeError::new)?;
+        }
         self.recompute_splits();
 
         Ok(())
@@ -77,222 +152,325 @@ impl prost::Message for ResourceName {
     }
 }
 
-impl ResourceName {
-    /// Get a given segment value from this resource name.
-    ///
-    /// # Panics
-    ///
-    /// If the segment is not present in the name.
-    pub fn get(&self, segment: &'static str) -> &str {
-        for (idx, seg) in self.segments().enumerate() {
-            if seg == segment {
-                // The value of the segment is the part right after it.
-                if let Some(val) = self.parts().nth(2 * idx + 1) {
-                    return val;
+/// Names are packed with one string element per part, e.g. `users/john` is
+/// packed as `("users", "john")`, so that the keys of child resources start
+/// with the key of their parent.
+///
+/// Like tuples, names are nested when packed inside another tuple, so that
+/// they can't be confused with the elements around them.
+impl TuplePack for ResourceName {
+    fn pack<W: io::Write>(
+        &self,
+        w: &mut W,
+        tuple_depth: TupleDepth,
+    ) -> io::Result<VersionstampOffset> {
+        self.as_name_ref().pack(w, tuple_depth)
+    }
+}
+
+impl<'de> TupleUnpack<'de> for ResourceName {
+    fn unpack(mut input: &'de [u8], tuple_depth: TupleDepth) -> PackResult<(&'de [u8], Self)> {
+        let nested = tuple_depth.depth() > 0;
+
+        if nested {
+            match input.split_first() {
+                Some((&NESTED_CODE, rest)) => input = rest,
+                Some((&found, _)) => {
+                    return Err(PackError::BadCode {
+                        found,
+                        expected: Some(NESTED_CODE),
+                    })
                 }
+                None => return Err(PackError::MissingBytes),
             }
         }
 
-        panic!("Could not find segment '{segment}' in '{self}'")
+        let mut inner = String::new();
+        loop {
+            match input.first() {
+                None if !nested => break,
+                Some(&NIL_CODE) if nested => {
+                    input = &input[1..];
+                    break;
+                }
+                _ => {}
+            }
+
+            let (rest, part) = <Cow<'de, str>>::unpack(input, tuple_depth.increment())?;
+            if part.is_empty() || part.contains('/') {
+                return Err(PackError::Message("invalid resource name part".into()));
+            }
+
+            if !inner.is_empty() {
+                inner.push('/');
+            }
+            inner.push_str(&part);
+            input = rest;
+        }
+
+        if inner.is_empty() {
+            return Err(PackError::Message("empty resource name".into()));
+        }
+        if inner.len() > MAX_NAME_LEN {
+            return Err(PackError::Message("resource name is too long".into()));
+        }
+
+        Ok((input, Self::from_inner(inner)))
     }
+}
 
-    /// Get the parent of this resource name.
+impl ResourceName {
+    /// Parse and validate a resource name following AIP-122.
     ///
-    /// The name returned by this method will always be an entity.
-    /// If `self` is a collection, we return the entity owner of
-    /// the collection, otherwise, we return the enity owner of
-    /// the collection where `self` is stored.
-    pub fn parent(&self) -> Option<Self> {
-        let last = if self.is_collection() {
-            *self.splits.split_last()?.0
-        } else {
-            let parent_end_idx = (self.splits.len() as u8).saturating_sub(2);
-            self.splits[parent_end_idx as usize]
-        } as usize;
+    /// Names must be non-empty, at most [`MAX_NAME_LEN`] bytes long, can't
+    /// start or end with `/`, nor contain empty segments. Collection identifiers
+    /// must be in `lowerCamelCase`, and resource IDs may only contain ASCII
+    /// letters, digits, `-`, `.`, `_`, `~` and percent-escapes, as produced by
+    /// [`escape_id`]. Both are limited to [`MAX_ID_LEN`] bytes, except for IDs
+    /// with escapes, which are limited to [`MAX_ESCAPED_ID_LEN`] bytes before
+    /// escaping.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status with a `BadRequest` violation for
+    /// `field` if the name is not valid.
+    pub fn parse(field: &str, name: impl Into<String>) -> StatusOr<Self> {
+        let name = name.into();
+
+        match validate(&name) {
+            Ok(()) => Ok(Self::from_inner(name)),
+            Err(description) => Err(invalid_name(field, description)),
+        }
+    }
 
-        if last == 0 {
-            None
-        } else {
-            Some(Self::__new(self.inner[..last].to_string()))
+    /// Get a borrowed view of this name.
+    pub fn as_name_ref(&self) -> ResourceNameRef<'_> {
+        ResourceNameRef {
+            inner: &self.inner,
+            splits: &self.splits,
         }
     }
 
-    /// Tests if the resource name matches a specific segments
-    /// pattern.
-    pub fn matches(&self, segments: &[&'static str]) -> bool {
-        dbg!(self.segments().collect::<Vec<_>>());
-        self.segments().eq(segments.iter().copied())
+    fn recompute_splits(&mut self) {
+        let splits_idxs = self
+            .inner
+            .match_indices('/')
+            .map(|(idx, _)| u16::try_from(idx).expect("resource name is too long"));
+
+        self.splits.clear();
+        // Add a virtual split at the start to simplify rest of the code.
+        self.splits.push(0);
+        self.splits.extend(splits_idxs);
     }
 
-    /// Create a new resource name of a child entity.
-    ///
-    /// Only can be called with entity names. Use [`Self::item`] to
-    /// create a name from a collection.
+    fn parts(&self) -> impl Iterator<Item = &str> {
+        self.as_name_ref().parts()
+    }
+
+    #[cfg(debug_assertions)]
+    #[doc(hidden)]
+    pub fn __new(inner: String) -> Self {
+        Self::from_inner(inner)
+    }
+
+    fn from_inner(inner: String) -> Self {
+        assert!(inner.len() <= MAX_NAME_LEN, "resource name is too long");
+
+        let mut name = Self {
+            inner,
+            splits: Splits::default(),
+        };
+        name.recompute_splits();
+
+        name
+    }
+}
+
+/// Generate methods of [`ResourceName`] that forward to [`ResourceNameRef`].
+///
+/// `ResourceName` can't implement `Deref<Target = ResourceNameRef<'_>>`, as
+/// `deref` returns a reference, so the view would have to be stored in the
+/// name. But the view borrows the string and the splits of the name, which
+/// would make it self-referential, and the inline splits move with the name.
+/// Storing offsets instead of a view doesn't help either, `deref` can't build
+/// a new value to return.
+macro_rules! delegate_to_ref {
+    ($($(#[$meta: meta])* fn $name: ident(&self $(, $arg: ident: $ty: ty)*) -> $ret: ty;)+) => {
+        impl ResourceName {
+        $(
+            $(#[$meta])*
+            pub fn $name(&self $(, $arg: $ty)*) -> $ret {
+                self.as_name_ref().$name($($arg),*)
+            }
+        )+
+        }
+    };
+}
+
+delegate_to_ref! {
+    /// Get a given segment value from this resource name.
     ///
     /// # Panics
     ///
-    /// Panics if called with a collection resource name.
-    pub fn child(&self, segment: &'static str, id: impl fmt::Display) -> Self {
-        use std::fmt::Write;
+    /// If the segment is not present in the name.
+    fn get(&self, segment: &str) -> Cow<'_, str>;
 
-        assert!(self.is_entity(), "Cannot create a child from a collection");
+    /// Get a given segment value from this resource name, if it is present.
+    fn try_get(&self, segment: &str) -> Option<Cow<'_, str>>;
 
-        let mut inner = self.inner.to_string();
-        let _ = write!(inner, "/{segment}/{id}");
+    /// Get the parent of this resource name, see [`ResourceNameRef::parent`].
+    ///
+    /// The parent is borrowed from this name, use [`ResourceNameRef::into_owned`]
+    /// to get an owned name.
+    fn parent(&self) -> Option<ResourceNameRef<'_>>;
 
-        Self::__new(inner)
-    }
+    /// Tests if the resource name matches a specific segments
+    /// pattern.
+    fn matches(&self, segments: &[&str]) -> bool;
+
+    /// Tests if this name has a [`WILDCARD`] ID, e.g. `users/-/repos`.
+    fn is_wildcard(&self) -> bool;
+
+    /// Tests if `name` matches this name, see [`ResourceNameRef::matches_name`].
+    fn matches_name(&self, name: &ResourceName) -> bool;
 
-    /// Create a new resource name for an item of this collection.
+    /// Iterate over the ancestors of this name, see [`ResourceNameRef::ancestors`].
+    fn ancestors(&self) -> impl Iterator<Item = ResourceNameRef<'_>>;
+
+    /// Tests if this name is a proper ancestor of `name`, see
+    /// [`ResourceNameRef::is_ancestor_of`].
+    fn is_ancestor_of(&self, name: &ResourceName) -> bool;
+
+    /// Get the remaining parts of `self` after the ancestor `prefix`, see
+    /// [`ResourceNameRef::strip_prefix`].
+    fn strip_prefix(&self, prefix: &ResourceName) -> Option<&str>;
+
+    /// Get the range of keys covering all descendants of this name, see
+    /// [`ResourceNameRef::key_range`].
+    fn key_range(&self) -> (Vec<u8>, Vec<u8>);
+
+    /// Create a new resource name of a child entity, see
+    /// [`ResourceNameRef::child`].
     ///
-    /// Only can be called with collection names. Use [`Self::child`] to
-    /// create a name from a collection.
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status if `segment` or `id` are not
+    /// valid, or if the new name would be longer than [`MAX_NAME_LEN`].
     ///
     /// # Panics
     ///
-    /// Panics if called with an entity resource name.
-    pub fn item(&self, id: impl fmt::Display) -> Self {
-        use std::fmt::Write;
-
-        assert!(self.is_collection(), "Cannot create an item from an entity");
-
-        let mut inner = self.inner.to_string();
-        let _ = write!(inner, "/{id}");
+    /// Panics if called with a collection resource name.
+    fn child(&self, segment: &str, id: impl fmt::Display) -> StatusOr<Self>;
 
-        Self::__new(inner)
-    }
+    /// Create a new resource name for an item of this collection, see
+    /// [`ResourceNameRef::item`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status if `id` is not valid, or if the
+    /// new name would be longer than [`MAX_NAME_LEN`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if called with an entity resource name.
+    fn item(&self, id: impl fmt::Display) -> StatusOr<Self>;
 
     /// Checks that this entity name if of a given type.
     ///
     /// # Panics
     ///
     /// Panics if called with a collection resource name.
-    pub fn is(&self, segment: &'static str) -> bool {
-        self.type_() == segment
-    }
+    fn is(&self, segment: &str) -> bool;
 
     /// Get the type of the entity represented by this resource name.
     ///
     /// # Panics
     ///
     /// The method panics if the resource name is not of an entity.
-    pub fn type_(&self) -> &str {
-        // This assert ensures that there are at least two elements
-        // in self.splits
-        assert!(self.is_entity(), "cannot get type of collection");
-
-        let end_idx = self.splits.len() - 1;
-        let end = self.splits[end_idx] as usize;
-        let mut start = self.splits[end_idx - 1] as usize;
-        if start > 0 {
-            start += 1;
-        }
+    fn type_(&self) -> &str;
 
-        &self.inner[start..end]
-    }
+    /// Get the type of the entity represented by this resource name, or
+    /// `None` if it is the name of a collection.
+    fn try_type(&self) -> Option<&str>;
 
-    /// Get the last part of this resource name.
-    ///
-    /// If this is an entity name, the identifier of the entity will be
-    /// returned. If this is a collection, the "name" of the collection
-    /// will be returned.
-    pub fn id(&self) -> &str {
-        let &last = self
-            .splits
-            .last()
-            .expect("all names have at least one split");
-
-        if last == 0 {
-            &self.inner
-        } else {
-            &self.inner[(last + 1) as usize..]
-        }
-    }
+    /// Get the last part of this resource name, see [`ResourceNameRef::id`].
+    fn id(&self) -> Cow<'_, str>;
 
     /// Is this name the name of a collection?
-    pub fn is_collection(&self) -> bool {
-        // As we add the virtual split at the start, each part
-        // starts with a split, meaning that collections, which
-        // have odd parts, have odd splits.
-        self.splits.len() % 2 == 1
-    }
+    fn is_collection(&self) -> bool;
 
     /// Is this name the name of an entity?
-    pub fn is_entity(&self) -> bool {
-        !self.is_collection()
-    }
+    fn is_entity(&self) -> bool;
 
     /// Returns the next name in a bytewise fashion.
-    pub fn next_bytewise(&self) -> Self {
-        let mut inner = self.inner.clone();
-        let l = inner.pop().expect("name is never empty");
-
-        let next = {
-            let l = l as u32;
-            let mut n = l.saturating_add(1);
-            if l < 0xD800 && 0xD800 <= n {
-                n += 0xD800;
-            }
+    fn next_bytewise(&self) -> Self;
+}
 
-            char::from_u32(n).expect("invalid next char")
-        };
+/// Build the status returned for an invalid name in `field`.
+fn invalid_name(field: &str, description: String) -> Status {
+    let mut status = Status::invalid_argument(format!("invalid {field}: {description}"));
+    status
+        .details_mut()
+        .add_bad_request_violation(field, description);
 
-        inner.push(next);
+    status
+}
 
-        Self {
-            inner,
-            splits: self.splits.clone(),
-        }
+/// Validate a resource name, returning the description of the violation.
+fn validate(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("resource name is empty".to_string());
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(format!("resource name is longer than {MAX_NAME_LEN} bytes"));
+    }
+    if name.starts_with('/') {
+        return Err("resource name must not start with '/'".to_string());
+    }
+    if name.ends_with('/') {
+        return Err("resource name must not end with '/'".to_string());
     }
 
-    fn recompute_splits(&mut self) {
-        let splits_idxs = self
-            .inner
-            .match_indices('/')
-            .map(|(idx, _)| u8::try_from(idx).unwrap());
-
-        self.splits.clear();
-        // Add a virtual split at the start to simplify rest of the code.
-        self.splits.push(0);
-        self.splits.extend(splits_idxs);
+    for (idx, part) in name.split('/').enumerate() {
+        validate_part(part, idx % 2 == 1)?;
     }
 
-    fn parts(&self) -> impl Iterator<Item = &str> {
-        let mut last = *self.splits.last().unwrap();
-        if last > 0 {
-            last += 1;
-        }
+    Ok(())
+}
 
-        self.splits
-            .windows(2)
-            .map(|w| self.translate_window(w))
-            .chain(std::iter::once(&self.inner[last as usize..]))
+/// Validate a collection identifier or an escaped resource ID.
+fn validate_part(part: &str, is_id: bool) -> Result<(), String> {
+    if part.is_empty() {
+        return Err("resource name contains an empty segment".to_string());
     }
 
-    fn segments(&self) -> impl Iterator<Item = &str> {
-        self.parts().step_by(2)
+    let (len, max_len) = if is_id && part.contains('%') {
+        (unescape_id(part).len(), MAX_ESCAPED_ID_LEN)
+    } else {
+        (part.len(), MAX_ID_LEN)
+    };
+    if len > max_len {
+        return Err(format!("'{part}' is longer than {max_len} bytes"));
     }
 
-    fn translate_window(&self, window: &[u8]) -> &str {
-        match *window {
-            [0, e] => &self.inner[..e as usize],
-            [s, e] => &self.inner[(s + 1) as usize..e as usize],
-            // Only two elements are returned per iteration.
-            _ => unsafe { std::hint::unreachable_unchecked() },
-        }
-    }
+    let valid = if is_id {
+        escape::is_canonical(part)
+    } else {
+        let mut chars = part.chars();
+        chars.next().is_some_and(|c| c.is_ascii_lowercase())
+            && chars.all(|c| c.is_ascii_alphanumeric())
+    };
 
-    #[cfg(debug_assertions)]
-    #[doc(hidden)]
-    pub fn __new(inner: String) -> Self {
-        let mut name = Self {
-            inner,
-            splits: Splits::default(),
+    if !valid {
+        let kind = if is_id {
+            "resource ID"
+        } else {
+            "collection identifier"
         };
-        name.recompute_splits();
-
-        name
+        return Err(format!("invalid {kind} '{part}'"));
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -311,7 +489,7 @@ mod tests {
 
             assert_eq!(data.get("repos"), "linux", "Invalid repos segment value.");
             assert_eq!(
-                data.parent(),
+                data.parent().map(crate::ResourceNameRef::into_owned),
                 Some(resource_name!("users/john")),
                 "Failed to compute correct parent."
             );
@@ -320,7 +498,7 @@ mod tests {
             assert!(
                 data.matches(&["users", "repos"]),
                 "Invalid match result, segments: {:?}",
-                data.segments().collect::<Vec<_>>()
+                data.as_name_ref().segments().collect::<Vec<_>>()
             );
         }
 
@@ -340,7 +518,34 @@ mod tests {
             assert!(
                 data.matches(&["users"]),
                 "Invalid match result, segments: {:?}",
-                data.segments().collect::<Vec<_>>()
+                data.as_name_ref().segments().collect::<Vec<_>>()
+            );
+        }
+
+        #[test]
+        fn test_long_name() {
+            let id = "0b7e4a3c-8f0e-4a8e-9d6f-5c2d3b1a0e9f";
+            let name: crate::ResourceName = format!(
+                "users/{id}/repos/{id}/changes/{id}/patchsets/{id}/comments/{id}/replies/{id}"
+            )
+            .parse()
+            .unwrap();
+            assert!(name.to_string().len() > 255);
+
+            assert!(name.is("replies"));
+            assert_eq!(name.get("comments"), id);
+            assert_eq!(name.id(), id);
+            assert!(name.matches(&[
+                "users",
+                "repos",
+                "changes",
+                "patchsets",
+                "comments",
+                "replies"
+            ]));
+            assert_eq!(
+                name.parent().unwrap().to_string(),
+                format!("users/{id}/repos/{id}/changes/{id}/patchsets/{id}/comments/{id}")
             );
         }
 
@@ -365,7 +570,7 @@ mod tests {
             assert!(col.is_collection());
             assert_eq!(col.id(), "users");
             assert_eq!(col.parent(), None);
-            assert_eq!(col.item("john"), resource_name!("users/john"));
+            assert_eq!(col.item("john").unwrap(), resource_name!("users/john"));
             assert!(col.matches(&["users"]));
             assert!(!col.matches(&["users", "repos"]));
         }
@@ -376,9 +581,12 @@ mod tests {
 
             assert!(col.is_collection());
             assert_eq!(col.id(), "repos");
-            assert_eq!(col.parent(), Some(resource_name!("users/john")));
+            assert_eq!(col.parent().unwrap(), resource_name!("users/john"));
             assert_eq!(col.get("users"), "john");
-            assert_eq!(col.item("linux"), resource_name!("users/john/repos/linux"));
+            assert_eq!(
+                col.item("linux").unwrap(),
+                resource_name!("users/john/repos/linux")
+            );
             assert!(col.matches(&["users", "repos"]));
             assert!(!col.matches(&["users"]));
         }
@@ -390,6 +598,223 @@ mod tests {
         }
     }
 
+    mod parsing {
+        use tonic::Code;
+
+        use crate::ResourceName;
+
+        #[test]
+        fn test_valid() {
+            for name in [
+                "users",
+                "users/john",
+                "users/john.doe/gitRepos/linux-6.1_rc~1",
+            ] {
+                let parsed: ResourceName = name.parse().unwrap();
+                assert_eq!(parsed.to_string(), name);
+            }
+
+            assert_eq!(
+                ResourceName::try_from("users/john".to_string()).unwrap(),
+                resource_name!("users/john")
+            );
+        }
+
+        #[test]
+        fn test_invalid() {
+            let long_id = format!("users/{}", "a".repeat(64));
+
+            for name in [
+                "",
+                "/users/john",
+                "users/john/",
+                "users//john",
+                "Users/john",
+                "git_repos/linux",
+                "users/john doe",
+                "users/jöhn",
+                &long_id,
+            ] {
+                let err = name.parse::<ResourceName>().unwrap_err();
+                assert_eq!(err.code(), Code::InvalidArgument, "accepted {name:?}");
+            }
+        }
+
+        #[test]
+        fn test_too_long() {
+            let name = "users/john/repos/".to_string() + &"a".repeat(crate::MAX_ID_LEN);
+            let name = std::iter::repeat(name)
+                .take(100)
+                .collect::<Vec<_>>()
+                .join("/");
+
+            let err = name.parse::<ResourceName>().unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument);
+        }
+
+        #[test]
+        fn test_child_too_long() {
+            let mut name = resource_name!("users/john");
+            let err = loop {
+                match name.child("repos", "a".repeat(crate::MAX_ID_LEN)) {
+                    Ok(child) => name = child,
+                    Err(err) => break err,
+                }
+            };
+
+            assert_eq!(err.code(), Code::InvalidArgument);
+            assert!(name.as_ref().len() > crate::MAX_NAME_LEN - 2 * crate::MAX_ID_LEN);
+        }
+
+        #[test]
+        fn test_field_violation() {
+            let err = ResourceName::parse("parent", "users//john").unwrap_err();
+
+            let violations = &err.details().bad_request().unwrap().field_violations;
+            assert_eq!(violations.len(), 1);
+            assert_eq!(violations[0].field, "parent");
+            assert_eq!(
+                violations[0].description,
+                "resource name contains an empty segment"
+            );
+        }
+
+        #[test]
+        fn test_fallible_getters() {
+            let name = resource_name!("users/john/repos");
+
+            assert_eq!(name.try_get("users"), Some("john".into()));
+            assert_eq!(name.try_get("repos"), None);
+            assert_eq!(name.try_type(), None);
+            assert_eq!(name.parent().unwrap().try_type(), Some("users"));
+        }
+
+        #[test]
+        fn test_decode_validates() {
+            use prost::Message;
+
+            let message = "users//john".to_string().encode_to_vec();
+            assert!(ResourceName::decode(&message[..]).is_err());
+        }
+    }
+
+    mod ancestry {
+        use foundationdb::tuple::TuplePack;
+
+        #[test]
+        fn test_ancestors() {
+            let name = resource_name!("users/john/repos/linux/issues/1");
+
+            assert_eq!(
+                name.ancestors().collect::<Vec<_>>(),
+                [
+                    resource_name!("users/john/repos/linux"),
+                    resource_name!("users/john"),
+                ]
+            );
+            assert_eq!(resource_name!("users/john").ancestors().count(), 0);
+        }
+
+        #[test]
+        fn test_is_ancestor_of() {
+            let name = resource_name!("users/john/repos/linux");
+
+            assert!(resource_name!("users/john").is_ancestor_of(&name));
+            assert!(resource_name!("users/john/repos").is_ancestor_of(&name));
+            assert!(resource_name!("users/-").is_ancestor_of(&name));
+            assert!(!name.is_ancestor_of(&name));
+            assert!(!resource_name!("users/johnny").is_ancestor_of(&name));
+            assert!(!resource_name!("users/jane").is_ancestor_of(&name));
+            assert!(!resource_name!("orgs/-").is_ancestor_of(&name));
+        }
+
+        #[test]
+        fn test_strip_prefix() {
+            let name = resource_name!("users/john/repos/linux");
+
+            assert_eq!(
+                name.strip_prefix(&resource_name!("users/john")),
+                Some("repos/linux")
+            );
+            assert_eq!(
+                name.strip_prefix(&resource_name!("users/-/repos")),
+                Some("linux")
+            );
+            assert_eq!(name.strip_prefix(&name), None);
+            assert_eq!(name.strip_prefix(&resource_name!("users/jane")), None);
+        }
+
+        #[test]
+        fn test_wildcards() {
+            let pattern = resource_name!("users/-/repos/linux");
+
+            assert!(pattern.is_wildcard());
+            assert!(!resource_name!("users/john/repos").is_wildcard());
+            assert!(pattern.matches_name(&resource_name!("users/john/repos/linux")));
+            assert!(!pattern.matches_name(&resource_name!("users/john/repos/llvm")));
+            assert!(!pattern.matches_name(&resource_name!("users/john/repos")));
+            // Wildcards only apply to IDs.
+            assert!(!resource_name!("-/john").matches_name(&resource_name!("users/john")));
+        }
+
+        #[test]
+        fn test_key_range() {
+            let (begin, end) = resource_name!("users/john").key_range();
+
+            let child = resource_name!("users/john/repos/linux").pack_to_vec();
+            assert!(begin < child && child < end);
+
+            for other in ["users/john", "users/johnny", "users/jane/repos/linux"] {
+                let other = crate::ResourceName::__new(other.to_string()).pack_to_vec();
+                assert!(other < begin || end <= other, "{other:?} in range");
+            }
+
+            let (begin, end) = resource_name!("users/-/repos").key_range();
+            assert_eq!((begin, end), resource_name!("users").key_range());
+        }
+    }
+
+    mod tuple {
+        use foundationdb::tuple::{pack, unpack};
+
+        use crate::ResourceName;
+
+        #[test]
+        fn test_pack_parts() {
+            let name = resource_name!("users/john/repos/linux");
+
+            assert_eq!(pack(&name), pack(&("users", "john", "repos", "linux")));
+            assert_eq!(unpack::<ResourceName>(&pack(&name)).unwrap(), name);
+        }
+
+        #[test]
+        fn test_children_share_prefix() {
+            let parent = pack(&resource_name!("users/john"));
+
+            assert!(pack(&resource_name!("users/john/repos/linux")).starts_with(&parent));
+            assert!(!pack(&resource_name!("users/johnny")).starts_with(&parent));
+        }
+
+        #[test]
+        fn test_nested() {
+            let name = resource_name!("users/john");
+            let key = pack(&(&name, 1u32));
+
+            assert_eq!(
+                unpack::<(ResourceName, u32)>(&key).unwrap(),
+                (name, 1),
+                "nested name consumed the following elements"
+            );
+        }
+
+        #[test]
+        fn test_invalid_parts() {
+            assert!(unpack::<ResourceName>(&pack(&("users", ""))).is_err());
+            assert!(unpack::<ResourceName>(&pack(&("users/john",))).is_err());
+            assert!(unpack::<ResourceName>(&[]).is_err());
+        }
+    }
+
     mod protobuf {
         use prost::Message;
 
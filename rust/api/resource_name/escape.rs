@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+
+/// Escape a resource ID, so it can be used as a part of a resource name.
+///
+/// IDs are percent-encoded: each byte of the UTF-8 encoding of the ID that is
+/// not an unreserved character (`[A-Za-z0-9-._~]`) is replaced by `%XX`, where
+/// `XX` is the byte in uppercase hexadecimal. This covers `/`, `%` and non-ASCII
+/// characters, e.g. `feature/foo` is escaped as `feature%2Ffoo`.
+///
+/// The ID `-` is kept as-is, as it is the [`WILDCARD`](crate::WILDCARD) in
+/// all APIs taking IDs, so `%2D` is not a valid escape.
+pub fn escape_id(id: &str) -> Cow<'_, str> {
+    if id.bytes().all(is_unreserved) {
+        return Cow::Borrowed(id);
+    }
+
+    let mut escaped = String::with_capacity(id.len() + 2 * id.len() / 3);
+    for b in id.bytes() {
+        if is_unreserved(b) {
+            escaped.push(b as char);
+        } else {
+            const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+            escaped.push('%');
+            escaped.push(HEX[(b >> 4) as usize] as char);
+            escaped.push(HEX[(b & 0xF) as usize] as char);
+        }
+    }
+
+    Cow::Owned(escaped)
+}
+
+/// Unescape a resource ID escaped by [`escape_id`].
+///
+/// Invalid escapes are kept as-is, but they are rejected when parsing names.
+pub fn unescape_id(id: &str) -> Cow<'_, str> {
+    decode(id).unwrap_or(Cow::Borrowed(id))
+}
+
+/// Check that `id` is escaped the same way [`escape_id`] would have escaped it.
+///
+/// This ensures that each ID has only one representation, allowing names to
+/// be compared as strings.
+pub(crate) fn is_canonical(id: &str) -> bool {
+    decode(id).is_some_and(|decoded| escape_id(&decoded) == id)
+}
+
+fn decode(id: &str) -> Option<Cow<'_, str>> {
+    if !id.contains('%') {
+        return Some(Cow::Borrowed(id));
+    }
+
+    let mut bytes = Vec::with_capacity(id.len());
+    let mut iter = id.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hi = (iter.next()? as char).to_digit(16)?;
+            let lo = (iter.next()? as char).to_digit(16)?;
+            bytes.push((hi << 4 | lo) as u8);
+        } else {
+            bytes.push(b);
+        }
+    }
+
+    String::from_utf8(bytes).ok().map(Cow::Owned)
+}
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{resource_name, ResourceName, MAX_ESCAPED_ID_LEN, MAX_ID_LEN, WILDCARD};
+
+    const REF_NAMES: &[&str] = &[
+        "main",
+        "feature/foo",
+        "users/john/fix-100%",
+        "release-1.0",
+        "--",
+        "a%2Fb",
+        "ação/ünïcödé",
+        "日本語",
+        "emoji/🦀",
+        "spaces and\ttabs",
+        "~weird.._name~",
+    ];
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape_id("main"), "main");
+        assert_eq!(escape_id("feature/foo"), "feature%2Ffoo");
+        assert_eq!(escape_id("100%"), "100%25");
+        assert_eq!(escape_id("ç"), "%C3%A7");
+        assert_eq!(escape_id("-"), "-");
+        assert_eq!(escape_id("--"), "--");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let branches = resource_name!("repositories/linux/branches");
+
+        for &ref_name in REF_NAMES {
+            assert_eq!(unescape_id(&escape_id(ref_name)), ref_name);
+
+            let name = branches.item(ref_name).unwrap();
+            assert_eq!(name.id(), ref_name);
+            assert_eq!(name.get("branches"), ref_name);
+            assert_eq!(name.parent().unwrap(), resource_name!("repositories/linux"));
+            assert!(!name.is_wildcard(), "{name} is a wildcard");
+
+            let parsed = ResourceName::parse("name", name.to_string()).unwrap();
+            assert_eq!(parsed, name);
+
+            let child = name.child("commits", ref_name).unwrap();
+            assert_eq!(child.get("branches"), ref_name);
+            assert_eq!(child.id(), ref_name);
+            assert!(child.matches(&["repositories", "branches", "commits"]));
+        }
+    }
+
+    #[test]
+    fn test_wildcard() {
+        let branches = resource_name!("repositories/linux/branches");
+
+        let name = branches.item(WILDCARD).unwrap();
+        assert!(name.is_wildcard());
+        assert_eq!(name.to_string(), "repositories/linux/branches/-");
+        assert_eq!(name.id(), WILDCARD);
+    }
+
+    #[test]
+    fn test_id_len() {
+        let branches = resource_name!("repositories/linux/branches");
+
+        let ref_name = "/".repeat(MAX_ID_LEN);
+        let name = branches.item(&ref_name).unwrap();
+        assert_eq!(name.id(), ref_name);
+        assert_eq!(ResourceName::parse("name", name.to_string()).unwrap(), name);
+
+        let ref_name = "a".repeat(MAX_ID_LEN + 1);
+        let name = format!("{branches}/{ref_name}");
+        assert!(ResourceName::parse("name", name).is_err());
+
+        // IDs that need escaping, like most ref names, can be longer.
+        let ref_name = format!("refs/heads/{}", "a".repeat(200));
+        let name = branches.item(&ref_name).unwrap();
+        assert_eq!(name.id(), ref_name);
+        assert_eq!(ResourceName::parse("name", name.to_string()).unwrap(), name);
+
+        let ref_name = "/".repeat(MAX_ESCAPED_ID_LEN + 1);
+        assert!(branches.item(&ref_name).is_err());
+    }
+
+    #[test]
+    fn test_invalid_item() {
+        let branches = resource_name!("repositories/linux/branches");
+
+        for id in [String::new(), "a".repeat(MAX_ID_LEN + 1)] {
+            let err = branches.item(&id).unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument, "accepted {id:?}");
+        }
+
+        let name = branches.item("main").unwrap();
+        let err = name.child("Commits", "1").unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_not_canonical() {
+        for id in ["%2f", "%41", "%2D", "%", "%2", "%zz", "%FF", "a/b"] {
+            assert!(!is_canonical(id), "accepted {id:?}");
+        }
+
+        assert!(is_canonical(WILDCARD));
+        assert_eq!(unescape_id("%zz"), "%zz");
+    }
+}
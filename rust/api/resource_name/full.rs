@@ -0,0 +1,221 @@
+use std::{fmt, str::FromStr};
+
+use pl_api_status::{Status, StatusOr};
+
+use crate::{invalid_name, ResourceName, TypedResourceName};
+
+/// A full resource name, following AIP-122.
+///
+/// Full names are used to reference resources across services, e.g. in audit
+/// logs or IAM policies. They are made of the service name, followed by the
+/// relative name of the resource, e.g. `//grs.patch.labs/repositories/linux`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FullResourceName {
+    service: String,
+    name: ResourceName,
+}
+
+impl fmt::Display for FullResourceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "//{}/{}", self.service, self.name)
+    }
+}
+
+impl FromStr for FullResourceName {
+    type Err = Status;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse("name", s)
+    }
+}
+
+impl From<FullResourceName> for ResourceName {
+    fn from(name: FullResourceName) -> Self {
+        name.name
+    }
+}
+
+impl FullResourceName {
+    /// Create a new full name of a resource in the given service.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status if `service` is not a valid domain name.
+    pub fn new(service: impl Into<String>, name: ResourceName) -> StatusOr<Self> {
+        let service = service.into();
+
+        if !is_valid_service(&service) {
+            return Err(Status::invalid_argument(format!(
+                "invalid service name '{service}'"
+            )));
+        }
+
+        Ok(Self { service, name })
+    }
+
+    /// Create the full name of a resource of the given type, e.g.
+    /// `grs.patch.labs/Repository`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status if the type doesn't contain a valid
+    /// service name.
+    pub fn for_type(resource_type: &str, name: ResourceName) -> StatusOr<Self> {
+        let Some(service) = service_of_type(resource_type) else {
+            return Err(Status::invalid_argument(format!(
+                "invalid resource type '{resource_type}'"
+            )));
+        };
+
+        Self::new(service, name)
+    }
+
+    /// Create the full name of a typed resource name.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status if the name is not valid, see
+    /// [`TypedResourceName::to_resource_name`].
+    pub fn from_typed<T: TypedResourceName>(name: &T) -> StatusOr<Self> {
+        Self::for_type(T::RESOURCE_TYPE, name.to_resource_name()?)
+    }
+
+    /// Parse and validate a full resource name.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status with a `BadRequest` violation for
+    /// `field` if the name doesn't start with `//`, has an invalid service, or
+    /// an invalid relative name, see [`ResourceName::parse`].
+    pub fn parse(field: &str, name: &str) -> StatusOr<Self> {
+        let Some(full) = name.strip_prefix("//") else {
+            return Err(invalid_name(
+                field,
+                "full resource name must start with '//'".to_string(),
+            ));
+        };
+
+        let Some((service, name)) = full.split_once('/') else {
+            return Err(invalid_name(
+                field,
+                "full resource name is missing the relative name".to_string(),
+            ));
+        };
+
+        if !is_valid_service(service) {
+            return Err(invalid_name(
+                field,
+                format!("invalid service name '{service}'"),
+            ));
+        }
+
+        Ok(Self {
+            service: service.to_string(),
+            name: ResourceName::parse(field, name)?,
+        })
+    }
+
+    /// Get the name of the service owning the resource, e.g. `grs.patch.labs`.
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    /// Get the relative name of the resource.
+    pub fn name(&self) -> &ResourceName {
+        &self.name
+    }
+
+    /// Get the relative name of the resource, consuming the full name.
+    pub fn into_name(self) -> ResourceName {
+        self.name
+    }
+
+    /// Tests if the resource is of the given type, i.e. if it is owned by the
+    /// type service.
+    pub fn is_of_type(&self, resource_type: &str) -> bool {
+        service_of_type(resource_type) == Some(&self.service)
+    }
+}
+
+/// Get the service of a resource type, e.g. `grs.patch.labs` for
+/// `grs.patch.labs/Repository`.
+pub fn service_of_type(resource_type: &str) -> Option<&str> {
+    let (service, type_name) = resource_type.split_once('/')?;
+
+    (is_valid_service(service) && !type_name.is_empty() && !type_name.contains('/'))
+        .then_some(service)
+}
+
+/// Check that the service is a valid domain name.
+fn is_valid_service(service: &str) -> bool {
+    !service.is_empty()
+        && service.len() <= 253
+        && service.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+    use crate::resource_name;
+
+    #[test]
+    fn test_parse() {
+        let name: FullResourceName = "//grs.patch.labs/repositories/linux".parse().unwrap();
+
+        assert_eq!(name.service(), "grs.patch.labs");
+        assert_eq!(name.name(), &resource_name!("repositories/linux"));
+        assert_eq!(name.to_string(), "//grs.patch.labs/repositories/linux");
+        assert!(name.is_of_type("grs.patch.labs/Repository"));
+        assert!(!name.is_of_type("iam.patch.labs/Policy"));
+
+        assert_eq!(
+            ResourceName::from(name),
+            resource_name!("repositories/linux")
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        for name in [
+            "grs.patch.labs/repositories/linux",
+            "//grs.patch.labs",
+            "//grs.patch.labs/",
+            "///repositories/linux",
+            "//GRS.patch.labs/repositories/linux",
+            "//grs..labs/repositories/linux",
+            "//grs.patch.labs/repositories//linux",
+        ] {
+            let err = name.parse::<FullResourceName>().unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument, "accepted {name:?}");
+        }
+    }
+
+    #[test]
+    fn test_for_type() {
+        assert_eq!(
+            service_of_type("grs.patch.labs/Repository"),
+            Some("grs.patch.labs")
+        );
+        assert_eq!(service_of_type("Repository"), None);
+
+        let name = FullResourceName::for_type(
+            "grs.patch.labs/Repository",
+            resource_name!("repositories/linux"),
+        )
+        .unwrap();
+        assert_eq!(name.to_string(), "//grs.patch.labs/repositories/linux");
+
+        assert!(
+            FullResourceName::for_type("Repository", resource_name!("repositories/linux")).is_err()
+        );
+    }
+}
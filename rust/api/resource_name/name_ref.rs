@@ -0,0 +1,485 @@
+use std::{borrow::Cow, fmt, io};
+
+use foundationdb::tuple::{TupleDepth, TuplePack, VersionstampOffset};
+
+use pl_api_status::{Status, StatusOr};
+
+use crate::{
+    escape_id, unescape_id, validate_part, ResourceName, Splits, MAX_NAME_LEN, NESTED_CODE,
+    NIL_CODE, WILDCARD,
+};
+
+/// A borrowed resource name.
+///
+/// This is a view over the name string and its precomputed splits, which
+/// allows comparing names and navigating their hierarchy without allocating,
+/// e.g. [`Self::parent`] returns a prefix of the name.
+///
+/// Use [`ResourceName::as_name_ref`] to get the view of an owned name, and
+/// [`Self::into_owned`] to get an owned name back. Owned names can't deref to
+/// their view, as it would borrow from the name itself, so they provide the
+/// same read-only methods by forwarding them to the view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ResourceNameRef<'a> {
+    pub(crate) inner: &'a str,
+    pub(crate) splits: &'a [u16],
+}
+
+impl fmt::Display for ResourceNameRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.inner)
+    }
+}
+
+impl AsRef<str> for ResourceNameRef<'_> {
+    fn as_ref(&self) -> &str {
+        self.inner
+    }
+}
+
+impl<'a> From<&'a ResourceName> for ResourceNameRef<'a> {
+    fn from(name: &'a ResourceName) -> Self {
+        name.as_name_ref()
+    }
+}
+
+impl PartialEq<ResourceName> for ResourceNameRef<'_> {
+    fn eq(&self, other: &ResourceName) -> bool {
+        *self == other.as_name_ref()
+    }
+}
+
+impl PartialEq<ResourceNameRef<'_>> for ResourceName {
+    fn eq(&self, other: &ResourceNameRef<'_>) -> bool {
+        self.as_name_ref() == *other
+    }
+}
+
+/// See the implementation for [`ResourceName`].
+impl TuplePack for ResourceNameRef<'_> {
+    fn pack<W: io::Write>(
+        &self,
+        w: &mut W,
+        tuple_depth: TupleDepth,
+    ) -> io::Result<VersionstampOffset> {
+        let nested = tuple_depth.depth() > 0;
+        let mut offset = VersionstampOffset::None { size: 0 };
+
+        if nested {
+            w.write_all(&[NESTED_CODE])?;
+            offset += 1;
+        }
+
+        for part in self.parts() {
+            offset += part.pack(w, tuple_depth.increment())?;
+        }
+
+        if nested {
+            w.write_all(&[NIL_CODE])?;
+            offset += 1;
+        }
+
+        Ok(offset)
+    }
+}
+
+impl<'a> ResourceNameRef<'a> {
+    /// Get the name as a string.
+    pub fn as_str(self) -> &'a str {
+        self.inner
+    }
+
+    /// Copy this name into an owned [`ResourceName`].
+    pub fn into_owned(self) -> ResourceName {
+        ResourceName {
+            inner: self.inner.to_string(),
+            splits: Splits::from_slice(self.splits),
+        }
+    }
+
+    /// Get a given segment value from this resource name.
+    ///
+    /// The value is unescaped, see [`unescape_id`].
+    ///
+    /// # Panics
+    ///
+    /// If the segment is not present in the name.
+    pub fn get(self, segment: &str) -> Cow<'a, str> {
+        self.try_get(segment)
+            .unwrap_or_else(|| panic!("Could not find segment '{segment}' in '{self}'"))
+    }
+
+    /// Get a given segment value from this resource name, if it is present.
+    ///
+    /// The value is unescaped, see [`unescape_id`].
+    pub fn try_get(self, segment: &str) -> Option<Cow<'a, str>> {
+        for (idx, seg) in self.segments().enumerate() {
+            if seg == segment {
+                // The value of the segment is the part right after it.
+                if let Some(val) = self.parts().nth(2 * idx + 1) {
+                    return Some(unescape_id(val));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Get the parent of this resource name.
+    ///
+    /// The name returned by this method will always be an entity.
+    /// If `self` is a collection, we return the entity owner of
+    /// the collection, otherwise, we return the enity owner of
+    /// the collection where `self` is stored.
+    ///
+    /// The parent is a prefix of this name, so no allocation is done.
+    pub fn parent(self) -> Option<Self> {
+        // The splits of the parent are a prefix of ours, and the split
+        // right after them is where the parent name ends.
+        let len = if self.is_collection() {
+            self.splits.len() - 1
+        } else {
+            self.splits.len().saturating_sub(2)
+        };
+
+        if len == 0 {
+            None
+        } else {
+            Some(Self {
+                inner: &self.inner[..self.splits[len] as usize],
+                splits: &self.splits[..len],
+            })
+        }
+    }
+
+    /// Tests if the resource name matches a specific segments
+    /// pattern.
+    pub fn matches(self, segments: &[&str]) -> bool {
+        self.segments().eq(segments.iter().copied())
+    }
+
+    /// Tests if this name has a [`WILDCARD`] ID, e.g. `users/-/repos`.
+    pub fn is_wildcard(self) -> bool {
+        !self.inner.is_empty() && self.ids().any(|id| id == WILDCARD)
+    }
+
+    /// Tests if `name` matches this name, where [`WILDCARD`] IDs match any ID.
+    ///
+    /// For example, `users/-/repos/linux` matches `users/john/repos/linux`.
+    pub fn matches_name<'b>(self, name: impl Into<ResourceNameRef<'b>>) -> bool {
+        let name = name.into();
+
+        !self.inner.is_empty()
+            && !name.inner.is_empty()
+            && self.splits.len() == name.splits.len()
+            && self.matches_prefix_of(name)
+    }
+
+    /// Iterate over the ancestors of this name, from the closest to the farthest.
+    ///
+    /// Ancestors are always entities, e.g. `users/john/repos/linux` has only
+    /// `users/john` as ancestor.
+    pub fn ancestors(self) -> impl Iterator<Item = ResourceNameRef<'a>> {
+        std::iter::successors(self.parent(), |name| name.parent())
+    }
+
+    /// Tests if this name is a proper ancestor of `name`, i.e. if `name` is
+    /// stored under this name. [`WILDCARD`] IDs in this name match any ID.
+    ///
+    /// Unlike [`Self::ancestors`], collections are considered too, e.g. both
+    /// `users/john` and `users/john/repos` are ancestors of `users/john/repos/linux`.
+    pub fn is_ancestor_of<'b>(self, name: impl Into<ResourceNameRef<'b>>) -> bool {
+        let name = name.into();
+
+        !self.inner.is_empty()
+            && !name.inner.is_empty()
+            && self.splits.len() < name.splits.len()
+            && self.matches_prefix_of(name)
+    }
+
+    /// Get the remaining parts of `self` after the ancestor `prefix`.
+    ///
+    /// For example, `users/john/repos/linux` without `users/john` is `repos/linux`.
+    /// The IDs in the returned string are kept escaped.
+    /// Returns `None` if `prefix` is not an ancestor of this name.
+    pub fn strip_prefix<'b>(self, prefix: impl Into<ResourceNameRef<'b>>) -> Option<&'a str> {
+        let prefix = prefix.into();
+        if !prefix.is_ancestor_of(self) {
+            return None;
+        }
+
+        let start = self.splits[prefix.splits.len()] as usize + 1;
+        Some(&self.inner[start..])
+    }
+
+    /// Get the range of keys, as packed by [`TuplePack`], covering all descendants
+    /// of this name.
+    ///
+    /// The keys are relative to the subspace where names are packed, so callers
+    /// must prefix them with the subspace bytes. The name itself isn't part of
+    /// the range. For wildcard names, the range covers the descendants of the
+    /// longest prefix without wildcards, and callers must filter the results
+    /// with [`Self::is_ancestor_of`].
+    pub fn key_range(self) -> (Vec<u8>, Vec<u8>) {
+        let mut begin = Vec::with_capacity(self.inner.len() + self.splits.len() + 1);
+        for (idx, part) in self.parts().enumerate() {
+            if idx % 2 == 1 && part == WILDCARD {
+                break;
+            }
+
+            part.pack_into_vec(&mut begin);
+        }
+
+        let mut end = begin.clone();
+        begin.push(0x00);
+        end.push(0xFF);
+
+        (begin, end)
+    }
+
+    /// Create a new resource name of a child entity.
+    ///
+    /// Only can be called with entity names. Use [`Self::item`] to
+    /// create a name from a collection. The ID is escaped, see [`escape_id`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status if `segment` or `id` are not
+    /// valid, see [`ResourceName::parse`], or if the new name would be longer
+    /// than [`MAX_NAME_LEN`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if called with a collection resource name.
+    pub fn child(self, segment: &str, id: impl fmt::Display) -> StatusOr<ResourceName> {
+        assert!(self.is_entity(), "Cannot create a child from a collection");
+
+        let id = id.to_string();
+        let id = escape_id(&id);
+        validate_part(segment, false)
+            .and_then(|()| validate_part(&id, true))
+            .map_err(Status::invalid_argument)?;
+
+        self.join(&[segment, &id])
+    }
+
+    /// Create a new resource name for an item of this collection.
+    ///
+    /// Only can be called with collection names. Use [`Self::child`] to
+    /// create a name from a collection. The ID is escaped, see [`escape_id`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status if `id` is not valid, see
+    /// [`ResourceName::parse`], or if the new name would be longer than
+    /// [`MAX_NAME_LEN`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if called with an entity resource name.
+    pub fn item(self, id: impl fmt::Display) -> StatusOr<ResourceName> {
+        assert!(self.is_collection(), "Cannot create an item from an entity");
+
+        let id = id.to_string();
+        let id = escape_id(&id);
+        validate_part(&id, true).map_err(Status::invalid_argument)?;
+
+        self.join(&[&id])
+    }
+
+    /// Append validated parts to this name.
+    fn join(self, parts: &[&str]) -> StatusOr<ResourceName> {
+        let len = self.inner.len() + parts.iter().map(|part| part.len() + 1).sum::<usize>();
+        if len > MAX_NAME_LEN {
+            return Err(Status::invalid_argument(format!(
+                "resource name is longer than {MAX_NAME_LEN} bytes"
+            )));
+        }
+
+        let mut inner = String::with_capacity(len);
+        inner.push_str(self.inner);
+        for part in parts {
+            inner.push('/');
+            inner.push_str(part);
+        }
+
+        Ok(ResourceName::from_inner(inner))
+    }
+
+    /// Checks that this entity name if of a given type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called with a collection resource name.
+    pub fn is(self, segment: &str) -> bool {
+        self.type_() == segment
+    }
+
+    /// Get the type of the entity represented by this resource name.
+    ///
+    /// # Panics
+    ///
+    /// The method panics if the resource name is not of an entity.
+    pub fn type_(self) -> &'a str {
+        self.try_type().expect("cannot get type of collection")
+    }
+
+    /// Get the type of the entity represented by this resource name, or
+    /// `None` if it is the name of a collection.
+    pub fn try_type(self) -> Option<&'a str> {
+        // This check ensures that there are at least two elements
+        // in self.splits
+        if !self.is_entity() || self.splits.len() < 2 {
+            return None;
+        }
+
+        let end_idx = self.splits.len() - 1;
+        let end = self.splits[end_idx] as usize;
+        let mut start = self.splits[end_idx - 1] as usize;
+        if start > 0 {
+            start += 1;
+        }
+
+        Some(&self.inner[start..end])
+    }
+
+    /// Get the last part of this resource name.
+    ///
+    /// If this is an entity name, the unescaped identifier of the entity
+    /// will be returned. If this is a collection, the "name" of the collection
+    /// will be returned.
+    pub fn id(self) -> Cow<'a, str> {
+        let &last = self
+            .splits
+            .last()
+            .expect("all names have at least one split");
+
+        if last == 0 {
+            unescape_id(self.inner)
+        } else {
+            unescape_id(&self.inner[(last + 1) as usize..])
+        }
+    }
+
+    /// Is this name the name of a collection?
+    pub fn is_collection(self) -> bool {
+        // As we add the virtual split at the start, each part
+        // starts with a split, meaning that collections, which
+        // have odd parts, have odd splits.
+        self.splits.len() % 2 == 1
+    }
+
+    /// Is this name the name of an entity?
+    pub fn is_entity(self) -> bool {
+        !self.is_collection()
+    }
+
+    /// Returns the next name in a bytewise fashion.
+    pub fn next_bytewise(self) -> ResourceName {
+        let mut inner = self.inner.to_string();
+        let l = inner.pop().expect("name is never empty");
+
+        let next = {
+            let l = l as u32;
+            let mut n = l.saturating_add(1);
+            if l < 0xD800 && 0xD800 <= n {
+                n += 0xD800;
+            }
+
+            char::from_u32(n).expect("invalid next char")
+        };
+
+        inner.push(next);
+
+        ResourceName {
+            inner,
+            splits: Splits::from_slice(self.splits),
+        }
+    }
+
+    pub(crate) fn parts(self) -> impl Iterator<Item = &'a str> {
+        let mut last = *self.splits.last().unwrap();
+        if last > 0 {
+            last += 1;
+        }
+
+        self.splits
+            .windows(2)
+            .map(move |w| self.translate_window(w))
+            .chain(std::iter::once(&self.inner[last as usize..]))
+    }
+
+    pub(crate) fn segments(self) -> impl Iterator<Item = &'a str> {
+        self.parts().step_by(2)
+    }
+
+    fn ids(self) -> impl Iterator<Item = &'a str> {
+        self.parts().skip(1).step_by(2)
+    }
+
+    /// Tests if the parts of this name are a prefix of the parts of `name`,
+    /// where [`WILDCARD`] IDs match any ID.
+    fn matches_prefix_of(self, name: ResourceNameRef<'_>) -> bool {
+        let mut parts = name.parts();
+
+        self.parts().enumerate().all(|(idx, part)| {
+            parts
+                .next()
+                .is_some_and(|other| part == other || (idx % 2 == 1 && part == WILDCARD))
+        })
+    }
+
+    fn translate_window(self, window: &[u16]) -> &'a str {
+        match *window {
+            [0, e] => &self.inner[..e as usize],
+            [s, e] => &self.inner[(s + 1) as usize..e as usize],
+            // Only two elements are returned per iteration.
+            _ => unsafe { std::hint::unreachable_unchecked() },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource_name;
+
+    #[test]
+    fn test_parent_is_borrowed() {
+        let name = resource_name!("users/john/repos/linux");
+        let parent = name.parent().unwrap();
+
+        assert_eq!(parent, resource_name!("users/john"));
+        assert_eq!(parent.as_str().as_ptr(), name.as_ref().as_ptr());
+        assert_eq!(parent.get("users"), "john");
+        assert!(parent.is("users"));
+        assert_eq!(parent.parent(), None);
+    }
+
+    #[test]
+    fn test_into_owned() {
+        let name = resource_name!("users/john/repos");
+        let parent = name.parent().unwrap();
+
+        assert_eq!(parent.into_owned(), resource_name!("users/john"));
+        assert_eq!(
+            parent.child("keys", "main").unwrap(),
+            resource_name!("users/john/keys/main")
+        );
+        assert_eq!(
+            name.as_name_ref().item("linux").unwrap(),
+            resource_name!("users/john/repos/linux")
+        );
+    }
+
+    #[test]
+    fn test_pack_same_as_owned() {
+        let name = resource_name!("users/john/repos/linux");
+
+        assert_eq!(name.as_name_ref().pack_to_vec(), name.pack_to_vec());
+        assert_eq!(
+            (name.parent().unwrap(), 1).pack_to_vec(),
+            (resource_name!("users/john"), 1).pack_to_vec()
+        );
+    }
+}
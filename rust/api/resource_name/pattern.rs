@@ -0,0 +1,507 @@
+use std::{borrow::Cow, collections::HashMap, fmt, str::FromStr};
+
+use pl_api_status::{ErrorResource, Status, StatusOr};
+
+use crate::{escape_id, invalid_name, unescape_id, ResourceName};
+
+/// A resource name pattern, as used in the `google.api.resource` option.
+///
+/// Patterns are made of literal collection identifiers and `{variable}`
+/// parts, e.g. `"users/{user}/repos/{repo}"`. They can be used to check if a
+/// name is of a given resource type, to extract the variables of a name in a
+/// single pass, and to render a name from its variables.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResourceNamePattern {
+    pattern: String,
+    parts: Vec<PatternPart>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum PatternPart {
+    Literal(String),
+    Variable(String),
+}
+
+impl fmt::Display for ResourceNamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl FromStr for ResourceNamePattern {
+    type Err = Status;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        if pattern.is_empty() {
+            return Err(Status::invalid_argument("resource name pattern is empty"));
+        }
+
+        let mut parts = Vec::new();
+        for part in pattern.split('/') {
+            let part =
+                if let Some(variable) = part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                    let valid = variable.starts_with(|c: char| c.is_ascii_lowercase())
+                        && variable
+                            .chars()
+                            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+                    if !valid {
+                        return Err(Status::invalid_argument(format!(
+                            "invalid variable '{variable}' in pattern '{pattern}'"
+                        )));
+                    }
+                    if parts.contains(&PatternPart::Variable(variable.to_string())) {
+                        return Err(Status::invalid_argument(format!(
+                            "duplicated variable '{variable}' in pattern '{pattern}'"
+                        )));
+                    }
+
+                    PatternPart::Variable(variable.to_string())
+                } else {
+                    let mut chars = part.chars();
+                    let valid = chars.next().is_some_and(|c| c.is_ascii_lowercase())
+                        && chars.all(|c| c.is_ascii_alphanumeric());
+                    if !valid {
+                        return Err(Status::invalid_argument(format!(
+                            "invalid collection identifier '{part}' in pattern '{pattern}'"
+                        )));
+                    }
+
+                    PatternPart::Literal(part.to_string())
+                };
+
+            parts.push(part);
+        }
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            parts,
+        })
+    }
+}
+
+impl ResourceNamePattern {
+    /// Get the variables of this pattern, in the order they appear.
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            PatternPart::Variable(variable) => Some(variable.as_str()),
+            PatternPart::Literal(_) => None,
+        })
+    }
+
+    /// Tests if the resource name matches this pattern.
+    pub fn matches(&self, name: &ResourceName) -> bool {
+        self.captures(name, |_, _| {})
+    }
+
+    /// Extract the value of all variables of this pattern from the name.
+    ///
+    /// Values are unescaped IDs, like the ones returned by
+    /// [`ResourceName::id`], and can be passed back to [`Self::render`].
+    ///
+    /// Returns `None` if the name doesn't match the pattern.
+    pub fn extract<'n>(&self, name: &'n ResourceName) -> Option<HashMap<&str, Cow<'n, str>>> {
+        let mut variables = HashMap::with_capacity(self.parts.len() / 2);
+
+        self.captures(name, |variable, value| {
+            variables.insert(variable, unescape_id(value));
+        })
+        .then_some(variables)
+    }
+
+    /// Render a resource name from the values of the variables of this pattern.
+    ///
+    /// Values are IDs, which are escaped like in [`ResourceName::item`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status if a variable of the pattern is missing
+    /// from `variables`, or if the rendered name is not valid, see
+    /// [`ResourceName::parse`].
+    pub fn render<'a>(
+        &self,
+        variables: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> StatusOr<ResourceName> {
+        let variables: HashMap<_, _> = variables.into_iter().collect();
+
+        let mut name = String::with_capacity(self.pattern.len());
+        for part in &self.parts {
+            if !name.is_empty() {
+                name.push('/');
+            }
+
+            match part {
+                PatternPart::Literal(literal) => name.push_str(literal),
+                PatternPart::Variable(variable) => {
+                    let Some(value) = variables.get(variable.as_str()) else {
+                        return Err(Status::invalid_argument(format!(
+                            "missing variable '{variable}' of pattern '{self}'"
+                        )));
+                    };
+
+                    name.push_str(&escape_id(value));
+                }
+            }
+        }
+
+        ResourceName::parse("name", name)
+    }
+
+    /// Walk the name parts, calling `capture` for each variable, and returning
+    /// if the name matches the pattern.
+    fn captures<'p, 'n>(
+        &'p self,
+        name: &'n ResourceName,
+        mut capture: impl FnMut(&'p str, &'n str),
+    ) -> bool {
+        if name.as_ref().is_empty() {
+            return false;
+        }
+
+        let mut parts = name.parts();
+        for pattern_part in &self.parts {
+            let Some(part) = parts.next() else {
+                return false;
+            };
+
+            match pattern_part {
+                PatternPart::Literal(literal) if literal != part => return false,
+                PatternPart::Literal(_) => {}
+                PatternPart::Variable(variable) => capture(variable, part),
+            }
+        }
+
+        parts.next().is_none()
+    }
+}
+
+/// The patterns of a resource type.
+///
+/// A resource can have more than one name pattern, e.g. a resource that may be
+/// owned by an user or by an organization. Patterns are tried in order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResourceNamePatterns(Vec<ResourceNamePattern>);
+
+impl ResourceNamePatterns {
+    /// Parse the patterns of a resource type.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status if any of the patterns is invalid,
+    /// or if there is no pattern.
+    pub fn parse<'a>(patterns: impl IntoIterator<Item = &'a str>) -> StatusOr<Self> {
+        let patterns = patterns
+            .into_iter()
+            .map(str::parse)
+            .collect::<StatusOr<Vec<_>>>()?;
+
+        if patterns.is_empty() {
+            return Err(Status::invalid_argument("resource has no name pattern"));
+        }
+
+        Ok(Self(patterns))
+    }
+
+    /// Get the patterns of the resource.
+    pub fn patterns(&self) -> &[ResourceNamePattern] {
+        &self.0
+    }
+
+    /// Find the first pattern matching the name.
+    pub fn find(&self, name: &ResourceName) -> Option<&ResourceNamePattern> {
+        self.0.iter().find(|pattern| pattern.matches(name))
+    }
+
+    /// Tests if the resource name matches any of the patterns.
+    pub fn matches(&self, name: &ResourceName) -> bool {
+        self.find(name).is_some()
+    }
+
+    /// Extract the variables of the name using the first matching pattern.
+    pub fn extract<'p, 'n>(
+        &'p self,
+        name: &'n ResourceName,
+    ) -> Option<(&'p ResourceNamePattern, HashMap<&'p str, Cow<'n, str>>)> {
+        self.0
+            .iter()
+            .find_map(|pattern| Some((pattern, pattern.extract(name)?)))
+    }
+}
+
+/// A resource name type with a single pattern.
+///
+/// This is implemented by the name types generated from the `google.api.resource`
+/// option of messages, e.g. `RepositoryName { repository }` for the pattern
+/// `repositories/{repository}`.
+pub trait TypedResourceName: Sized {
+    /// The type of the resource, e.g. `grs.patch.labs/Repository`.
+    const RESOURCE_TYPE: &'static str;
+    /// The name pattern of the resource, e.g. `repositories/{repository}`.
+    const PATTERN: &'static str;
+
+    /// Extract the variables of a name matching [`Self::PATTERN`].
+    ///
+    /// Variables hold unescaped IDs, see [`ResourceNamePattern::extract`].
+    ///
+    /// Returns `None` if the name doesn't match the pattern.
+    fn from_resource_name(name: &ResourceName) -> Option<Self>;
+
+    /// Get the unescaped values of the variables of this name, in pattern order.
+    fn variables(&self) -> Vec<(&'static str, &str)>;
+
+    /// Parse and validate a name of this resource type.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status with a `BadRequest` violation for
+    /// `field` if the name is not valid or doesn't match [`Self::PATTERN`].
+    fn parse(field: &str, name: impl Into<String>) -> StatusOr<Self> {
+        let name = ResourceName::parse(field, name)?;
+
+        Self::from_resource_name(&name).ok_or_else(|| {
+            invalid_name(
+                field,
+                format!("'{name}' doesn't match pattern '{}'", Self::PATTERN),
+            )
+        })
+    }
+
+    /// Convert this name into a [`ResourceName`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status if the variables don't form a valid
+    /// name, see [`ResourceName::parse`].
+    fn to_resource_name(&self) -> StatusOr<ResourceName> {
+        let pattern: ResourceNamePattern = Self::PATTERN.parse()?;
+
+        pattern.render(self.variables())
+    }
+}
+
+/// A name type that can be parsed from a field of a message.
+///
+/// This is used by the accessors generated for resource reference fields with
+/// a Rust type set through `pl.api.rust_field_options`, so that violations are
+/// reported for the right field.
+pub trait ParseResourceName: Sized {
+    /// Parse and validate the name in `field`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `INVALID_ARGUMENT` status with a `BadRequest` violation for
+    /// `field` if the name is not valid.
+    fn parse_field(field: &str, name: &str) -> StatusOr<Self>;
+}
+
+impl ParseResourceName for ResourceName {
+    fn parse_field(field: &str, name: &str) -> StatusOr<Self> {
+        Self::parse(field, name)
+    }
+}
+
+impl<T: TypedResourceName> ParseResourceName for T {
+    fn parse_field(field: &str, name: &str) -> StatusOr<Self> {
+        Self::parse(field, name)
+    }
+}
+
+/// Untyped names use the collection of the resource as type, e.g. `repos` for
+/// `users/john/repos/linux`.
+impl ErrorResource for ResourceName {
+    fn resource_type(&self) -> String {
+        if self.is_collection() {
+            self.id().into_owned()
+        } else {
+            self.try_type().unwrap_or_default().to_string()
+        }
+    }
+
+    fn resource_name(&self) -> String {
+        self.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+    use crate::resource_name;
+
+    #[test]
+    fn test_extract() {
+        let pattern: ResourceNamePattern = "users/{user}/repos/{repo}".parse().unwrap();
+        let name = resource_name!("users/john/repos/linux");
+
+        assert!(pattern.matches(&name));
+        assert_eq!(pattern.variables().collect::<Vec<_>>(), ["user", "repo"]);
+
+        let variables = pattern.extract(&name).unwrap();
+        assert_eq!(variables.len(), 2);
+        assert_eq!(variables["user"], "john");
+        assert_eq!(variables["repo"], "linux");
+    }
+
+    #[test]
+    fn test_no_match() {
+        let pattern: ResourceNamePattern = "users/{user}/repos/{repo}".parse().unwrap();
+
+        for name in [
+            resource_name!("users/john"),
+            resource_name!("users/john/repos"),
+            resource_name!("orgs/linux/repos/linux"),
+            resource_name!("users/john/repos/linux/issues/1"),
+            ResourceName::default(),
+        ] {
+            assert!(!pattern.matches(&name), "matched {name}");
+            assert_eq!(pattern.extract(&name), None);
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let pattern: ResourceNamePattern = "users/{user}/repos/{repo}".parse().unwrap();
+
+        let name = pattern
+            .render([("repo", "linux"), ("user", "john")])
+            .unwrap();
+        assert_eq!(name, resource_name!("users/john/repos/linux"));
+
+        let err = pattern.render([("user", "john")]).unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let err = pattern
+            .render([("user", "john"), ("repo", "")])
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_escaped_variables() {
+        let pattern: ResourceNamePattern = "repos/{repo}/branches/{branch}".parse().unwrap();
+
+        let name = pattern
+            .render([("repo", "linux"), ("branch", "feature/foo")])
+            .unwrap();
+        assert_eq!(name.to_string(), "repos/linux/branches/feature%2Ffoo");
+
+        let variables = pattern.extract(&name).unwrap();
+        assert_eq!(variables["branch"], "feature/foo");
+        assert_eq!(name.get("branches"), variables["branch"]);
+
+        let wildcard = pattern.render([("repo", "-"), ("branch", "main")]).unwrap();
+        assert!(wildcard.is_wildcard());
+        assert_eq!(pattern.extract(&wildcard).unwrap()["repo"], "-");
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        for pattern in [
+            "",
+            "users/{user",
+            "users/{User}",
+            "users/{user}/repos/{user}",
+            "Users/{user}",
+            "users//{user}",
+        ] {
+            assert!(
+                pattern.parse::<ResourceNamePattern>().is_err(),
+                "accepted {pattern:?}"
+            );
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct RepoName {
+        user: String,
+        repo: String,
+    }
+
+    impl TypedResourceName for RepoName {
+        const RESOURCE_TYPE: &'static str = "grs.patch.labs/Repo";
+        const PATTERN: &'static str = "users/{user}/repos/{repo}";
+
+        fn from_resource_name(name: &ResourceName) -> Option<Self> {
+            let pattern: ResourceNamePattern = Self::PATTERN.parse().ok()?;
+            let variables = pattern.extract(name)?;
+
+            Some(Self {
+                user: variables["user"].to_string(),
+                repo: variables["repo"].to_string(),
+            })
+        }
+
+        fn variables(&self) -> Vec<(&'static str, &str)> {
+            vec![("user", &self.user), ("repo", &self.repo)]
+        }
+    }
+
+    #[test]
+    fn test_typed_name() {
+        let name = RepoName::parse("parent", "users/john/repos/linux").unwrap();
+        assert_eq!(
+            name,
+            RepoName {
+                user: "john".to_string(),
+                repo: "linux".to_string(),
+            }
+        );
+        assert_eq!(
+            name.to_resource_name().unwrap(),
+            resource_name!("users/john/repos/linux")
+        );
+
+        let name = RepoName {
+            user: "john".to_string(),
+            repo: "linux/stable".to_string(),
+        };
+        let resource_name = name.to_resource_name().unwrap();
+        assert_eq!(resource_name.to_string(), "users/john/repos/linux%2Fstable");
+        assert_eq!(RepoName::from_resource_name(&resource_name).unwrap(), name);
+
+        let err = RepoName::parse("parent", "users/john").unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        assert_eq!(
+            err.details().bad_request().unwrap().field_violations[0].field,
+            "parent"
+        );
+    }
+
+    #[test]
+    fn test_parse_field() {
+        let err = <RepoName as ParseResourceName>::parse_field("repository", "users").unwrap_err();
+        assert_eq!(
+            err.details().bad_request().unwrap().field_violations[0].field,
+            "repository"
+        );
+
+        let name = <ResourceName as ParseResourceName>::parse_field("parent", "users").unwrap();
+        assert_eq!(name, resource_name!("users"));
+    }
+
+    #[test]
+    fn test_error_resource() {
+        let status = Status::not_found_resource(&resource_name!("users/john/repos/linux"));
+        let info = status.details().resource_info().unwrap();
+
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(info.resource_type, "repos");
+        assert_eq!(info.resource_name, "users/john/repos/linux");
+    }
+
+    #[test]
+    fn test_multi_pattern() {
+        let patterns =
+            ResourceNamePatterns::parse(["users/{user}/repos/{repo}", "orgs/{org}/repos/{repo}"])
+                .unwrap();
+        let name = resource_name!("orgs/rust-lang/repos/rust");
+
+        let (pattern, variables) = patterns.extract(&name).unwrap();
+        assert_eq!(pattern, &patterns.patterns()[1]);
+        assert_eq!(variables["org"], "rust-lang");
+        assert_eq!(variables["repo"], "rust");
+
+        assert!(!patterns.matches(&resource_name!("teams/core/repos/rust")));
+        assert!(ResourceNamePatterns::parse([]).is_err());
+    }
+}
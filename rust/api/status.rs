@@ -8,14 +8,26 @@
 //! pairs, the key being a URL that maps to a specific protobuf type, which
 //! is encoded in the value. These payloads can be used to provide semantic
 //! and contextual information about a specific error.
-use std::error::Error;
+use std::{collections::HashMap, error::Error, time::Duration};
 
+use prost::Message;
 use tonic::Code;
 use tonic_types::{ErrorDetails, StatusExt};
 
 /// A type that can be a `T` or [`Status`].
 pub type StatusOr<T> = Result<T, Status>;
 
+/// A resource that can be referenced in the details of a [`Status`].
+///
+/// See [`Status::with_resource_info`].
+pub trait ErrorResource {
+    /// The type of the resource, e.g. `grs.patch.labs/Repository`.
+    fn resource_type(&self) -> String;
+
+    /// The name of the resource, e.g. `repositories/linux`.
+    fn resource_name(&self) -> String;
+}
+
 /// A standard gRPC status. Incremented with a [`google.rpc.Status`].
 ///
 /// This is the default error type used through out the system. The
@@ -64,6 +76,19 @@ impl From<Status> for tonic::Status {
     }
 }
 
+impl From<Status> for tonic_types::Status {
+    fn from(s: Status) -> Self {
+        let status = tonic::Status::from(s);
+
+        // The details of a tonic status are the whole encoded `google.rpc.Status`.
+        Self::decode(status.details()).unwrap_or_else(|_| Self {
+            code: status.code() as i32,
+            message: status.message().to_owned(),
+            details: vec![],
+        })
+    }
+}
+
 impl Status {
     fn new(code: Code, message: String) -> Self {
         Self(Box::new(Inner {
@@ -93,16 +118,93 @@ impl Status {
     pub fn details_mut(&mut self) -> &mut ErrorDetails {
         &mut self.0.details
     }
+
+    /// Add a `BadRequest` violation for `field` to the details.
+    pub fn with_field_violation(
+        mut self,
+        field: impl Into<String>,
+        description: impl Into<String>,
+    ) -> Self {
+        self.details_mut()
+            .add_bad_request_violation(field, description);
+        self
+    }
+
+    /// Set the `ResourceInfo` of the details to `resource`, using the status
+    /// message as description.
+    pub fn with_resource_info(
+        mut self,
+        resource: &(impl ErrorResource + ?Sized),
+        owner: impl Into<String>,
+    ) -> Self {
+        let description = self.0.message.clone();
+        self.details_mut().set_resource_info(
+            resource.resource_type(),
+            resource.resource_name(),
+            owner,
+            description,
+        );
+        self
+    }
+
+    /// Set the `RetryInfo` of the details, telling clients to wait `delay`
+    /// before retrying.
+    pub fn with_retry_info(mut self, delay: Duration) -> Self {
+        self.details_mut().set_retry_info(Some(delay));
+        self
+    }
+
+    /// Set the `ErrorInfo` of the details.
+    pub fn with_error_info(
+        mut self,
+        reason: impl Into<String>,
+        domain: impl Into<String>,
+        metadata: impl Into<HashMap<String, String>>,
+    ) -> Self {
+        self.details_mut().set_error_info(reason, domain, metadata);
+        self
+    }
+
+    /// Add a `PreconditionFailure` violation to the details.
+    pub fn with_precondition_failure(
+        mut self,
+        violation_type: impl Into<String>,
+        subject: impl Into<String>,
+        description: impl Into<String>,
+    ) -> Self {
+        self.details_mut()
+            .add_precondition_failure_violation(violation_type, subject, description);
+        self
+    }
+
+    /// Add a `QuotaFailure` violation to the details.
+    pub fn with_quota_failure(
+        mut self,
+        subject: impl Into<String>,
+        description: impl Into<String>,
+    ) -> Self {
+        self.details_mut()
+            .add_quota_failure_violation(subject, description);
+        self
+    }
 }
 
 macro_rules! gen_constructors {
-    ($($(#[$meta: meta])* $name: ident => $code: ident,)+) => {
+    ($($(#[$meta: meta])* $name: ident, $resource: ident($msg: literal) => $code: ident,)+) => {
         impl Status {
         $(
             $(#[$meta])*
             pub fn $name(message: impl std::fmt::Display) -> Self {
                 Self::new(Code::$code, message.to_string())
             }
+
+            #[doc = concat!("Same as [`Self::", stringify!($name), "`], for an operation on `resource`.")]
+            ///
+            /// The status includes the `ResourceInfo` of `resource`.
+            pub fn $resource(resource: &(impl ErrorResource + ?Sized)) -> Self {
+                Self::$name(format!($msg, name = resource.resource_name()))
+                    .with_resource_info(resource, "")
+            }
         )+
         }
     };
@@ -110,16 +212,19 @@ macro_rules! gen_constructors {
 
 gen_constructors! {
     /// The operation was cancelled
-    cancelled => Cancelled,
+    cancelled,
+    cancelled_resource("operation on resource '{name}' was cancelled") => Cancelled,
 
     /// Unknown error.
-    unknown => Unknown,
+    unknown,
+    unknown_resource("unknown error on resource '{name}'") => Unknown,
 
     /// Client specified an invalid argument.
     ///
     /// Note that this differs from `FailedPrecondition`. `InvalidArgument`
     /// indicates arguments that are problematic regardless of the state of the system.
-    invalid_argument => InvalidArgument,
+    invalid_argument,
+    invalid_argument_resource("resource '{name}' is invalid") => InvalidArgument,
 
     /// Deadline expired before operation coud complete.
     ///
@@ -127,24 +232,29 @@ gen_constructors! {
     /// be returned even if the operation has completed successfully. For
     /// example, a successful response from a server could have been delayed
     /// long enough for the deadline to expire.
-    deadline_exceeded => DeadlineExceeded,
+    deadline_exceeded,
+    deadline_exceeded_resource("deadline exceeded for resource '{name}'") => DeadlineExceeded,
 
     /// Some requested entity was not found.
-    not_found => NotFound,
+    not_found,
+    not_found_resource("resource '{name}' not found") => NotFound,
 
     /// Some entity that we attempted to create already exists.
-    already_exists => AlreadyExists,
+    already_exists,
+    already_exists_resource("resource '{name}' already exists") => AlreadyExists,
 
     /// The caller does not have permission to execute the specified operation.
     ///
     /// This should not be used for rejections caused by exhauting some resource, use
     /// `ResoureceExhausted` instead. It should also not be used if we cannot identify
     /// the called, use `Unauthenticated` in this case.
-    permission_denied => PermissionDenied,
+    permission_denied,
+    permission_denied_resource("permission denied on resource '{name}'") => PermissionDenied,
 
     /// Some resource has been exhausted, perhaps a per-user quota, or perhaps
     /// the file system is out of space.
-    resource_exhausted => ResourceExhausted,
+    resource_exhausted,
+    resource_exhausted_resource("quota exhausted for resource '{name}'") => ResourceExhausted,
 
     /// Operation was rejected because the system is not in a state required
     /// for the operation's execution. For example, directory to be deleted
@@ -159,11 +269,13 @@ gen_constructors! {
     /// fails because the direcory is non-empty, `FailedPrecondition` should be
     /// returned since the client should not retry unless they have first fixed
     /// up the directory by deleting files from it.
-    failed_precondition => FailedPrecondition,
+    failed_precondition,
+    failed_precondition_resource("precondition failed for resource '{name}'") => FailedPrecondition,
 
     /// The operation was aborted, typically due to a concurrency issue like
     /// sequecner check failures, transaction aborts, etc.
-    aborted => Aborted,
+    aborted,
+    aborted_resource("operation on resource '{name}' was aborted") => Aborted,
 
     /// Operation was attempted past the valid range. E.g., seeking or reading
     /// past end of file.
@@ -178,24 +290,98 @@ gen_constructors! {
     /// We recommend using `OutOfRange` (the more specific error) when it applies
     /// so that callers who are iterating through a space can easily look for an
     /// `OutOfRange` error to detect when they are done.
-    out_of_range => OutOfRange,
+    out_of_range,
+    out_of_range_resource("out of range access to resource '{name}'") => OutOfRange,
 
     /// Operation is not implemented or not supported/enabled in this service.
-    unimplemented => Unimplemented,
+    unimplemented,
+    unimplemented_resource("operation on resource '{name}' is not implemented") => Unimplemented,
 
     /// Internal errors. Means some invariants expected by underlying system has
     /// been broken.
     ///
     /// If you see one of these erros, something is very broken.
-    internal => Internal,
+    internal,
+    internal_resource("internal error on resource '{name}'") => Internal,
 
     /// The service is currently unavailable. This is most likely a transient condition
     /// and may be corrected by retrying with a back-off.
-    unavailable => Unavailable,
+    unavailable,
+    unavailable_resource("resource '{name}' is unavailable") => Unavailable,
 
     /// Unrecoverable data loss or corruption.
-    data_loss => DataLoss,
+    data_loss,
+    data_loss_resource("data loss on resource '{name}'") => DataLoss,
 
     /// The request does not have valid authentication credentials for the operation.
-    unauthenticated => Unauthenticated,
+    unauthenticated,
+    unauthenticated_resource("unauthenticated access to resource '{name}'") => Unauthenticated,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Repo(&'static str);
+
+    impl ErrorResource for Repo {
+        fn resource_type(&self) -> String {
+            "grs.patch.labs/Repository".to_string()
+        }
+
+        fn resource_name(&self) -> String {
+            format!("repositories/{}", self.0)
+        }
+    }
+
+    #[test]
+    fn test_resource_constructors() {
+        let status = Status::not_found_resource(&Repo("linux"));
+
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.message(), "resource 'repositories/linux' not found");
+
+        let info = status.details().resource_info().unwrap();
+        assert_eq!(info.resource_type, "grs.patch.labs/Repository");
+        assert_eq!(info.resource_name, "repositories/linux");
+        assert_eq!(info.description, status.message());
+
+        let status = Status::already_exists_resource(&Repo("linux"));
+        assert_eq!(status.code(), Code::AlreadyExists);
+    }
+
+    #[test]
+    fn test_builders() {
+        let status = Status::invalid_argument("invalid request")
+            .with_field_violation("name", "name is empty")
+            .with_field_violation("parent", "parent is empty")
+            .with_precondition_failure("ETAG", "repositories/linux", "etag mismatch")
+            .with_quota_failure("users/john", "too many repositories")
+            .with_retry_info(Duration::from_secs(5))
+            .with_error_info(
+                "EMPTY_NAME",
+                "grs.patch.labs",
+                HashMap::from([("field".to_string(), "name".to_string())]),
+            );
+
+        let details = status.details();
+        assert_eq!(details.bad_request().unwrap().field_violations.len(), 2);
+        assert_eq!(
+            details.precondition_failure().unwrap().violations[0].subject,
+            "repositories/linux"
+        );
+        assert_eq!(
+            details.quota_failure().unwrap().violations[0].description,
+            "too many repositories"
+        );
+        assert_eq!(
+            details.retry_info().unwrap().retry_delay,
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(details.error_info().unwrap().reason, "EMPTY_NAME");
+
+        let status = tonic::Status::from(status);
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(!status.details().is_empty());
+    }
 }
@@ -10,6 +10,19 @@ pub enum DbError<E> {
 
 pub type DbResult<T, E> = Result<T, DbError<E>>;
 
+impl<E> DbError<E> {
+    /// Maps the abort error using the given function, keeping storage errors as is.
+    ///
+    /// Useful to convert errors returned by layers into the error type used by
+    /// the transaction.
+    pub fn map_abort<F>(self, f: impl FnOnce(E) -> F) -> DbError<F> {
+        match self {
+            DbError::Abort(err) => DbError::Abort(f(err)),
+            DbError::Storage(err) => DbError::Storage(err),
+        }
+    }
+}
+
 #[doc(hidden)]
 #[derive(Debug)]
 pub enum Infallible {}
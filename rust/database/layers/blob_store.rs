@@ -0,0 +1,743 @@
+//! # Content-addressed blob store
+//!
+//! This layer stores git object data keyed by their OID. As objects are
+//! content-addressed, the same OID always has the same data, meaning that
+//! an object stored by a repository is reused by all other repositories
+//! that have the same object, e.g. forks.
+//!
+//! Object data is split into chunks, which are written in multiple
+//! transactions, so that large objects don't exceed the transaction
+//! size limit. Reads can start at any offset, which allows streaming
+//! objects without loading them fully into memory.
+//!
+//! Blobs are reference counted, and blobs without references are removed
+//! by [`BlobStore::collect_garbage`].
+use std::{
+    error::Error,
+    fmt, io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use foundationdb::{tuple::Subspace, RangeOption};
+use futures_util::Stream;
+use pl_api_status::Status;
+use pl_database::{Db, DbError, DbResult, StorageError, Tx};
+
+/// Size of each chunk of a blob.
+///
+/// FoundationDB limits values to 100 kB, but recommends keeping them
+/// under 10 kB for best performance.
+const CHUNK_SIZE: usize = 10_000;
+
+/// How many chunks are written or read in a single transaction.
+///
+/// This keeps transactions around 1 MB, well under the 10 MB limit.
+const CHUNKS_PER_TX: usize = 100;
+
+/// The blob header was written, but not all of its chunks.
+const STATE_UPLOADING: u8 = 0;
+
+/// All chunks of the blob were written.
+const STATE_COMPLETE: u8 = 1;
+
+/// A store of content-addressed blobs.
+pub struct BlobStore {
+    headers: Subspace,
+    chunks: Subspace,
+    refs: Subspace,
+    /// When blobs without references were marked as garbage, by OID.
+    garbage: Subspace,
+    /// Empty values, keyed by `(marked at, OID)` of the garbage markers.
+    ///
+    /// Markers replaced or removed later leave their entries behind, which are
+    /// skipped and removed by [`BlobStore::collect_garbage`].
+    garbage_by_time: Subspace,
+}
+
+/// Header of a blob, stored in `headers`.
+///
+/// Encoded as `[state u8][length u64][chunk size u32]`, little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlobHeader {
+    state: u8,
+    length: u64,
+    chunk_size: u32,
+}
+
+impl BlobStore {
+    /// Create a new blob store with a static name.
+    ///
+    /// The name must not clash with any other layer stored in the same
+    /// database, as it is used as the prefix of all keys of the store.
+    pub fn from_static(name: &'static str) -> Self {
+        let root = Subspace::from_bytes(name.as_bytes());
+
+        Self {
+            headers: root.subspace(&"h"),
+            chunks: root.subspace(&"c"),
+            refs: root.subspace(&"r"),
+            garbage: root.subspace(&"g"),
+            garbage_by_time: root.subspace(&"t"),
+        }
+    }
+
+    /// Write a blob into the store.
+    ///
+    /// This does nothing if the blob is already in the store, besides giving it
+    /// a new grace period if it has no references. Otherwise, the data is written
+    /// in multiple transactions, and only becomes visible after all of them
+    /// commit. Concurrent writes of the same blob are safe, as they always write
+    /// the same data.
+    ///
+    /// New blobs don't have any references. Add one with [`Self::add_ref`]
+    /// before the grace period given to [`Self::collect_garbage`] ends. Uploads
+    /// are collectable too, so interrupted ones don't leak their chunks.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BlobError::NotFound`] if the upload is collected before it
+    /// finishes, which happens if a single transaction takes longer than the
+    /// grace period, and an error if any of the transactions fail.
+    pub async fn write<E>(&self, db: &Db, oid: &[u8], data: &[u8]) -> Result<(), E>
+    where
+        E: From<StorageError> + From<BlobError> + Error,
+    {
+        let header = BlobHeader {
+            state: STATE_UPLOADING,
+            length: data.len() as u64,
+            chunk_size: CHUNK_SIZE as u32,
+        };
+
+        let exists = db
+            .transaction(|mut tx| async move {
+                let exists = self
+                    .start_upload(&mut tx, oid, header)
+                    .await
+                    .map_err(|e| e.map_abort(E::from))?;
+
+                Ok((exists, tx)) as DbResult<_, E>
+            })
+            .await?;
+
+        if exists {
+            return Ok(());
+        }
+
+        let chunks = data.chunks(CHUNK_SIZE).collect::<Vec<_>>();
+        for (batch_idx, batch) in chunks.chunks(CHUNKS_PER_TX).enumerate() {
+            let uploading: bool = db
+                .transaction(|mut tx| async move {
+                    let uploading = self
+                        .continue_upload(&mut tx, oid, header)
+                        .await
+                        .map_err(|e| e.map_abort(E::from))?;
+
+                    if uploading {
+                        for (i, chunk) in batch.iter().enumerate() {
+                            let chunk_idx = (batch_idx * CHUNKS_PER_TX + i) as u32;
+                            tx.set(&self.chunks.pack(&(oid, chunk_idx)), chunk);
+                        }
+                    }
+
+                    Ok((uploading, tx)) as DbResult<_, E>
+                })
+                .await?;
+
+            // A concurrent write completed the blob.
+            if !uploading {
+                return Ok(());
+            }
+        }
+
+        db.transaction(|mut tx| async move {
+            let uploading = self
+                .continue_upload(&mut tx, oid, header)
+                .await
+                .map_err(|e| e.map_abort(E::from))?;
+
+            if uploading {
+                let complete = BlobHeader {
+                    state: STATE_COMPLETE,
+                    ..header
+                };
+                tx.set(&self.headers.pack(&oid), &complete.encode());
+            }
+
+            Ok(((), tx)) as DbResult<_, E>
+        })
+        .await
+    }
+
+    /// Get the length of a blob, returning `None` if it is not in the store.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database operation fails or if the blob header
+    /// is corrupted.
+    pub async fn len(&self, tx: &Tx, oid: &[u8]) -> DbResult<Option<u64>, BlobError> {
+        let header = self.header(tx, oid).await?;
+
+        Ok(header
+            .filter(|h| h.state == STATE_COMPLETE)
+            .map(|h| h.length))
+    }
+
+    /// Read up to `len` bytes of a blob starting at `offset`.
+    ///
+    /// Returns `None` if the blob is not in the store, and less than `len`
+    /// bytes if the blob ends before `offset + len`. Keep the reads small,
+    /// they need to fit in a single transaction. Use [`Self::stream`] to read
+    /// whole blobs.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BlobError::OutOfRange`] if `offset` is past the end of the
+    /// blob, and [`BlobError::InvalidData`] if the blob is corrupted.
+    pub async fn read_at(
+        &self,
+        tx: &Tx,
+        oid: &[u8],
+        offset: u64,
+        len: usize,
+    ) -> DbResult<Option<Vec<u8>>, BlobError> {
+        let Some(header) = self
+            .header(tx, oid)
+            .await?
+            .filter(|h| h.state == STATE_COMPLETE)
+        else {
+            return Ok(None);
+        };
+
+        if offset > header.length {
+            return Err(DbError::Abort(BlobError::OutOfRange {
+                offset,
+                length: header.length,
+            }));
+        }
+
+        let end = header.length.min(offset.saturating_add(len as u64));
+        let chunk_size = header.chunk_size as u64;
+        let first_chunk = (offset / chunk_size) as u32;
+        let last_chunk = ((end + chunk_size - 1) / chunk_size) as u32;
+
+        let mut data = Vec::with_capacity((end - offset) as usize);
+        let mut chunk_start = first_chunk as u64 * chunk_size;
+
+        let begin_key = self.chunks.pack(&(oid, first_chunk));
+        let end_key = self.chunks.pack(&(oid, last_chunk));
+        tx.for_each_in_range(RangeOption::from((begin_key, end_key)), |_, chunk| {
+            let from = offset.saturating_sub(chunk_start) as usize;
+            let to = ((end - chunk_start) as usize).min(chunk.len());
+            if from < to {
+                data.extend_from_slice(&chunk[from..to]);
+            }
+            chunk_start += chunk_size;
+
+            std::future::ready(Ok(true)) as std::future::Ready<DbResult<_, BlobError>>
+        })
+        .await?;
+
+        if data.len() as u64 != end - offset {
+            return Err(DbError::Abort(BlobError::InvalidData(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing blob chunks in database!",
+            ))));
+        }
+
+        Ok(Some(data))
+    }
+
+    /// Stream the data of a blob starting at `offset`.
+    ///
+    /// Each item is read in its own transaction. The stream is empty if the
+    /// blob is not in the store.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Self::read_at`] apply to each item.
+    pub fn stream<'s>(
+        &'s self,
+        db: &'s Db,
+        oid: &'s [u8],
+        offset: u64,
+    ) -> impl Stream<Item = Result<Vec<u8>, Status>> + 's {
+        futures_util::stream::try_unfold(offset, move |offset| async move {
+            let data = db
+                .transaction(|tx| async move {
+                    let data = self
+                        .read_at(&tx, oid, offset, CHUNK_SIZE * CHUNKS_PER_TX)
+                        .await
+                        .map_err(|err| err.map_abort(Status::from))?;
+
+                    Ok((data, tx))
+                })
+                .await?;
+
+            Ok(data.filter(|d| !d.is_empty()).map(|d| {
+                let next_offset = offset + d.len() as u64;
+                (d, next_offset)
+            }))
+        })
+    }
+
+    /// Add a reference to a blob, preventing it from being collected.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BlobError::NotFound`] if the blob is not in the store.
+    pub async fn add_ref(&self, tx: &mut Tx, oid: &[u8]) -> DbResult<(), BlobError> {
+        if self.len(tx, oid).await?.is_none() {
+            return Err(DbError::Abort(BlobError::NotFound));
+        }
+
+        // Adding references doesn't read the count or the garbage marker, so
+        // it doesn't conflict.
+        tx.atomic_add(&self.refs.pack(&oid), 1);
+        tx.clear(&self.garbage.pack(&oid));
+
+        Ok(())
+    }
+
+    /// Remove a reference from a blob.
+    ///
+    /// After the last reference is removed, the blob will be removed by the
+    /// next garbage collection.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BlobError::NotFound`] if the blob has no references.
+    pub async fn remove_ref(&self, tx: &mut Tx, oid: &[u8]) -> DbResult<(), BlobError> {
+        let count = self.ref_count(tx, oid).await?;
+
+        match count {
+            0 => return Err(DbError::Abort(BlobError::NotFound)),
+            1 => {
+                tx.clear(&self.refs.pack(&oid));
+                self.mark_garbage(tx, oid);
+            }
+            _ => tx.atomic_add(&self.refs.pack(&oid), -1),
+        }
+
+        Ok(())
+    }
+
+    /// Get how many references a blob has.
+    pub async fn ref_count(&self, tx: &Tx, oid: &[u8]) -> DbResult<u64, BlobError> {
+        let Some(bytes) = tx.get(&self.refs.pack(&oid)).await? else {
+            return Ok(0);
+        };
+
+        let bytes = <[u8; 8]>::try_from(&*bytes).map_err(|_| {
+            DbError::Abort(BlobError::InvalidData(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid blob reference count in database!",
+            )))
+        })?;
+
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Remove the blobs that have no references for at least `grace`, looking
+    /// at up to `limit` of them, oldest first.
+    ///
+    /// The grace period protects blobs that were just written, but still didn't
+    /// have their first reference added. Returns how many blobs were removed,
+    /// which may be less than `limit` even if there are more collectable blobs,
+    /// as blobs referenced again also count against it. Call this again until it
+    /// returns 0 to collect all of them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the transaction fails or if a garbage marker is
+    /// corrupted.
+    pub async fn collect_garbage(
+        &self,
+        tx: &mut Tx,
+        grace: Duration,
+        limit: usize,
+    ) -> DbResult<usize, BlobError> {
+        let deadline = unix_now().saturating_sub(grace.as_secs());
+
+        let mut entries = vec![];
+        let opts = RangeOption {
+            limit: Some(limit),
+            ..RangeOption::from((
+                self.garbage_by_time.range().0,
+                self.garbage_by_time.pack(&(deadline + 1,)),
+            ))
+        };
+        tx.for_each_in_range(opts, |key, _| {
+            entries.push(key.to_vec());
+            std::future::ready(Ok(true)) as std::future::Ready<DbResult<_, BlobError>>
+        })
+        .await?;
+
+        let mut collected = 0;
+        for entry in &entries {
+            let Ok((marked_at, oid)) = self.garbage_by_time.unpack::<(u64, Vec<u8>)>(entry) else {
+                return Err(DbError::Abort(BlobError::InvalidData(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid blob garbage key in database!",
+                ))));
+            };
+            tx.clear(entry);
+
+            // Skip blobs referenced or marked again since the entry was written.
+            let marker_key = self.garbage.pack(&oid.as_slice());
+            let marker = tx.get(&marker_key).await?;
+            if marker.as_deref() != Some(&marked_at.to_le_bytes()[..]) {
+                continue;
+            }
+
+            let (begin, end) = self.chunks.subspace(&oid.as_slice()).range();
+            tx.clear_range(&begin, &end)?;
+            tx.clear(&self.headers.pack(&oid.as_slice()));
+            tx.clear(&marker_key);
+
+            collected += 1;
+        }
+
+        Ok(collected)
+    }
+
+    /// Mark a blob as garbage, starting its grace period from now.
+    fn mark_garbage(&self, tx: &mut Tx, oid: &[u8]) {
+        let now = unix_now();
+
+        tx.set(&self.garbage.pack(&oid), &now.to_le_bytes());
+        tx.set(&self.garbage_by_time.pack(&(now, oid)), &[]);
+    }
+
+    /// Start the upload of a blob, returning if it is already in the store.
+    ///
+    /// The blob is marked as garbage until its first reference is added, so
+    /// that an interrupted upload is collected with its chunks.
+    async fn start_upload(
+        &self,
+        tx: &mut Tx,
+        oid: &[u8],
+        header: BlobHeader,
+    ) -> DbResult<bool, BlobError> {
+        let exists = match self.header(tx, oid).await? {
+            Some(h) if h.state == STATE_COMPLETE => true,
+            // A concurrent write with the same chunk size is writing
+            // the same data, we can just overwrite it.
+            Some(h) if h == header => false,
+            _ => {
+                let (begin, end) = self.chunks.subspace(&oid).range();
+                tx.clear_range(&begin, &end)?;
+                tx.set(&self.headers.pack(&oid), &header.encode());
+                false
+            }
+        };
+
+        // This also restarts the grace period of unreferenced blobs that are
+        // written again, as the caller is about to reference them.
+        if self.ref_count(tx, oid).await? == 0 {
+            self.mark_garbage(tx, oid);
+        }
+
+        Ok(exists)
+    }
+
+    /// Check that the upload of a blob is still running, returning `false` if
+    /// a concurrent write completed it.
+    ///
+    /// The grace period of the upload is restarted, so that it is not collected
+    /// while making progress.
+    async fn continue_upload(
+        &self,
+        tx: &mut Tx,
+        oid: &[u8],
+        header: BlobHeader,
+    ) -> DbResult<bool, BlobError> {
+        match self.header(tx, oid).await? {
+            Some(h) if h == header => {
+                self.mark_garbage(tx, oid);
+                Ok(true)
+            }
+            Some(h) if h.state == STATE_COMPLETE => Ok(false),
+            // The upload was collected.
+            _ => Err(DbError::Abort(BlobError::NotFound)),
+        }
+    }
+
+    async fn header(&self, tx: &Tx, oid: &[u8]) -> DbResult<Option<BlobHeader>, BlobError> {
+        let Some(bytes) = tx.get(&self.headers.pack(&oid)).await? else {
+            return Ok(None);
+        };
+
+        BlobHeader::decode(&bytes).map(Some).ok_or_else(|| {
+            DbError::Abort(BlobError::InvalidData(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid blob header in database!",
+            )))
+        })
+    }
+}
+
+impl BlobHeader {
+    fn encode(&self) -> [u8; 13] {
+        let mut bytes = [0; 13];
+        bytes[0] = self.state;
+        bytes[1..9].copy_from_slice(&self.length.to_le_bytes());
+        bytes[9..].copy_from_slice(&self.chunk_size.to_le_bytes());
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes = <&[u8; 13]>::try_from(bytes).ok()?;
+
+        Some(Self {
+            state: bytes[0],
+            length: u64::from_le_bytes(bytes[1..9].try_into().unwrap()),
+            chunk_size: u32::from_le_bytes(bytes[9..].try_into().unwrap()),
+        })
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Errors returned by [`BlobStore`] operations.
+#[derive(Debug)]
+pub enum BlobError {
+    /// The blob is not in the store.
+    NotFound,
+    /// A read started past the end of the blob.
+    OutOfRange {
+        /// The offset of the read.
+        offset: u64,
+        /// The length of the blob.
+        length: u64,
+    },
+    /// The data stored in the database is corrupted.
+    InvalidData(io::Error),
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => f.write_str("blob not found"),
+            Self::OutOfRange { offset, length } => {
+                write!(f, "offset {offset} is past the blob end at {length}")
+            }
+            Self::InvalidData(err) => write!(f, "invalid blob data: {err}"),
+        }
+    }
+}
+
+impl Error for BlobError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidData(err) => Some(err),
+            Self::NotFound | Self::OutOfRange { .. } => None,
+        }
+    }
+}
+
+impl From<BlobError> for Status {
+    fn from(err: BlobError) -> Self {
+        match err {
+            BlobError::NotFound => Status::not_found(err),
+            BlobError::OutOfRange { .. } => Status::out_of_range(err),
+            BlobError::InvalidData(err) => Status::data_loss(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+    use pl_database::{run, try_run};
+    use tonic::Code;
+
+    use super::*;
+
+    const OID: &[u8] = b"0123456789abcdef0123";
+
+    fn data() -> Vec<u8> {
+        (0..CHUNK_SIZE * CHUNKS_PER_TX * 2 + 123)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    /// Pretend that a blob was marked as garbage long ago.
+    async fn mark_long_ago(db: &Db, blobs: &BlobStore, oid: &[u8]) {
+        db.transaction(|mut tx| async move {
+            tx.set(&blobs.garbage.pack(&oid), &0_u64.to_le_bytes());
+            tx.set(&blobs.garbage_by_time.pack(&(0_u64, oid)), &[]);
+            Ok::<_, DbError<Status>>(((), tx))
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_and_read() {
+        let db = Db::temporary();
+        let blobs = &BlobStore::from_static("blobs");
+        let data = &data();
+
+        blobs.write::<Status>(&db, OID, data).await.unwrap();
+        // Writing again is a no-op.
+        blobs.write::<Status>(&db, OID, data).await.unwrap();
+
+        let len = run!(db, |tx| blobs.len(&tx, OID));
+        assert_eq!(len, Some(data.len() as u64));
+
+        let offset = CHUNK_SIZE as u64 + 17;
+        let read = run!(db, |tx| blobs.read_at(&tx, OID, offset, 2 * CHUNK_SIZE));
+        assert_eq!(
+            read.as_deref(),
+            Some(&data[offset as usize..offset as usize + 2 * CHUNK_SIZE])
+        );
+
+        let streamed = blobs
+            .stream(&db, OID, 5)
+            .try_concat()
+            .await
+            .expect("failed to stream blob");
+        assert_eq!(streamed, data[5..]);
+
+        let err = try_run!(db, |tx| blobs.read_at(&tx, OID, data.len() as u64 + 1, 1))
+            .expect_err("read past the end");
+        assert_eq!(err.code(), Code::OutOfRange);
+
+        let missing = run!(db, |tx| blobs.read_at(&tx, b"missing", 0, 1));
+        assert_eq!(missing, None);
+    }
+
+    #[tokio::test]
+    async fn test_garbage_collection() {
+        let db = Db::temporary();
+        let blobs = &BlobStore::from_static("blobs");
+        let data = &data();
+
+        blobs.write::<Status>(&db, OID, data).await.unwrap();
+
+        // Referenced blobs are not collected.
+        run!(db, |mut tx| blobs.add_ref(&mut tx, OID));
+        run!(db, |mut tx| blobs.add_ref(&mut tx, OID));
+        run!(db, |mut tx| blobs.remove_ref(&mut tx, OID));
+
+        let collected = run!(db, |mut tx| blobs.collect_garbage(
+            &mut tx,
+            Duration::ZERO,
+            10
+        ));
+        assert_eq!(collected, 0);
+
+        run!(db, |mut tx| blobs.remove_ref(&mut tx, OID));
+
+        // Recently unreferenced blobs are kept during the grace period.
+        let collected = run!(db, |mut tx| blobs.collect_garbage(
+            &mut tx,
+            Duration::from_secs(3600),
+            10
+        ));
+        assert_eq!(collected, 0);
+
+        let collected = run!(db, |mut tx| blobs.collect_garbage(
+            &mut tx,
+            Duration::ZERO,
+            10
+        ));
+        assert_eq!(collected, 1);
+
+        let len = run!(db, |tx| blobs.len(&tx, OID));
+        assert_eq!(len, None);
+
+        let err = try_run!(db, |mut tx| blobs.add_ref(&mut tx, OID))
+            .expect_err("added reference to collected blob");
+        assert_eq!(err.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_upload() {
+        let db = Db::temporary();
+        let blobs = &BlobStore::from_static("blobs");
+        let header = BlobHeader {
+            state: STATE_UPLOADING,
+            length: 1,
+            chunk_size: CHUNK_SIZE as u32,
+        };
+
+        let exists = run!(db, |mut tx| blobs.start_upload(&mut tx, OID, header));
+        assert!(!exists);
+
+        let collected = run!(db, |mut tx| blobs.collect_garbage(
+            &mut tx,
+            Duration::ZERO,
+            10
+        ));
+        assert_eq!(collected, 1);
+
+        let header = run!(db, |tx| blobs.header(&tx, OID));
+        assert_eq!(header, None);
+
+        let err = try_run!(db, |mut tx| blobs.continue_upload(
+            &mut tx,
+            OID,
+            BlobHeader {
+                state: STATE_UPLOADING,
+                length: 1,
+                chunk_size: CHUNK_SIZE as u32,
+            }
+        ))
+        .expect_err("continued collected upload");
+        assert_eq!(err.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_unreferenced() {
+        let db = Db::temporary();
+        let blobs = &BlobStore::from_static("blobs");
+
+        blobs.write::<Status>(&db, OID, b"data").await.unwrap();
+
+        mark_long_ago(&db, blobs, OID).await;
+
+        // Writing it again restarts its grace period.
+        blobs.write::<Status>(&db, OID, b"data").await.unwrap();
+
+        let collected = run!(db, |mut tx| blobs.collect_garbage(
+            &mut tx,
+            Duration::from_secs(3600),
+            10
+        ));
+        assert_eq!(collected, 0);
+
+        let len = run!(db, |tx| blobs.len(&tx, OID));
+        assert_eq!(len, Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_collect_oldest_first() {
+        let db = Db::temporary();
+        let blobs = &BlobStore::from_static("blobs");
+
+        // Blobs in their grace period don't hide older garbage, even if their
+        // OIDs sort first.
+        for oid in [b"a", b"b", b"c", b"z"] {
+            blobs.write::<Status>(&db, oid, b"data").await.unwrap();
+        }
+        mark_long_ago(&db, blobs, b"z").await;
+
+        let collected = run!(db, |mut tx| blobs.collect_garbage(
+            &mut tx,
+            Duration::from_secs(3600),
+            1
+        ));
+        assert_eq!(collected, 1);
+
+        let len = run!(db, |tx| blobs.len(&tx, b"z"));
+        assert_eq!(len, None);
+        let len = run!(db, |tx| blobs.len(&tx, b"a"));
+        assert_eq!(len, Some(4));
+    }
+}
@@ -0,0 +1,429 @@
+//! # Change data capture
+//!
+//! Webhooks, search indexing and cache invalidation need to know when entities
+//! change. Writers append a [`ChangeRecord`] to a feed in the same transaction
+//! as the change, meaning that the feed has exactly the changes that committed,
+//! in commit order. Collections do this by themselves, see
+//! `Collection::with_change_feed`.
+//!
+//! Changes are keyed by the versionstamp of their transaction, which always
+//! increases. Each [`Subscriber`] stores the versionstamp of the last change it
+//! processed as a cursor, from where it resumes after restarts. Processing is
+//! at-least-once: update the cursor in the same transaction as the effects of
+//! the changes, if they are in the database, or after them otherwise.
+//!
+//! The feed grows forever, use [`ChangeFeed::trim`] to remove changes that all
+//! subscribers processed.
+use std::{error::Error, fmt, io, time::Duration};
+
+use foundationdb::{
+    tuple::{Subspace, Versionstamp},
+    RangeOption,
+};
+use pl_api_status::Status;
+use pl_database::{Db, DbError, DbResult, StorageError, Tx};
+use prost::Message;
+
+/// Maximum time [`Subscriber::poll`] waits before checking the feed again.
+///
+/// Watches may be lost if the database connection has problems, so don't
+/// trust them blindly.
+const MAX_POLL_WAIT: Duration = Duration::from_secs(30);
+
+/// A log of changes, ordered by commit.
+pub struct ChangeFeed {
+    /// Changes, keyed by the versionstamp of the transaction that made them.
+    log: Subspace,
+    /// Cursors of subscribers, keyed by subscriber name.
+    cursors: Subspace,
+    /// Changed whenever a change is appended, to wake up subscribers.
+    signal: Vec<u8>,
+}
+
+/// A change to an entity.
+#[derive(Clone, PartialEq, Message)]
+pub struct ChangeRecord {
+    /// The name of the collection of the entity.
+    #[prost(string, tag = "1")]
+    pub collection: String,
+    /// The packed key of the entity, relative to the collection subspace.
+    #[prost(bytes = "vec", tag = "2")]
+    pub key: Vec<u8>,
+    /// What happened to the entity.
+    #[prost(enumeration = "ChangeOperation", tag = "3")]
+    pub operation: i32,
+    /// The protobuf-encoded entity before the change, if the feed has values
+    /// and the entity existed.
+    #[prost(bytes = "vec", optional, tag = "4")]
+    pub before: Option<Vec<u8>>,
+    /// The protobuf-encoded entity after the change, if the feed has values
+    /// and the entity still exists.
+    #[prost(bytes = "vec", optional, tag = "5")]
+    pub after: Option<Vec<u8>>,
+}
+
+/// The operation that changed an entity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ChangeOperation {
+    Unspecified = 0,
+    /// The entity was created or updated.
+    Set = 1,
+    /// The entity was permanently removed.
+    Clear = 2,
+    /// The entity was soft-deleted, purging it later is recorded as a
+    /// [`Self::Clear`].
+    SoftDelete = 3,
+    /// The entity was restored after a soft delete.
+    Undelete = 4,
+}
+
+/// A change read from a feed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// The position of the change in the feed, the versionstamp of the
+    /// transaction that made it.
+    pub id: Versionstamp,
+    pub record: ChangeRecord,
+}
+
+/// A reader of a feed, that remembers what it already processed.
+pub struct Subscriber<'f> {
+    feed: &'f ChangeFeed,
+    cursor: Vec<u8>,
+}
+
+impl ChangeFeed {
+    /// Create a new feed with a static name.
+    ///
+    /// The name must not clash with any other layer stored in the same
+    /// database, as it is used as the prefix of all keys of the feed.
+    pub fn from_static(name: &'static str) -> Self {
+        let root = Subspace::from_bytes(name.as_bytes());
+
+        Self {
+            log: root.subspace(&"l"),
+            cursors: root.subspace(&"c"),
+            signal: root.pack(&"s"),
+        }
+    }
+
+    /// Append a change to the feed.
+    ///
+    /// The change is only visible to subscribers after the transaction commits.
+    pub fn append(&self, tx: &mut Tx, record: &ChangeRecord) {
+        let id = tx.next_versionstamp();
+        let key = self.log.pack_with_versionstamp(&(id,));
+
+        tx.set_versionstamped_key(&key, &record.encode_to_vec());
+        tx.atomic_add(&self.signal, 1);
+    }
+
+    /// Read up to `limit` changes, starting right after `after`, or from the
+    /// beginning of the feed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database operation fails or if a change can't
+    /// be decoded.
+    pub async fn read(
+        &self,
+        tx: &Tx,
+        after: Option<&Versionstamp>,
+        limit: usize,
+    ) -> DbResult<Vec<Change>, ChangeFeedError> {
+        let (mut begin, end) = self.log.range();
+        if let Some(after) = after {
+            begin = self.log.pack(&(after,));
+            begin.push(0);
+        }
+
+        let opts = RangeOption {
+            limit: Some(limit),
+            ..RangeOption::from((begin, end))
+        };
+
+        let mut changes = vec![];
+        tx.for_each_in_range(opts, |key, value| {
+            let change = self.decode_change(key, value).map(|change| {
+                changes.push(change);
+                true
+            });
+
+            std::future::ready(change)
+        })
+        .await?;
+
+        Ok(changes)
+    }
+
+    /// Remove all changes up to `up_to`, inclusive.
+    pub fn trim(&self, tx: &mut Tx, up_to: &Versionstamp) -> DbResult<(), ChangeFeedError> {
+        let mut end = self.log.pack(&(up_to,));
+        end.push(0);
+
+        tx.clear_range(&self.log.range().0, &end)?;
+
+        Ok(())
+    }
+
+    /// Get a subscriber of the feed.
+    ///
+    /// Subscribers with the same name share their cursor, use a different name
+    /// for each consumer of the feed.
+    pub fn subscriber(&self, name: &str) -> Subscriber<'_> {
+        Subscriber {
+            feed: self,
+            cursor: self.cursors.pack(&name),
+        }
+    }
+
+    fn decode_change(&self, key: &[u8], value: &[u8]) -> DbResult<Change, ChangeFeedError> {
+        let (id,) = self
+            .log
+            .unpack::<(Versionstamp,)>(key)
+            .map_err(|_| invalid_data("invalid change key in database!"))?;
+        let record = ChangeRecord::decode(value)
+            .map_err(|_| invalid_data("invalid change record in database!"))?;
+
+        Ok(Change { id, record })
+    }
+}
+
+impl Subscriber<'_> {
+    /// Get the id of the last change processed by the subscriber, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database operation fails or if the cursor is
+    /// corrupted.
+    pub async fn cursor(&self, tx: &Tx) -> DbResult<Option<Versionstamp>, ChangeFeedError> {
+        let Some(bytes) = tx.get(&self.cursor).await? else {
+            return Ok(None);
+        };
+
+        let bytes = <[u8; 12]>::try_from(&*bytes)
+            .map_err(|_| invalid_data("invalid subscriber cursor in database!"))?;
+
+        Ok(Some(Versionstamp::from(bytes)))
+    }
+
+    /// Mark all changes up to `id` as processed.
+    pub fn set_cursor(&self, tx: &mut Tx, id: &Versionstamp) {
+        tx.set(&self.cursor, id.as_bytes());
+    }
+
+    /// Read up to `limit` changes after the cursor of the subscriber.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`ChangeFeed::read`] apply here.
+    pub async fn read(&self, tx: &Tx, limit: usize) -> DbResult<Vec<Change>, ChangeFeedError> {
+        let cursor = self.cursor(tx).await?;
+
+        self.feed.read(tx, cursor.as_ref(), limit).await
+    }
+
+    /// Wait until there are changes after the cursor and read up to `limit` of
+    /// them.
+    ///
+    /// This doesn't move the cursor, call [`Self::set_cursor`] after processing
+    /// the changes. Each try runs in its own transaction. Between them, this
+    /// waits for new changes using a watch.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the transactions fail.
+    pub async fn poll<E>(&self, db: &Db, limit: usize) -> Result<Vec<Change>, E>
+    where
+        E: From<StorageError> + From<ChangeFeedError> + Error,
+    {
+        loop {
+            let (changes, watch) = db
+                .transaction(|tx| async move {
+                    let changes = self
+                        .read(&tx, limit)
+                        .await
+                        .map_err(|err| err.map_abort(E::from))?;
+                    if !changes.is_empty() {
+                        return Ok(((changes, None), tx));
+                    }
+
+                    let watch = tx.watch(&self.feed.signal);
+                    Ok(((changes, Some(watch)), tx))
+                })
+                .await?;
+
+            let Some(watch) = watch else {
+                return Ok(changes);
+            };
+
+            if let Ok(Err(DbError::Storage(err))) = tokio::time::timeout(MAX_POLL_WAIT, watch).await
+            {
+                return Err(E::from(err));
+            }
+        }
+    }
+}
+
+fn invalid_data(msg: &'static str) -> DbError<ChangeFeedError> {
+    DbError::Abort(ChangeFeedError::InvalidData(io::Error::new(
+        io::ErrorKind::InvalidData,
+        msg,
+    )))
+}
+
+/// Errors returned by [`ChangeFeed`] operations.
+#[derive(Debug)]
+pub enum ChangeFeedError {
+    /// The data stored in the database is corrupted.
+    InvalidData(io::Error),
+}
+
+impl fmt::Display for ChangeFeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidData(err) => write!(f, "invalid change feed data: {err}"),
+        }
+    }
+}
+
+impl Error for ChangeFeedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidData(err) => Some(err),
+        }
+    }
+}
+
+impl From<ChangeFeedError> for Status {
+    fn from(err: ChangeFeedError) -> Self {
+        match err {
+            ChangeFeedError::InvalidData(err) => Status::data_loss(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn record(key: &str, operation: ChangeOperation) -> ChangeRecord {
+        ChangeRecord {
+            collection: "users".to_string(),
+            key: key.as_bytes().to_vec(),
+            operation: operation as i32,
+            before: None,
+            after: None,
+        }
+    }
+
+    async fn append(db: &Db, feed: &ChangeFeed, records: &[ChangeRecord]) {
+        db.transaction(|mut tx| async move {
+            for record in records {
+                feed.append(&mut tx, record);
+            }
+            Ok(((), tx)) as DbResult<_, Status>
+        })
+        .await
+        .expect("failed to append changes");
+    }
+
+    async fn process(db: &Db, subscriber: &Subscriber<'_>, limit: usize) -> Vec<ChangeRecord> {
+        let changes = subscriber.poll::<Status>(db, limit).await.unwrap();
+
+        db.transaction(|mut tx| {
+            let last = &changes.last().expect("no changes").id;
+            async move {
+                subscriber.set_cursor(&mut tx, last);
+                Ok(((), tx)) as DbResult<_, Status>
+            }
+        })
+        .await
+        .unwrap();
+
+        changes.into_iter().map(|change| change.record).collect()
+    }
+
+    #[tokio::test]
+    async fn test_subscriber() {
+        let db = Db::temporary();
+        let feed = ChangeFeed::from_static("changes");
+        let (a, b, c) = (
+            record("a", ChangeOperation::Set),
+            record("b", ChangeOperation::Set),
+            record("a", ChangeOperation::Clear),
+        );
+
+        append(&db, &feed, &[a.clone(), b.clone()]).await;
+        append(&db, &feed, std::slice::from_ref(&c)).await;
+
+        let indexer = feed.subscriber("indexer");
+        assert_eq!(process(&db, &indexer, 2).await, vec![a.clone(), b.clone()]);
+        assert_eq!(process(&db, &indexer, 2).await, vec![c.clone()]);
+
+        // Other subscribers have their own cursor.
+        let webhooks = feed.subscriber("webhooks");
+        assert_eq!(process(&db, &webhooks, 10).await, vec![a, b, c.clone()]);
+
+        let last = db
+            .transaction(|tx| {
+                let indexer = &indexer;
+                async move {
+                    let cursor = indexer
+                        .cursor(&tx)
+                        .await
+                        .map_err(|err| err.map_abort(Status::from))?;
+                    Ok::<_, DbError<Status>>((cursor, tx))
+                }
+            })
+            .await
+            .unwrap()
+            .expect("cursor wasn't stored");
+
+        db.transaction(|mut tx| {
+            let (feed, last) = (&feed, &last);
+            async move {
+                feed.trim(&mut tx, last)
+                    .map_err(|err| err.map_abort(Status::from))?;
+
+                let changes = feed
+                    .read(&tx, None, 10)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+                assert!(changes.is_empty(), "changes weren't trimmed");
+
+                Ok(((), tx)) as DbResult<_, Status>
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_poll_waits() {
+        let db = Arc::new(Db::temporary());
+        let feed = Arc::new(ChangeFeed::from_static("changes"));
+
+        let poll = tokio::spawn({
+            let (db, feed) = (db.clone(), feed.clone());
+            async move {
+                let subscriber = feed.subscriber("indexer");
+                subscriber.poll::<Status>(&db, 10).await.unwrap()
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let created = record("a", ChangeOperation::Set);
+        append(&db, &feed, std::slice::from_ref(&created)).await;
+
+        let changes = tokio::time::timeout(Duration::from_secs(5), poll)
+            .await
+            .expect("poll didn't wake up")
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].record, created);
+    }
+}
@@ -1,24 +1,103 @@
-use std::{io, marker::PhantomData};
+use std::{
+    cell::RefCell,
+    error::Error,
+    fmt, fs, io,
+    ops::{Deref, DerefMut},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::SystemTime,
+};
 
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
 use foundationdb::{
-    tuple::{Subspace, TuplePack},
-    RangeOption,
+    tuple::{Element, Subspace, TupleDepth, TuplePack, TupleUnpack, VersionstampOffset},
+    KeySelector, RangeOption,
 };
-use pl_database::{DbError, DbResult, Tx};
+use pl_api_resource_name::{escape_id, ResourceName};
+use pl_api_status::Status;
+use pl_database::{unix_millis, Db, DbError, DbResult, IBytes, StorageError, Tx, Watch};
+use pl_database_layers_changefeed::{ChangeFeed, ChangeOperation, ChangeRecord};
 use prost::Message;
 
 /// V1 metadata: protobuf encoded, no extra transformations done.
 const V1_METADATA: u8 = 0;
 
+/// Chunked metadata: the encoded value was split into chunks, stored in
+/// sequential keys. The value is a header with the length of the encoded
+/// value and the number of chunks, both as little-endian `u32`.
+const CHUNKED_METADATA: u8 = 1;
+
+/// V2 metadata: followed by a flags byte with the compression used in the
+/// payload, and the CRC32C checksum of the payload, as a little-endian `u32`.
+const V2_METADATA: u8 = 2;
+
+/// Encrypted metadata: followed by the ID of the key-encryption key, as a
+/// little-endian `u32`, the wrapped data key, and the value encrypted with the
+/// data key. The decrypted value is a V2 value.
+const ENCRYPTED_METADATA: u8 = 3;
+
+/// Length of the nonces used by AES-GCM.
+const NONCE_LEN: usize = 12;
+
+/// Length of a data key wrapped by a key-encryption key: its nonce, the
+/// encrypted 256-bit key and the authentication tag.
+const WRAPPED_KEY_LEN: usize = NONCE_LEN + 32 + 16;
+
+/// Length of the header of V2 values.
+const V2_HEADER_LEN: usize = 6;
+
+/// V2 flags for uncompressed payloads.
+const COMPRESSION_NONE: u8 = 0;
+
+/// V2 flags for payloads compressed with zstd.
+const COMPRESSION_ZSTD: u8 = 1;
+
+/// V2 flags for payloads compressed with LZ4, prefixed by their uncompressed size.
+const COMPRESSION_LZ4: u8 = 2;
+
+/// Size of the chunks of a chunked value.
+///
+/// FoundationDB limits values to 100 kB, but recommends keeping them
+/// under 10 kB for best performance.
+const CHUNK_SIZE: usize = 10_000;
+
+/// Prefix of the keys storing collection metadata, e.g. index entries.
+///
+/// Tuple-encoded values never start with this byte and static collection
+/// names are ASCII, so these keys never collide with an entity key.
+const METADATA_PREFIX: u8 = 0xFE;
+
 pub struct Collection<E> {
+    name: &'static str,
+    subspace: Subspace,
+    revisions: Subspace,
+    chunks: Subspace,
+    chunked: bool,
+    compression: Option<(Compression, usize)>,
+    keyring: Option<Arc<Keyring>>,
+    unique_indexes: Vec<UniqueIndex<E>>,
+    /// Where changes are recorded, and if records include the entities.
+    change_feed: Option<(Arc<ChangeFeed>, bool)>,
+}
+
+/// A secondary index where each value can be owned by only one entity.
+struct UniqueIndex<E> {
+    name: &'static str,
     subspace: Subspace,
-    _marker: PhantomData<E>,
-    // TODO(mempool): use mempool to reduce allocation cost.
-    //
-    // Most of the operations in this type need to allocate
-    // at least the buffer to the key (sometimes multiple).
-    // The cost associated can be reduced by reusing memory
-    // buffers.
+    extractor: IndexExtractor<E>,
+}
+
+type IndexExtractor<E> = Box<dyn Fn(&E) -> Option<IndexValue> + Send + Sync>;
+
+/// A value extracted from an entity by an index.
+#[derive(PartialEq, Eq)]
+struct IndexValue {
+    packed: Vec<u8>,
+    display: String,
 }
 
 impl<E> Collection<E> {
@@ -31,9 +110,383 @@ impl<E> Collection<E> {
     /// as improves the database performance.
     pub fn from_static(name: &'static str) -> Self {
         Self {
+            name,
             subspace: Subspace::from_bytes(name.as_bytes()),
-            _marker: PhantomData,
+            revisions: metadata_subspace(name, "rev"),
+            chunks: metadata_subspace(name, "chunk"),
+            chunked: false,
+            compression: None,
+            keyring: None,
+            unique_indexes: Vec::new(),
+            change_feed: None,
+        }
+    }
+
+    /// Split values larger than the database value size limit into chunks.
+    ///
+    /// Values that fit in a single database value are still stored as is.
+    /// Chunked values are reassembled transparently on reads, but note that
+    /// a whole value still has to fit in a transaction.
+    ///
+    /// Values that were chunked are always readable, but only collections with
+    /// chunked values remove chunks on updates and clears. Don't disable this
+    /// after storing large values in the collection.
+    pub fn with_chunked_values(mut self) -> Self {
+        self.chunked = true;
+        self
+    }
+
+    /// Compress values with encoded size of at least `min_size` bytes.
+    ///
+    /// Values that don't get smaller when compressed are stored as is. Reads
+    /// don't depend on this configuration, values are readable independently
+    /// of the compression used to write them.
+    pub fn with_compression(mut self, compression: Compression, min_size: usize) -> Self {
+        self.compression = Some((compression, min_size));
+        self
+    }
+
+    /// Encrypt values at rest with keys from `keyring`.
+    ///
+    /// Each value is encrypted with its own data key, which is stored encrypted
+    /// with the primary key of the keyring. Values encrypted with other keys of
+    /// the keyring are still readable, use [`Self::rotate_keys`] to re-encrypt
+    /// them after changing the primary key.
+    ///
+    /// Index values and keys are not encrypted, don't use sensitive fields in them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the collection records changes with values, as they would be
+    /// stored unencrypted in the change feed.
+    pub fn with_encryption(mut self, keyring: Arc<Keyring>) -> Self {
+        assert!(
+            !matches!(self.change_feed, Some((_, true))),
+            "collection '{}' can't be encrypted, its change feed has values",
+            self.name
+        );
+
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Record all changes to entities in `feed`, in the transaction that does them.
+    ///
+    /// If `with_values` is set, records include the entity before and after the
+    /// change, which makes every write read the previous value.
+    ///
+    /// Changes that don't affect the entities, e.g. migrations and key rotations,
+    /// aren't recorded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `with_values` is set and the collection is encrypted, as the
+    /// values would be stored unencrypted in the feed.
+    pub fn with_change_feed(mut self, feed: Arc<ChangeFeed>, with_values: bool) -> Self {
+        assert!(
+            !with_values || self.keyring.is_none(),
+            "change feed of encrypted collection '{}' can't have values",
+            self.name
+        );
+
+        self.change_feed = Some((feed, with_values));
+        self
+    }
+
+    /// Declare a unique index in this collection.
+    ///
+    /// The `extractor` returns the indexed value of an entity, or `None` if the
+    /// entity shouldn't be indexed, e.g. when the indexed field is empty. After
+    /// this, [`Self::set`] fails with [`CollectionError::ConstraintViolation`] if
+    /// another entity already owns the value.
+    ///
+    /// Note that indexes are only maintained for writes done after they are
+    /// declared, adding an index to a collection with data requires a backfill.
+    pub fn with_unique_index<K>(
+        mut self,
+        name: &'static str,
+        extractor: impl Fn(&E) -> Option<K> + Send + Sync + 'static,
+    ) -> Self
+    where
+        K: TuplePack + fmt::Display,
+    {
+        self.unique_indexes.push(UniqueIndex {
+            name,
+            subspace: metadata_subspace(self.name, "idx").subspace(&name),
+            extractor: Box::new(move |entity| {
+                extractor(entity).map(|value| IndexValue {
+                    packed: value.pack_to_vec(),
+                    display: value.to_string(),
+                })
+            }),
+        });
+
+        self
+    }
+
+    /// The name of the collection, as used in its errors.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The subspace with the keys of the entities of the collection.
+    ///
+    /// Entity keys are the packed keys given to the collection methods, appended
+    /// to this subspace. Use it to build the ranges given to [`Self::range`].
+    pub fn subspace(&self) -> &Subspace {
+        &self.subspace
+    }
+
+    /// A subspace for the data of a layer built on top of the collection.
+    ///
+    /// Layers wrapping a collection store their own data about its entities
+    /// here, so that they don't need a name of their own. `layer` must be
+    /// unique among the layers used with the collection.
+    pub fn layer_subspace(&self, layer: &str) -> Subspace {
+        metadata_subspace(self.name, "layer").subspace(&layer)
+    }
+
+    /// Get the range of keys listed by [`Self::list_children`], with the
+    /// subspace of the children keys relative to [`Self::subspace`].
+    ///
+    /// The range also covers the grandchildren, layers listing children must
+    /// skip the keys that don't unpack from the subspace as a single ID, e.g.
+    /// those returned by [`Self::range_with_keys`].
+    pub fn children_range(
+        &self,
+        parent: Option<&ResourceName>,
+        collection_id: &str,
+        after: Option<&str>,
+    ) -> (Subspace, (Vec<u8>, Vec<u8>)) {
+        let mut prefix = vec![];
+        if let Some(parent) = parent {
+            parent.pack_into_vec(&mut prefix);
+        }
+        collection_id.pack_into_vec(&mut prefix);
+
+        let children = Subspace::from_bytes([self.subspace.bytes(), &prefix].concat());
+        let (mut begin, end) = children.range();
+        if let Some(after) = after {
+            // Skip the child and everything under it, as no element code is 0xFF.
+            begin = children.pack(&&*escape_id(after));
+            begin.push(0xFF);
+        }
+
+        (Subspace::from_bytes(prefix), (begin, end))
+    }
+}
+
+/// Compression algorithms for collection values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Zstandard, with the default compression level.
+    Zstd,
+    /// LZ4, faster than zstd but with worse compression ratios.
+    Lz4,
+}
+
+impl Compression {
+    fn flags(self) -> u8 {
+        match self {
+            Self::Zstd => COMPRESSION_ZSTD,
+            Self::Lz4 => COMPRESSION_LZ4,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL).ok(),
+            Self::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+}
+
+/// The key-encryption keys used by encrypted collections.
+///
+/// Keyrings are loaded from files with one key per line, in the format
+/// `<id> <hex-encoded 256-bit key>`. Empty lines and lines starting with `#`
+/// are ignored. The last key is the primary one, used to encrypt new values.
+pub struct Keyring {
+    keys: Vec<(u32, Aes256Gcm)>,
+}
+
+impl Keyring {
+    /// Load a keyring from a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read, or if it isn't a valid keyring.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    fn primary(&self) -> (u32, &Aes256Gcm) {
+        let (id, key) = self.keys.last().expect("keyrings are never empty");
+        (*id, key)
+    }
+
+    fn get(&self, id: u32) -> Option<&Aes256Gcm> {
+        self.keys
+            .iter()
+            .find_map(|(key_id, key)| (*key_id == id).then_some(key))
+    }
+}
+
+impl FromStr for Keyring {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut keys = Vec::new();
+
+        for (lineno, line) in s.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (id, hex) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid(format!("line {lineno}: expected '<id> <key>'")))?;
+            let id: u32 = id
+                .parse()
+                .map_err(|_| invalid(format!("line {lineno}: invalid key ID '{id}'")))?;
+
+            if keys.iter().any(|(key_id, _)| *key_id == id) {
+                return Err(invalid(format!("line {lineno}: duplicated key ID {id}")));
+            }
+
+            let key = decode_hex(hex.trim())
+                .and_then(|key| Aes256Gcm::new_from_slice(&key).ok())
+                .ok_or_else(|| invalid(format!("line {lineno}: invalid 256-bit key")))?;
+
+            keys.push((id, key));
+        }
+
+        if keys.is_empty() {
+            return Err(invalid("keyring has no keys".to_string()));
+        }
+
+        Ok(Self { keys })
+    }
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the keys.
+        f.debug_struct("Keyring")
+            .field(
+                "ids",
+                &self.keys.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The subspace where a collection stores metadata of a given kind.
+fn metadata_subspace(collection: &str, kind: &str) -> Subspace {
+    Subspace::from_bytes(&[METADATA_PREFIX][..]).subspace(&(collection, kind))
+}
+
+/// Maximum number of buffers kept in the pool of each thread.
+const MAX_POOLED_BUFFERS: usize = 32;
+
+/// Buffers with a larger capacity aren't returned to the pool, so that a few
+/// large values don't keep memory allocated forever.
+const MAX_POOLED_CAPACITY: usize = 16 * 1024;
+
+thread_local! {
+    static BUFFER_POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    #[cfg(any(test, feature = "bench"))]
+    static BUFFER_POOLING: std::cell::Cell<bool> = const { std::cell::Cell::new(true) };
+}
+
+/// Enable or disable the buffer pool of the current thread.
+///
+/// Pooling is enabled by default. This is only meant to measure the pool
+/// against a baseline, so it needs the `bench` feature, which the
+/// `collection_bench` target enables.
+#[cfg(any(test, feature = "bench"))]
+pub fn set_buffer_pooling(enabled: bool) {
+    BUFFER_POOLING.with(|pooling| pooling.set(enabled));
+    if !enabled {
+        BUFFER_POOL.with(|pool| pool.borrow_mut().clear());
+    }
+}
+
+/// Whether dropped buffers return to the pool, see [`set_buffer_pooling`].
+#[cfg(any(test, feature = "bench"))]
+fn buffer_pooling() -> bool {
+    BUFFER_POOLING.with(|pooling| pooling.get())
+}
+
+/// Whether dropped buffers return to the pool, which is always the case
+/// without the `bench` feature.
+#[cfg(not(any(test, feature = "bench")))]
+fn buffer_pooling() -> bool {
+    true
+}
+
+/// A buffer from the thread-local pool, returned to it when dropped.
+///
+/// Most operations need to allocate at least the buffer to the key, and writes
+/// another one for the encoded value. Reusing buffers removes these allocations
+/// from hot paths, e.g. ref lookups. Futures holding buffers may move between
+/// threads, which is fine, as the buffer is then returned to another pool.
+struct PooledBuf(Vec<u8>);
+
+impl PooledBuf {
+    fn new() -> Self {
+        Self(BUFFER_POOL.with(|pool| pool.borrow_mut().pop().unwrap_or_default()))
+    }
+
+    fn from_slice(bytes: &[u8]) -> Self {
+        let mut buf = Self::new();
+        buf.extend_from_slice(bytes);
+        buf
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if self.0.capacity() == 0 || self.0.capacity() > MAX_POOLED_CAPACITY || !buffer_pooling() {
+            return;
         }
+
+        let mut buf = std::mem::take(&mut self.0);
+        buf.clear();
+
+        // The pool is already gone if the thread is exiting.
+        let _ = BUFFER_POOL.try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() < MAX_POOLED_BUFFERS {
+                pool.push(buf);
+            }
+        });
     }
 }
 
@@ -49,13 +502,66 @@ where
     /// decode the database value into an instance of `E`. Note that, as we use protocol
     /// buffers for the encoding, the later can be prevented by not making wire incompatible
     /// changes to the entity.
-    pub async fn get(&self, tx: &Tx, key: &impl TuplePack) -> DbResult<Option<E>, io::Error> {
-        let key = self.subspace.pack(key);
-        let Some(bytes) = tx.get(&key).await? else {
+    pub async fn get(&self, tx: &Tx, key: &impl TuplePack) -> DbResult<Option<E>, CollectionError> {
+        let key = self.pack_key(key);
+
+        self.get_packed(tx, &key).await
+    }
+
+    /// Get an entity and its current etag from the collection, returning `None` if
+    /// the key is not present.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Self::get`] apply here. Also, the etag of an entity
+    /// written in this transaction is only known on commit, reading it fails.
+    pub async fn get_with_etag(
+        &self,
+        tx: &Tx,
+        key: &impl TuplePack,
+    ) -> DbResult<Option<(E, Etag)>, CollectionError> {
+        let key = self.pack_key(key);
+
+        let Some(entity) = self.get_packed(tx, &key).await? else {
+            return Ok(None);
+        };
+
+        let etag = Etag(self.revision(tx, &key).await?);
+
+        Ok(Some((entity, etag)))
+    }
+
+    /// Get the entity owning a value of a unique index, returning `None` if no
+    /// entity owns it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CollectionError::UnknownIndex`] if there is no unique index named
+    /// `index` in the collection. The errors of [`Self::get`] also apply here.
+    pub async fn get_by_unique_index(
+        &self,
+        tx: &Tx,
+        index: &str,
+        value: &impl TuplePack,
+    ) -> DbResult<Option<E>, CollectionError> {
+        let Some(index) = self.unique_indexes.iter().find(|idx| idx.name == index) else {
+            return Err(DbError::Abort(CollectionError::UnknownIndex {
+                collection: self.name,
+                index: index.to_string(),
+            }));
+        };
+
+        let mut index_key = PooledBuf::from_slice(index.subspace.bytes());
+        value.pack_into_vec(&mut index_key);
+
+        let Some(owner) = tx.get(&index_key).await? else {
             return Ok(None);
         };
 
-        decode_to_entity(&bytes).map(Some)
+        let mut key = PooledBuf::from_slice(self.subspace.bytes());
+        key.extend_from_slice(&owner);
+
+        self.get_packed(tx, &key).await
     }
 
     /// Get a range of entities.
@@ -69,59 +575,1971 @@ where
         &self,
         tx: &'t Tx,
         opts: RangeOption<'_>,
-    ) -> DbResult<Vec<E>, io::Error> {
+    ) -> DbResult<Vec<E>, CollectionError> {
+        self.range_entries(tx, opts, |_, entity| entity).await
+    }
+
+    /// Get a range of entities, together with their packed keys.
+    ///
+    /// The keys are relative to [`Self::subspace`], i.e. the same bytes that the
+    /// collection methods get by packing their `key` argument.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Self::range`] apply here.
+    pub async fn range_with_keys(
+        &self,
+        tx: &Tx,
+        opts: RangeOption<'_>,
+    ) -> DbResult<Vec<(Vec<u8>, E)>, CollectionError> {
+        let prefix_len = self.subspace.bytes().len();
+
+        self.range_entries(tx, opts, |key, entity| (key[prefix_len..].to_vec(), entity))
+            .await
+    }
+
+    /// Read a range of entities, mapping each of them and their database key
+    /// with `entry`.
+    async fn range_entries<T>(
+        &self,
+        tx: &Tx,
+        opts: RangeOption<'_>,
+        mut entry: impl FnMut(&[u8], E) -> T,
+    ) -> DbResult<Vec<T>, CollectionError> {
         let mut range_elems = opts.limit.map_or_else(Vec::new, Vec::with_capacity);
+        // Chunked values need extra reads, do them after the range is done.
+        let mut chunked = vec![];
 
-        tx.for_each_in_range(opts, |_, value| {
-            let res = decode_to_entity::<E>(value);
+        tx.for_each_in_range(opts, |key, value| {
+            if value.first() == Some(&CHUNKED_METADATA) {
+                chunked.push((range_elems.len(), key.to_vec(), value.to_vec()));
+                range_elems.push(None);
+                return std::future::ready(Ok(true));
+            }
+
+            let res = self.decode(key, value);
 
             match res {
                 Err(err) => std::future::ready(Err(err)),
                 Ok(elem) => {
-                    range_elems.push(elem);
+                    range_elems.push(Some(entry(key, elem)));
                     std::future::ready(Ok(true))
                 }
             }
         })
         .await?;
 
-        todo!()
+        for (idx, key, header) in chunked {
+            let value = self.read_chunks(tx, &key, &header).await?;
+            range_elems[idx] = Some(entry(&key, self.decode(&key, &value)?));
+        }
+
+        Ok(range_elems.into_iter().flatten().collect())
+    }
+
+    /// List the direct children of `parent` in the `collection_id` collection,
+    /// e.g. the repositories of an user, in key order.
+    ///
+    /// Entities must be keyed by their [`ResourceName`], so that children are
+    /// stored under their parent's key. Top-level resources are listed with a
+    /// `None` parent. Grandchildren, i.e. entities under the listed children,
+    /// are skipped.
+    ///
+    /// Listing starts after the child with ID `after`, if given, and returns at
+    /// most `limit` entities, which makes it suitable for paginated `List` RPCs.
+    /// The ID is unescaped, like the ones returned by [`ResourceName::id`].
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Self::range`] apply here.
+    pub async fn list_children(
+        &self,
+        tx: &Tx,
+        parent: Option<&ResourceName>,
+        collection_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<E>, CollectionError> {
+        let (children, (mut begin, end)) = self.children_range(parent, collection_id, after);
+
+        let mut entities = Vec::with_capacity(limit);
+        while entities.len() < limit {
+            let remaining = limit - entities.len();
+            let opts = RangeOption {
+                limit: Some(remaining),
+                ..RangeOption::from((
+                    KeySelector::first_greater_or_equal(begin.as_slice()),
+                    KeySelector::first_greater_or_equal(end.as_slice()),
+                ))
+            };
+
+            let entries = self.range_with_keys(tx, opts).await?;
+            let read = entries.len();
+
+            let Some((last, _)) = entries.last() else {
+                break;
+            };
+            begin = [self.subspace.bytes(), last, &[0]].concat();
+
+            entities.extend(
+                entries
+                    .into_iter()
+                    .filter(|(key, _)| children.unpack::<String>(key).is_ok())
+                    .map(|(_, entity)| entity),
+            );
+
+            if read < remaining {
+                break;
+            }
+        }
+
+        Ok(entities)
     }
 
     /// Set the value of a specific key.
-    pub fn set(&self, tx: &mut Tx, key: &impl TuplePack, value: &E) {
-        // Add extra byte for encoded value metadata.
-        let mut bytes = Vec::with_capacity(value.encoded_len() + 1);
-        bytes.push(V1_METADATA);
-        value.encode_raw(&mut bytes);
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CollectionError::ConstraintViolation`] if the value would violate
+    /// a unique index of the collection. Also, the errors of [`Self::get`] apply
+    /// here, as we need the previous revision and index values to update them.
+    pub async fn set(
+        &self,
+        tx: &mut Tx,
+        key: &impl TuplePack,
+        value: &E,
+    ) -> DbResult<(), CollectionError> {
+        let key = self.pack_key(key);
+
+        self.set_packed(tx, &key, value).await
+    }
+
+    /// Set the value of a specific key if its current etag is `etag`.
+    ///
+    /// Use this to implement read-modify-write cycles that span multiple
+    /// transactions, e.g. an update of a resource read by a client.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CollectionError::EtagMismatch`] if the entity was modified since
+    /// `etag` was read, or if it doesn't exist. The errors of [`Self::set`] also
+    /// apply here.
+    pub async fn set_if_match(
+        &self,
+        tx: &mut Tx,
+        key: &impl TuplePack,
+        value: &E,
+        etag: &Etag,
+    ) -> DbResult<(), CollectionError> {
+        let key = self.pack_key(key);
 
-        let key = self.subspace.pack(key);
+        self.check_etag(tx, &key, etag).await?;
+        self.set_packed(tx, &key, value).await
+    }
+
+    /// Clear a specific value from the collection if its current etag is `etag`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CollectionError::EtagMismatch`] if the entity was modified since
+    /// `etag` was read, or if it doesn't exist. The errors of [`Self::clear`] also
+    /// apply here.
+    pub async fn clear_if_match(
+        &self,
+        tx: &mut Tx,
+        key: &impl TuplePack,
+        etag: &Etag,
+    ) -> DbResult<(), CollectionError> {
+        let key = self.pack_key(key);
 
-        tx.set(&key, &bytes)
+        self.check_etag(tx, &key, etag).await?;
+        self.clear_packed(tx, &key).await
     }
 
     /// Clear a specific value from the collection.
-    pub fn clear(&self, tx: &mut Tx, key: &impl TuplePack) {
-        let key = self.subspace.pack(key);
+    ///
+    /// # Errors
+    ///
+    /// If the collection has indexes, the errors of [`Self::get`] apply here, as
+    /// we need the previous value to update them.
+    pub async fn clear(&self, tx: &mut Tx, key: &impl TuplePack) -> DbResult<(), CollectionError> {
+        let key = self.pack_key(key);
 
-        tx.clear(&key)
+        self.clear_packed(tx, &key).await
     }
-}
 
-fn decode_to_entity<E>(bytes: &[u8]) -> DbResult<E, io::Error>
-where
-    E: Message + Default,
-{
-    // The first byte from a collection-stored value is always present and
-    // represents the encoding metadata.
-    let metadata_byte = bytes[0];
+    /// Record a change done by a layer built on top of the collection, e.g. a
+    /// soft delete, in the change feed of the collection, if any.
+    ///
+    /// Changes done through [`Self::set`] and [`Self::clear`] are already
+    /// recorded, layers only need this for the changes they keep in their own
+    /// subspace. The entities are only recorded if the feed has values.
+    pub fn record_layer_change(
+        &self,
+        tx: &mut Tx,
+        key: &impl TuplePack,
+        operation: ChangeOperation,
+        before: Option<&E>,
+        after: Option<&E>,
+    ) {
+        let key = self.pack_key(key);
 
-    if metadata_byte == V1_METADATA {
-        E::decode(&bytes[1..]).map_err(|err| DbError::Abort(err.into()))
-    } else {
-        Err(DbError::Abort(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid metadata byte from database!",
-        )))
+        self.record_change(tx, &key, operation, before, after);
+    }
+
+    /// Watch an entity for changes.
+    ///
+    /// The entity key is written on every [`Self::set`] and [`Self::clear`], see
+    /// [`Tx::watch`] for how to use the returned future.
+    pub fn watch(&self, tx: &Tx, key: &impl TuplePack) -> Watch {
+        tx.watch(&self.pack_key(key))
+    }
+
+    /// Rewrite the V1 values in a range using the current encoding, returning
+    /// how many values were rewritten.
+    ///
+    /// Writes already store values with the current encoding, meaning that V1
+    /// values are migrated lazily as entities are updated. Use this to migrate
+    /// the remaining ones in batches, e.g. from a background job. Revisions
+    /// aren't changed, as the entities stay the same.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Self::range`] apply here.
+    pub async fn migrate_range(
+        &self,
+        tx: &mut Tx,
+        opts: RangeOption<'_>,
+    ) -> DbResult<usize, CollectionError> {
+        let mut values = vec![];
+
+        tx.for_each_in_range(opts, |key, value| {
+            if self.owns_key(key) && matches!(value.first(), Some(&V1_METADATA | &CHUNKED_METADATA))
+            {
+                values.push((key.to_vec(), value.to_vec()));
+            }
+
+            std::future::ready(Ok(true)) as std::future::Ready<DbResult<_, CollectionError>>
+        })
+        .await?;
+
+        let mut migrated = 0;
+        for (key, mut value) in values {
+            if value[0] == CHUNKED_METADATA {
+                value = self.read_chunks(tx, &key, &value).await?;
+            }
+
+            if value.first() == Some(&V1_METADATA) {
+                self.write_encoded(tx, &key, &self.encode_raw(&value[1..]))?;
+                migrated += 1;
+            }
+        }
+
+        Ok(migrated)
+    }
+
+    /// Re-encrypt all values not encrypted with the primary key of the keyring,
+    /// returning how many values were re-encrypted.
+    ///
+    /// Values are re-encrypted in batches of `batch_size` entities, each in its
+    /// own transaction, so this can run on large collections. Unencrypted values,
+    /// e.g. written before encryption was enabled, are also encrypted. Revisions
+    /// aren't changed, as the entities stay the same.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the transactions fail, or if we can't decrypt
+    /// a value, e.g. because its key was removed from the keyring.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the collection isn't encrypted.
+    pub async fn rotate_keys<Err>(&self, db: &Db, batch_size: usize) -> Result<usize, Err>
+    where
+        Err: From<StorageError> + From<CollectionError> + Error,
+    {
+        let keyring = self
+            .keyring
+            .as_ref()
+            .unwrap_or_else(|| panic!("collection '{}' isn't encrypted", self.name));
+        let (primary_id, _) = keyring.primary();
+
+        let (mut cursor, end) = self.subspace.range();
+        let mut rotated = 0;
+
+        loop {
+            let (next, batch_rotated) = db
+                .transaction(|mut tx| {
+                    let (cursor, end) = (&cursor, &end);
+                    async move {
+                        let opts = RangeOption {
+                            begin: KeySelector::first_greater_or_equal(cursor.as_slice()),
+                            end: KeySelector::first_greater_or_equal(end.as_slice()),
+                            limit: Some(batch_size),
+                            ..RangeOption::default()
+                        };
+
+                        let mut values = vec![];
+                        let mut last_key = None;
+                        tx.for_each_in_range(opts, |key, value| {
+                            if self.owns_key(key) {
+                                values.push((key.to_vec(), value.to_vec()));
+                            }
+                            last_key = Some(key.to_vec());
+                            std::future::ready(Ok(true)) as std::future::Ready<DbResult<_, Err>>
+                        })
+                        .await?;
+
+                        let Some(mut next) = last_key else {
+                            return Ok(((None, 0), tx));
+                        };
+
+                        // Continue right after the last key of the batch.
+                        next.push(0);
+
+                        let mut batch_rotated = 0;
+                        for (key, mut value) in values {
+                            let changed = self
+                                .rotate_value(&mut tx, &key, &mut value, primary_id)
+                                .await
+                                .map_err(|err| err.map_abort(Err::from))?;
+
+                            batch_rotated += usize::from(changed);
+                        }
+
+                        Ok(((Some(next), batch_rotated), tx))
+                    }
+                })
+                .await?;
+
+            rotated += batch_rotated;
+
+            match next {
+                Some(next) => cursor = next,
+                None => return Ok(rotated),
+            }
+        }
+    }
+
+    /// Re-encrypt a value with the primary key if it isn't already, returning
+    /// if the value was changed.
+    async fn rotate_value(
+        &self,
+        tx: &mut Tx,
+        key: &[u8],
+        value: &mut Vec<u8>,
+        primary_id: u32,
+    ) -> DbResult<bool, CollectionError> {
+        if value.first() == Some(&CHUNKED_METADATA) {
+            *value = self.read_chunks(tx, key, value).await?;
+        }
+
+        match value.as_slice() {
+            [ENCRYPTED_METADATA, id @ ..] if id.get(..4) == Some(&primary_id.to_le_bytes()) => {
+                return Ok(false);
+            }
+            [ENCRYPTED_METADATA, ..] => self.write_encoded(tx, key, &self.decrypt(key, value)?)?,
+            [V1_METADATA, raw @ ..] => self.write_encoded(tx, key, &self.encode_raw(raw))?,
+            _ => self.write_encoded(tx, key, value)?,
+        }
+
+        Ok(true)
+    }
+
+    async fn set_packed(
+        &self,
+        tx: &mut Tx,
+        key: &[u8],
+        value: &E,
+    ) -> DbResult<(), CollectionError> {
+        let previous = if self.needs_previous() {
+            self.get_packed(tx, key).await?
+        } else {
+            None
+        };
+
+        if !self.unique_indexes.is_empty() {
+            self.update_indexes(tx, key, previous.as_ref(), Some(value))
+                .await?;
+        }
+
+        self.write_encoded(tx, key, &self.encode(value))?;
+
+        // The revision is the versionstamp of the transaction, so that writes
+        // don't need to read the previous one.
+        let mut revision = tx.next_versionstamp().as_bytes().to_vec();
+        revision.extend_from_slice(&0u32.to_le_bytes());
+        tx.set_versionstamped_value(&self.revision_key(key), &revision);
+
+        self.record_change(
+            tx,
+            key,
+            ChangeOperation::Set,
+            previous.as_ref(),
+            Some(value),
+        );
+
+        Ok(())
+    }
+
+    /// If writes need the previous value of the entity.
+    fn needs_previous(&self) -> bool {
+        !self.unique_indexes.is_empty() || self.records_values()
+    }
+
+    /// If change records include the entities.
+    fn records_values(&self) -> bool {
+        matches!(self.change_feed, Some((_, true)))
+    }
+
+    /// Append a change of `key` to the change feed of the collection, if any.
+    fn record_change(
+        &self,
+        tx: &mut Tx,
+        key: &[u8],
+        operation: ChangeOperation,
+        before: Option<&E>,
+        after: Option<&E>,
+    ) {
+        let Some((feed, with_values)) = &self.change_feed else {
+            return;
+        };
+        let value = |entity: Option<&E>| entity.filter(|_| *with_values).map(E::encode_to_vec);
+
+        feed.append(
+            tx,
+            &ChangeRecord {
+                collection: self.name.to_string(),
+                key: key[self.subspace.bytes().len()..].to_vec(),
+                operation: operation as i32,
+                before: value(before),
+                after: value(after),
+            },
+        );
+    }
+
+    /// Encode an entity as a V2 value.
+    fn encode(&self, value: &E) -> PooledBuf {
+        let len = value.encoded_len();
+
+        if matches!(self.compression, Some((_, min_size)) if len >= min_size) {
+            let mut raw = PooledBuf::new();
+            raw.reserve(len);
+            value.encode_raw(&mut *raw);
+
+            return self.encode_raw(&raw);
+        }
+
+        // Encode directly after the header, avoiding a copy of the payload.
+        let mut bytes = PooledBuf::from_slice(&[V2_METADATA, COMPRESSION_NONE, 0, 0, 0, 0]);
+        bytes.reserve(len);
+        value.encode_raw(&mut *bytes);
+        seal_v2(&mut bytes);
+
+        bytes
+    }
+
+    /// Encode an already protobuf-encoded entity as a V2 value.
+    fn encode_raw(&self, raw: &[u8]) -> PooledBuf {
+        if let Some((compression, min_size)) = self.compression {
+            if raw.len() >= min_size {
+                if let Some(compressed) = compression.compress(raw) {
+                    if compressed.len() < raw.len() {
+                        return encode_v2(compression.flags(), &compressed);
+                    }
+                }
+            }
+        }
+
+        encode_v2(COMPRESSION_NONE, raw)
+    }
+
+    /// Write an encoded value, encrypting it and splitting it into chunks if needed.
+    fn write_encoded(
+        &self,
+        tx: &mut Tx,
+        key: &[u8],
+        bytes: &[u8],
+    ) -> DbResult<(), CollectionError> {
+        let encrypted;
+        let bytes = if self.keyring.is_some() {
+            encrypted = self.encrypt(key, bytes);
+            &encrypted
+        } else {
+            bytes
+        };
+
+        if self.chunked {
+            let chunks = self.entity_chunks(key);
+            // The previous value may have more chunks than the new one.
+            let (begin, end) = chunks.range();
+            tx.clear_range(&begin, &end)?;
+
+            if bytes.len() > CHUNK_SIZE {
+                let mut count = 0u32;
+                for chunk in bytes.chunks(CHUNK_SIZE) {
+                    tx.set(&chunks.pack(&count), chunk);
+                    count += 1;
+                }
+
+                let mut header = [CHUNKED_METADATA; 9];
+                header[1..5].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+                header[5..].copy_from_slice(&count.to_le_bytes());
+
+                tx.set(key, &header);
+                return Ok(());
+            }
+        }
+
+        tx.set(key, bytes);
+
+        Ok(())
+    }
+
+    async fn clear_packed(&self, tx: &mut Tx, key: &[u8]) -> DbResult<(), CollectionError> {
+        let previous = if self.needs_previous() {
+            self.get_packed(tx, key).await?
+        } else {
+            None
+        };
+
+        if !self.unique_indexes.is_empty() {
+            self.update_indexes(tx, key, previous.as_ref(), None)
+                .await?;
+        }
+
+        tx.clear(key);
+        tx.clear(&self.revision_key(key));
+
+        if self.chunked {
+            let (begin, end) = self.entity_chunks(key).range();
+            tx.clear_range(&begin, &end)?;
+        }
+
+        self.record_change(tx, key, ChangeOperation::Clear, previous.as_ref(), None);
+
+        Ok(())
+    }
+
+    /// Get the current revision of an entity.
+    ///
+    /// Revisions are the versionstamps of the transactions that wrote the
+    /// entities, as big-endian integers. Entities written before we started
+    /// tracking revisions are at revision 0.
+    async fn revision(&self, tx: &Tx, key: &[u8]) -> DbResult<u128, CollectionError> {
+        let Some(bytes) = tx.get(&self.revision_key(key)).await? else {
+            return Ok(0);
+        };
+
+        let stamp = <[u8; 12]>::try_from(&*bytes).map_err(|_| {
+            DbError::Abort(CollectionError::InvalidData(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid revision from database!",
+            )))
+        })?;
+
+        let mut revision = [0; 16];
+        revision[4..].copy_from_slice(&stamp);
+
+        Ok(u128::from_be_bytes(revision))
+    }
+
+    async fn check_etag(&self, tx: &Tx, key: &[u8], etag: &Etag) -> DbResult<(), CollectionError> {
+        let exists = tx.get(key).await?.is_some();
+
+        if exists && self.revision(tx, key).await? == etag.0 {
+            Ok(())
+        } else {
+            Err(DbError::Abort(CollectionError::EtagMismatch {
+                collection: self.name,
+            }))
+        }
+    }
+
+    /// Pack a key of an entity into a pooled buffer.
+    fn pack_key(&self, key: &impl TuplePack) -> PooledBuf {
+        let mut packed = PooledBuf::from_slice(self.subspace.bytes());
+        key.pack_into_vec(&mut packed);
+
+        packed
+    }
+
+    /// Check that `key` is the key of an entity of the collection.
+    ///
+    /// The subspace of the collection is the raw bytes of its name, so ranges
+    /// over it also cover the keys of collections whose name starts with it,
+    /// e.g. `users_x` for `users`. Those keys don't unpack as a tuple.
+    fn owns_key(&self, key: &[u8]) -> bool {
+        let Some(mut packed) = key.strip_prefix(self.subspace.bytes()) else {
+            return false;
+        };
+
+        while !packed.is_empty() {
+            match Element::unpack(packed, TupleDepth::new()) {
+                Ok((rest, _)) => packed = rest,
+                Err(_) => return false,
+            }
+        }
+
+        true
+    }
+
+    fn revision_key(&self, key: &[u8]) -> PooledBuf {
+        let mut revision_key = PooledBuf::from_slice(self.revisions.bytes());
+        revision_key.extend_from_slice(&key[self.subspace.bytes().len()..]);
+
+        revision_key
+    }
+
+    async fn get_packed(&self, tx: &Tx, key: &[u8]) -> DbResult<Option<E>, CollectionError> {
+        let Some(bytes) = tx.get(key).await? else {
+            return Ok(None);
+        };
+
+        if bytes.first() == Some(&CHUNKED_METADATA) {
+            let value = self.read_chunks(tx, key, &bytes).await?;
+            self.decode(key, &value).map(Some)
+        } else {
+            self.decode(key, &bytes).map(Some)
+        }
+    }
+
+    /// Reassemble a chunked value given its header.
+    async fn read_chunks(
+        &self,
+        tx: &Tx,
+        key: &[u8],
+        header: &[u8],
+    ) -> DbResult<Vec<u8>, CollectionError> {
+        let invalid_chunks = || {
+            DbError::Abort(CollectionError::InvalidData(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid chunked value from database!",
+            )))
+        };
+
+        let (len, count) = match header {
+            [CHUNKED_METADATA, sizes @ ..] if sizes.len() == 8 => {
+                let read_u32 = |b: &[u8]| u32::from_le_bytes(b.try_into().unwrap()) as usize;
+                (read_u32(&sizes[..4]), read_u32(&sizes[4..]))
+            }
+            _ => return Err(invalid_chunks()),
+        };
+
+        let mut value = Vec::with_capacity(len);
+        let mut read_chunks = 0;
+
+        let range = RangeOption::from(self.entity_chunks(key).range());
+        tx.for_each_in_range(range, |_, chunk| {
+            value.extend_from_slice(chunk);
+            read_chunks += 1;
+
+            std::future::ready(Ok(true)) as std::future::Ready<DbResult<_, CollectionError>>
+        })
+        .await?;
+
+        if value.len() != len || read_chunks != count {
+            return Err(invalid_chunks());
+        }
+
+        Ok(value)
+    }
+
+    /// Decode the value of `key`, decrypting it if needed.
+    fn decode(&self, key: &[u8], bytes: &[u8]) -> DbResult<E, CollectionError> {
+        if bytes.first() == Some(&ENCRYPTED_METADATA) {
+            decode_to_entity(self.name, &self.decrypt(key, bytes)?)
+        } else {
+            decode_to_entity(self.name, bytes)
+        }
+    }
+
+    /// Encrypt a value of `key` with a new data key.
+    ///
+    /// The key is used as associated data, so that values can't be moved
+    /// between keys by someone with write access to the database.
+    fn encrypt(&self, key: &[u8], bytes: &[u8]) -> Vec<u8> {
+        let keyring = self.keyring.as_ref().expect("collection isn't encrypted");
+        let (kek_id, kek) = keyring.primary();
+        let kek_id = kek_id.to_le_bytes();
+
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let key_nonce = Aes256Gcm::generate_nonce(OsRng);
+        let value_nonce = Aes256Gcm::generate_nonce(OsRng);
+
+        // AES-GCM only fails for inputs larger than 64 GiB.
+        let wrapped_key = kek
+            .encrypt(
+                &key_nonce,
+                Payload {
+                    msg: &data_key,
+                    aad: &kek_id,
+                },
+            )
+            .expect("failed to encrypt data key");
+        let ciphertext = Aes256Gcm::new(&data_key)
+            .encrypt(
+                &value_nonce,
+                Payload {
+                    msg: bytes,
+                    aad: key,
+                },
+            )
+            .expect("failed to encrypt value");
+
+        let mut encrypted = Vec::with_capacity(5 + WRAPPED_KEY_LEN + NONCE_LEN + ciphertext.len());
+        encrypted.push(ENCRYPTED_METADATA);
+        encrypted.extend_from_slice(&kek_id);
+        encrypted.extend_from_slice(&key_nonce);
+        encrypted.extend_from_slice(&wrapped_key);
+        encrypted.extend_from_slice(&value_nonce);
+        encrypted.extend_from_slice(&ciphertext);
+
+        encrypted
+    }
+
+    /// Decrypt an encrypted value of `key`.
+    fn decrypt(&self, key: &[u8], bytes: &[u8]) -> DbResult<Vec<u8>, CollectionError> {
+        let invalid_data = |msg| {
+            DbError::Abort(CollectionError::InvalidData(io::Error::new(
+                io::ErrorKind::InvalidData,
+                msg,
+            )))
+        };
+        let checksum_mismatch = |_| {
+            DbError::Abort(CollectionError::ChecksumMismatch {
+                collection: self.name,
+            })
+        };
+
+        let Some(keyring) = &self.keyring else {
+            return Err(invalid_data("encrypted value in unencrypted collection!"));
+        };
+
+        let (kek_id, rest) = match bytes {
+            [ENCRYPTED_METADATA, i0, i1, i2, i3, rest @ ..]
+                if rest.len() >= WRAPPED_KEY_LEN + NONCE_LEN =>
+            {
+                ([*i0, *i1, *i2, *i3], rest)
+            }
+            _ => return Err(invalid_data("invalid encrypted value from database!")),
+        };
+
+        let kek = keyring
+            .get(u32::from_le_bytes(kek_id))
+            .ok_or_else(|| invalid_data("value encrypted with unknown key!"))?;
+
+        let (wrapped_key, rest) = rest.split_at(WRAPPED_KEY_LEN);
+        let (key_nonce, wrapped_key) = wrapped_key.split_at(NONCE_LEN);
+        let (value_nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let data_key = kek
+            .decrypt(
+                Nonce::from_slice(key_nonce),
+                Payload {
+                    msg: wrapped_key,
+                    aad: &kek_id,
+                },
+            )
+            .map_err(checksum_mismatch)?;
+
+        Aes256Gcm::new_from_slice(&data_key)
+            .map_err(|_| invalid_data("invalid data key from database!"))?
+            .decrypt(
+                Nonce::from_slice(value_nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: key,
+                },
+            )
+            .map_err(checksum_mismatch)
+    }
+
+    /// The subspace with the chunks of the value of `key`.
+    fn entity_chunks(&self, key: &[u8]) -> Subspace {
+        // Pack the key as a single element, so that the chunks of an entity
+        // are never inside the chunks range of another one.
+        self.chunks.subspace(&&key[self.subspace.bytes().len()..])
+    }
+
+    /// Move the index entries of `key` from the `previous` value to the `next` one.
+    async fn update_indexes(
+        &self,
+        tx: &mut Tx,
+        key: &[u8],
+        previous: Option<&E>,
+        next: Option<&E>,
+    ) -> DbResult<(), CollectionError> {
+        // Index entries point to the key relative to the collection subspace.
+        let owner = &key[self.subspace.bytes().len()..];
+
+        for index in &self.unique_indexes {
+            let previous_value = previous.and_then(|e| (index.extractor)(e));
+            let next_value = next.and_then(|e| (index.extractor)(e));
+
+            if previous_value == next_value {
+                continue;
+            }
+
+            if let Some(next_value) = next_value {
+                let mut index_key = PooledBuf::from_slice(index.subspace.bytes());
+                index_key.extend_from_slice(&next_value.packed);
+
+                if let Some(current_owner) = tx.get(&index_key).await? {
+                    if *current_owner != *owner {
+                        return Err(DbError::Abort(CollectionError::ConstraintViolation(
+                            ConstraintViolation {
+                                collection: self.name,
+                                index: index.name,
+                                value: next_value.display,
+                            },
+                        )));
+                    }
+                }
+
+                tx.set(&index_key, owner);
+            }
+
+            if let Some(previous_value) = previous_value {
+                let mut index_key = PooledBuf::from_slice(index.subspace.bytes());
+                index_key.extend_from_slice(&previous_value.packed);
+
+                tx.clear(&index_key);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A key that is already packed, e.g. one returned by
+/// [`Collection::range_with_keys`].
+///
+/// Lets layers call the [`Collection`] methods with the packed keys they store.
+pub struct PackedKey<'a>(pub &'a [u8]);
+
+impl TuplePack for PackedKey<'_> {
+    fn pack<W: io::Write>(
+        &self,
+        w: &mut W,
+        _tuple_depth: TupleDepth,
+    ) -> io::Result<VersionstampOffset> {
+        w.write_all(self.0)?;
+
+        Ok(VersionstampOffset::None {
+            size: self.0.len() as u32,
+        })
+    }
+}
+
+/// An index of when the entities of a collection expire, for layers that hide
+/// or remove entities after some time.
+///
+/// Each entry is stored twice: by packed entity key, with a value chosen by
+/// the layer, and by `(expiration time, packed entity key)`, so that expired
+/// entities are found without scanning the whole collection. Expiration times
+/// are in milliseconds since the Unix epoch, see [`unix_millis`].
+pub struct ExpiryIndex {
+    /// Values of the layer, keyed by the packed entity key.
+    by_key: Subspace,
+    /// Empty values, keyed by `(expiration time, packed entity key)`.
+    by_time: Subspace,
+}
+
+impl ExpiryIndex {
+    /// Create an index stored in `subspace`, usually one returned by
+    /// [`Collection::layer_subspace`].
+    pub fn new(subspace: &Subspace) -> Self {
+        Self {
+            by_key: subspace.subspace(&"k"),
+            by_time: subspace.subspace(&"t"),
+        }
+    }
+
+    /// The key of the entry of an entity.
+    fn key(&self, packed: &[u8]) -> Vec<u8> {
+        [self.by_key.bytes(), packed].concat()
+    }
+
+    /// Get the value of the entry of an entity, if it has one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database operation fails.
+    pub async fn get(&self, tx: &Tx, packed: &[u8]) -> DbResult<Option<IBytes>, CollectionError> {
+        Ok(tx.get(&self.key(packed)).await?)
+    }
+
+    /// Add the entry of an entity, expiring at `expiry`.
+    ///
+    /// The previous entry of the entity, if any, must be cleared first.
+    pub fn set(&self, tx: &mut Tx, packed: &[u8], expiry: u64, value: &[u8]) {
+        tx.set(&self.key(packed), value);
+        tx.set(&self.by_time.pack(&(expiry, packed)), &[]);
+    }
+
+    /// Remove the entry of an entity, which expires at `expiry`.
+    pub fn clear(&self, tx: &mut Tx, packed: &[u8], expiry: u64) {
+        tx.clear(&self.by_time.pack(&(expiry, packed)));
+        tx.clear(&self.key(packed));
+    }
+
+    /// Get the entries between two packed keys, inclusive, sorted by key.
+    async fn entries_between(
+        &self,
+        tx: &Tx,
+        first: &[u8],
+        last: &[u8],
+    ) -> DbResult<Vec<(Vec<u8>, Vec<u8>)>, CollectionError> {
+        let mut end = self.key(last);
+        end.push(0);
+
+        let prefix_len = self.by_key.bytes().len();
+        let mut entries = vec![];
+
+        tx.for_each_in_range(RangeOption::from((self.key(first), end)), |key, value| {
+            entries.push((key[prefix_len..].to_vec(), value.to_vec()));
+            std::future::ready(Ok(true)) as std::future::Ready<DbResult<_, CollectionError>>
+        })
+        .await?;
+
+        Ok(entries)
+    }
+
+    /// Get a range of entities of `collection`, mapping each of them with their
+    /// packed key and the value of their entry, skipping those mapped to `None`.
+    ///
+    /// The range is given as in [`Collection::range`]. Skipped entities don't
+    /// count against the limit of `opts`, the range continues until enough
+    /// entities are mapped.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::range`] apply here, as well as the ones
+    /// returned by `entry`.
+    pub async fn range<E, T>(
+        &self,
+        collection: &Collection<E>,
+        tx: &Tx,
+        mut opts: RangeOption<'_>,
+        mut entry: impl FnMut(&[u8], E, Option<&[u8]>) -> DbResult<Option<T>, CollectionError>,
+    ) -> DbResult<Vec<T>, CollectionError>
+    where
+        E: Message + Default,
+    {
+        let limit = opts.limit;
+        let mut mapped = limit.map_or_else(Vec::new, Vec::with_capacity);
+
+        loop {
+            let entities = collection.range_with_keys(tx, opts.clone()).await?;
+
+            let (Some((first, _)), Some((last, _))) = (entities.first(), entities.last()) else {
+                return Ok(mapped);
+            };
+            let exhausted = opts.limit.map_or(true, |limit| entities.len() < limit);
+
+            let (low, high) = if opts.reverse {
+                (last, first)
+            } else {
+                (first, last)
+            };
+            let entries = self.entries_between(tx, low, high).await?;
+
+            // Continue right after the last entity of this read.
+            let mut cursor = [collection.subspace().bytes(), last].concat();
+            if opts.reverse {
+                opts.end = KeySelector::first_greater_or_equal(cursor);
+            } else {
+                cursor.push(0);
+                opts.begin = KeySelector::first_greater_or_equal(cursor);
+            }
+
+            for (key, entity) in entities {
+                let value = entries
+                    .binary_search_by(|(entry_key, _)| entry_key.cmp(&key))
+                    .ok()
+                    .map(|idx| entries[idx].1.as_slice());
+
+                mapped.extend(entry(&key, entity, value)?);
+            }
+
+            match limit {
+                Some(limit) if !exhausted && mapped.len() < limit => {
+                    opts.limit = Some(limit - mapped.len());
+                }
+                _ => return Ok(mapped),
+            }
+        }
+    }
+
+    /// Clear the expired entities of `collection` with their entries, returning
+    /// how many were cleared.
+    ///
+    /// Entities are cleared in batches of `batch_size`, each in its own
+    /// transaction, so this can run on large collections.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the transactions fail.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch_size` is zero.
+    pub async fn clear_expired<E, Err>(
+        &self,
+        collection: &Collection<E>,
+        db: &Db,
+        batch_size: usize,
+    ) -> Result<usize, Err>
+    where
+        E: Message + Default,
+        Err: From<StorageError> + From<CollectionError> + Error,
+    {
+        assert!(batch_size > 0, "batch size must not be zero");
+
+        let mut cleared = 0;
+
+        loop {
+            let batch_cleared = db
+                .transaction(|mut tx| async move {
+                    let begin = self.by_time.range().0;
+                    let end = self.by_time.pack(&(unix_millis(SystemTime::now()) + 1,));
+                    let opts = RangeOption {
+                        limit: Some(batch_size),
+                        ..RangeOption::from((begin, end))
+                    };
+
+                    let mut expired = vec![];
+                    tx.for_each_in_range(opts, |key, _| {
+                        expired.push(key.to_vec());
+                        std::future::ready(Ok(true)) as std::future::Ready<DbResult<_, Err>>
+                    })
+                    .await?;
+
+                    for key in &expired {
+                        let Ok((_, packed)) = self.by_time.unpack::<(u64, Vec<u8>)>(key) else {
+                            return Err(DbError::Storage(
+                                format!("invalid expiration key: {key:?}").into(),
+                            ));
+                        };
+
+                        collection
+                            .clear(&mut tx, &PackedKey(&packed))
+                            .await
+                            .map_err(|err| err.map_abort(Err::from))?;
+                        tx.clear(&self.key(&packed));
+                        tx.clear(key);
+                    }
+
+                    Ok((expired.len(), tx))
+                })
+                .await?;
+
+            cleared += batch_cleared;
+
+            if batch_cleared < batch_size {
+                return Ok(cleared);
+            }
+        }
+    }
+}
+
+/// Errors returned by [`Collection`] operations.
+#[derive(Debug)]
+pub enum CollectionError {
+    /// The value stored in the database couldn't be decoded.
+    InvalidData(io::Error),
+    /// The operation would violate a constraint of the collection.
+    ConstraintViolation(ConstraintViolation),
+    /// The entity was modified since the etag given to the operation was read.
+    EtagMismatch {
+        /// The name of the collection.
+        collection: &'static str,
+    },
+    /// The value stored in the database doesn't match its checksum, or failed
+    /// authentication during decryption.
+    ChecksumMismatch {
+        /// The name of the collection.
+        collection: &'static str,
+    },
+    /// The collection has no unique index with the given name.
+    UnknownIndex {
+        /// The name of the collection.
+        collection: &'static str,
+        /// The name of the index.
+        index: String,
+    },
+}
+
+/// The version of an entity, used for optimistic concurrency control.
+///
+/// This follows [AIP-154]: services return the etag of an entity in its resource,
+/// and use the etag sent by clients in updates and deletes, to prevent them from
+/// overwriting changes they didn't see.
+///
+/// Etags are the versionstamps of the writes, which never repeat, so an entity
+/// created again with the same key never gets an etag of the deleted one.
+///
+/// [AIP-154]: https://google.aip.dev/154
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Etag(u128);
+
+impl fmt::Display for Etag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.0)
+    }
+}
+
+impl FromStr for Etag {
+    type Err = Status;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_prefix('"')
+            .and_then(|s| s.strip_suffix('"'))
+            .and_then(|s| s.parse().ok())
+            .map(Self)
+            .ok_or_else(|| Status::invalid_argument(format!("invalid etag: {s}")))
+    }
+}
+
+/// A write tried to use a unique index value already owned by another entity.
+#[derive(Debug)]
+pub struct ConstraintViolation {
+    /// The name of the collection.
+    pub collection: &'static str,
+    /// The name of the violated index.
+    pub index: &'static str,
+    /// The value that is already in use.
+    pub value: String,
+}
+
+impl fmt::Display for CollectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidData(err) => write!(f, "invalid data in collection: {err}"),
+            Self::ConstraintViolation(violation) => violation.fmt(f),
+            Self::EtagMismatch { collection } => {
+                write!(f, "entity of {collection} was concurrently modified")
+            }
+            Self::ChecksumMismatch { collection } => {
+                write!(f, "entity of {collection} failed checksum verification")
+            }
+            Self::UnknownIndex { collection, index } => {
+                write!(f, "{collection} has no unique index '{index}'")
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConstraintViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} with {} '{}' already exists",
+            self.collection, self.index, self.value
+        )
+    }
+}
+
+impl Error for CollectionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidData(err) => Some(err),
+            Self::ConstraintViolation(_)
+            | Self::EtagMismatch { .. }
+            | Self::ChecksumMismatch { .. }
+            | Self::UnknownIndex { .. } => None,
+        }
+    }
+}
+
+impl From<CollectionError> for Status {
+    fn from(err: CollectionError) -> Self {
+        match err {
+            CollectionError::InvalidData(err) => {
+                Status::from(Box::new(err) as Box<dyn Error + Send + Sync>)
+            }
+            CollectionError::ConstraintViolation(violation) => {
+                let mut status = Status::already_exists(&violation);
+                status.details_mut().set_resource_info(
+                    violation.collection,
+                    violation.value,
+                    "",
+                    format!(
+                        "value of unique index '{}' is already in use",
+                        violation.index
+                    ),
+                );
+
+                status
+            }
+            err @ CollectionError::EtagMismatch { .. } => Status::aborted(err),
+            err @ CollectionError::ChecksumMismatch { .. } => Status::data_loss(err),
+            err @ CollectionError::UnknownIndex { .. } => Status::internal(err),
+        }
+    }
+}
+
+/// Build a V2 value with the given flags and payload.
+fn encode_v2(flags: u8, payload: &[u8]) -> PooledBuf {
+    let mut bytes = PooledBuf::from_slice(&[V2_METADATA, flags, 0, 0, 0, 0]);
+    bytes.reserve(payload.len());
+    bytes.extend_from_slice(payload);
+    seal_v2(&mut bytes);
+
+    bytes
+}
+
+/// Write the checksum of the payload of a V2 value in its header.
+fn seal_v2(bytes: &mut [u8]) {
+    let checksum = crc32c::crc32c(&bytes[V2_HEADER_LEN..]);
+    bytes[2..V2_HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+}
+
+fn decode_to_entity<E>(collection: &'static str, bytes: &[u8]) -> DbResult<E, CollectionError>
+where
+    E: Message + Default,
+{
+    let invalid_data = |err| DbError::Abort(CollectionError::InvalidData(err));
+
+    // The first byte from a collection-stored value is always present and
+    // represents the encoding metadata.
+    match bytes {
+        [V1_METADATA, payload @ ..] => E::decode(payload).map_err(|err| invalid_data(err.into())),
+        [V2_METADATA, flags, c0, c1, c2, c3, payload @ ..] => {
+            if crc32c::crc32c(payload) != u32::from_le_bytes([*c0, *c1, *c2, *c3]) {
+                return Err(DbError::Abort(CollectionError::ChecksumMismatch {
+                    collection,
+                }));
+            }
+
+            let decompressed = match *flags {
+                COMPRESSION_NONE => None,
+                COMPRESSION_ZSTD => Some(zstd::stream::decode_all(payload).map_err(invalid_data)?),
+                COMPRESSION_LZ4 => {
+                    Some(lz4_flex::decompress_size_prepended(payload).map_err(|err| {
+                        invalid_data(io::Error::new(io::ErrorKind::InvalidData, err))
+                    })?)
+                }
+                _ => {
+                    return Err(invalid_data(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid value flags from database!",
+                    )))
+                }
+            };
+
+            E::decode(decompressed.as_deref().unwrap_or(payload))
+                .map_err(|err| invalid_data(err.into()))
+        }
+        _ => Err(invalid_data(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid metadata byte from database!",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pl_database::Db;
+    use tonic::Code;
+
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct User {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(string, tag = "2")]
+        email: String,
+    }
+
+    fn users() -> Collection<User> {
+        Collection::from_static("users").with_unique_index("email", |user: &User| {
+            (!user.email.is_empty()).then(|| user.email.clone())
+        })
+    }
+
+    fn user(name: &str, email: &str) -> User {
+        User {
+            name: name.to_string(),
+            email: email.to_string(),
+        }
+    }
+
+    async fn set_user(db: &Db, users: &Collection<User>, user: User) -> Result<(), Status> {
+        db.transaction(|mut tx| {
+            let user = user.clone();
+            async move {
+                users
+                    .set(&mut tx, &user.name, &user)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+
+                Ok(((), tx))
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_unique_index() {
+        let db = Db::temporary();
+        let users = users();
+
+        set_user(&db, &users, user("john", "john@example.com"))
+            .await
+            .expect("failed to create john");
+
+        let err = set_user(&db, &users, user("jane", "john@example.com"))
+            .await
+            .expect_err("reused email from john");
+        assert_eq!(err.code(), Code::AlreadyExists);
+        assert_eq!(
+            err.details()
+                .resource_info()
+                .map(|info| info.resource_type.as_str()),
+            Some("users")
+        );
+
+        // Updating the owner doesn't violate the constraint.
+        set_user(&db, &users, user("john", "john@example.com"))
+            .await
+            .expect("failed to update john");
+
+        // After changing the email, the old value can be reused.
+        set_user(&db, &users, user("john", "john@example.org"))
+            .await
+            .expect("failed to change john's email");
+        set_user(&db, &users, user("jane", "john@example.com"))
+            .await
+            .expect("failed to reuse john's old email");
+
+        db.transaction(|tx| {
+            let users = &users;
+            async move {
+                let jane = users
+                    .get_by_unique_index(&tx, "email", &"john@example.com")
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+                assert_eq!(jane.map(|u| u.name), Some("jane".to_string()));
+
+                let err = users
+                    .get_by_unique_index(&tx, "mail", &"john@example.com")
+                    .await
+                    .expect_err("found entity by unknown index");
+                assert!(matches!(
+                    err,
+                    DbError::Abort(CollectionError::UnknownIndex { .. })
+                ));
+
+                Ok(((), tx)) as DbResult<_, Status>
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unique_index_clear() {
+        let db = Db::temporary();
+        let users = users();
+
+        set_user(&db, &users, user("john", "john@example.com"))
+            .await
+            .expect("failed to create john");
+
+        db.transaction(|mut tx| {
+            let users = &users;
+            async move {
+                users
+                    .clear(&mut tx, &"john")
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+
+                Ok(((), tx)) as DbResult<_, Status>
+            }
+        })
+        .await
+        .expect("failed to delete john");
+
+        set_user(&db, &users, user("jane", "john@example.com"))
+            .await
+            .expect("failed to reuse deleted email");
+    }
+
+    async fn get_etag(db: &Db, users: &Collection<User>, name: &str) -> Etag {
+        db.transaction(|tx| async move {
+            let (_, etag) = users
+                .get_with_etag(&tx, &name)
+                .await
+                .map_err(|err| err.map_abort(Status::from))?
+                .expect("user not found");
+
+            Ok((etag, tx)) as DbResult<_, Status>
+        })
+        .await
+        .expect("failed to get etag")
+    }
+
+    async fn set_user_if_match(
+        db: &Db,
+        users: &Collection<User>,
+        user: User,
+        etag: Etag,
+    ) -> Result<(), Status> {
+        db.transaction(|mut tx| {
+            let user = user.clone();
+            async move {
+                users
+                    .set_if_match(&mut tx, &user.name, &user, &etag)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+
+                Ok(((), tx))
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_etag() {
+        let db = Db::temporary();
+        let users = users();
+
+        set_user(&db, &users, user("john", "john@example.com"))
+            .await
+            .expect("failed to create john");
+
+        let etag = get_etag(&db, &users, "john").await;
+        assert_eq!(etag.to_string().parse::<Etag>().unwrap(), etag);
+
+        set_user_if_match(&db, &users, user("john", "john@example.org"), etag)
+            .await
+            .expect("failed to update john");
+
+        let err = set_user_if_match(&db, &users, user("john", "john@example.net"), etag)
+            .await
+            .expect_err("updated with a stale etag");
+        assert_eq!(err.code(), Code::Aborted);
+
+        let new_etag = get_etag(&db, &users, "john").await;
+        assert_ne!(etag, new_etag);
+
+        db.transaction(|mut tx| {
+            let users = &users;
+            async move {
+                users
+                    .clear_if_match(&mut tx, &"john", &new_etag)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+
+                Ok(((), tx)) as DbResult<_, Status>
+            }
+        })
+        .await
+        .expect("failed to delete john");
+
+        db.transaction(|tx| {
+            let users = &users;
+            async move {
+                let revision = tx
+                    .get(&users.revision_key(&users.pack_key(&"john")))
+                    .await?;
+                assert!(revision.is_none(), "kept the revision of a deleted entity");
+
+                Ok(((), tx)) as DbResult<_, Status>
+            }
+        })
+        .await
+        .unwrap();
+
+        // Etags of deleted entities don't match the ones created again.
+        set_user(&db, &users, user("john", "john@example.com"))
+            .await
+            .expect("failed to create john again");
+
+        let err = set_user_if_match(&db, &users, user("john", "john@example.net"), etag)
+            .await
+            .expect_err("updated with the etag of a deleted entity");
+        assert_eq!(err.code(), Code::Aborted);
+        assert_ne!(get_etag(&db, &users, "john").await, etag);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_values() {
+        let db = Db::temporary();
+        let users = users().with_chunked_values();
+
+        let large = user(&"j".repeat(3 * CHUNK_SIZE), "john@example.com");
+
+        set_user(&db, &users, large.clone())
+            .await
+            .expect("failed to create large user");
+
+        db.transaction(|mut tx| {
+            let (users, large) = (&users, &large);
+            async move {
+                let key = users.subspace.pack(&large.name);
+                let chunks = users.entity_chunks(&key);
+
+                let stored = tx.get(&key).await?.expect("user not found");
+                assert_eq!(stored[0], CHUNKED_METADATA, "large value not chunked");
+
+                let found = users
+                    .get(&tx, &large.name)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+                assert_eq!(found.as_ref(), Some(large), "failed to reassemble chunks");
+
+                let all = users
+                    .range(&tx, RangeOption::from(users.subspace.range()))
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+                assert_eq!(all, vec![large.clone()], "failed to reassemble range");
+
+                users
+                    .clear(&mut tx, &large.name)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+
+                let mut remaining_chunks = 0;
+                tx.for_each_in_range(RangeOption::from(chunks.range()), |_, _| {
+                    remaining_chunks += 1;
+                    std::future::ready(Ok(true)) as std::future::Ready<DbResult<_, Status>>
+                })
+                .await?;
+                assert_eq!(remaining_chunks, 0, "chunks not cleared");
+
+                Ok(((), tx)) as DbResult<_, Status>
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            let db = Db::temporary();
+            let users = users().with_compression(compression, 100);
+
+            let small = user("john", "john@example.com");
+            let large = user(&"j".repeat(1000), "jj@example.com");
+
+            for user in [&small, &large] {
+                set_user(&db, &users, user.clone())
+                    .await
+                    .expect("failed to create user");
+            }
+
+            db.transaction(|tx| {
+                let (users, small, large) = (&users, &small, &large);
+                async move {
+                    let stored = tx
+                        .get(&users.subspace.pack(&small.name))
+                        .await?
+                        .expect("user not found");
+                    assert_eq!(stored[..2], [V2_METADATA, COMPRESSION_NONE]);
+
+                    let stored = tx
+                        .get(&users.subspace.pack(&large.name))
+                        .await?
+                        .expect("user not found");
+                    assert_eq!(stored[..2], [V2_METADATA, compression.flags()]);
+                    assert!(stored.len() < 500, "{compression:?} didn't compress value");
+
+                    for user in [small, large] {
+                        let found = users
+                            .get(&tx, &user.name)
+                            .await
+                            .map_err(|err| err.map_abort(Status::from))?;
+                        assert_eq!(found.as_ref(), Some(user), "{compression:?} roundtrip");
+                    }
+
+                    Ok(((), tx)) as DbResult<_, Status>
+                }
+            })
+            .await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch() {
+        let db = Db::temporary();
+        let users = users();
+
+        set_user(&db, &users, user("john", "john@example.com"))
+            .await
+            .expect("failed to create user");
+
+        let err = db
+            .transaction(|mut tx| {
+                let users = &users;
+                async move {
+                    let key = users.subspace.pack(&"john");
+                    let mut stored = tx.get(&key).await?.expect("user not found").to_vec();
+                    *stored.last_mut().unwrap() ^= 0xFF;
+                    tx.set(&key, &stored);
+
+                    users
+                        .get(&tx, &"john")
+                        .await
+                        .map_err(|err| err.map_abort(Status::from))?;
+
+                    Ok(((), tx)) as DbResult<_, Status>
+                }
+            })
+            .await
+            .expect_err("read corrupted value");
+
+        assert_eq!(err.code(), Code::DataLoss);
+    }
+
+    #[tokio::test]
+    async fn test_v1_migration() {
+        let db = Db::temporary();
+        let users = users();
+        let john = user("john", "john@example.com");
+
+        db.transaction(|mut tx| {
+            let (users, john) = (&users, &john);
+            async move {
+                let key = users.subspace.pack(&john.name);
+                let mut v1 = vec![V1_METADATA];
+                john.encode_raw(&mut v1);
+                tx.set(&key, &v1);
+
+                let (found, etag) = users
+                    .get_with_etag(&tx, &john.name)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?
+                    .expect("user not found");
+                assert_eq!(&found, john, "failed to read V1 value");
+
+                let range = || RangeOption::from(users.subspace.range());
+                let migrated = users
+                    .migrate_range(&mut tx, range())
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+                assert_eq!(migrated, 1);
+
+                let stored = tx.get(&key).await?.expect("user not found");
+                assert_eq!(stored[0], V2_METADATA, "value not migrated");
+
+                let migrated = users
+                    .migrate_range(&mut tx, range())
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+                assert_eq!(migrated, 0, "migrated V2 value");
+
+                let found = users
+                    .get_with_etag(&tx, &john.name)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+                assert_eq!(
+                    found,
+                    Some((john.clone(), etag)),
+                    "migration changed entity"
+                );
+
+                Ok(((), tx)) as DbResult<_, Status>
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    const KEY_1: &str = "1 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_2: &str = "2 202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+
+    fn users_with_keyring(keys: &[&str]) -> Collection<User> {
+        let keyring: Keyring = keys.join("\n").parse().expect("invalid keyring");
+        users().with_encryption(Arc::new(keyring))
+    }
+
+    async fn stored_value(db: &Db, users: &Collection<User>, name: &str) -> Vec<u8> {
+        db.transaction(|tx| async move {
+            let stored = tx.get(&users.subspace.pack(&name)).await?;
+            Ok((stored.expect("user not found").to_vec(), tx)) as DbResult<_, Status>
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_user(db: &Db, users: &Collection<User>, name: &str) -> Result<User, Status> {
+        db.transaction(|tx| async move {
+            let user = users
+                .get(&tx, &name)
+                .await
+                .map_err(|err| err.map_abort(Status::from))?;
+
+            Ok((user.expect("user not found"), tx))
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_encryption() {
+        let db = Db::temporary();
+        let users = users_with_keyring(&[KEY_1]);
+        let john = user("john", "john@example.com");
+
+        set_user(&db, &users, john.clone())
+            .await
+            .expect("failed to create user");
+
+        let mut stored = stored_value(&db, &users, "john").await;
+        assert_eq!(stored[..5], [ENCRYPTED_METADATA, 1, 0, 0, 0]);
+        assert!(
+            !stored.windows(5).any(|w| w == b"@exam"),
+            "value stored in plaintext"
+        );
+
+        let found = get_user(&db, &users, "john").await.unwrap();
+        assert_eq!(found, john, "failed to decrypt value");
+
+        // Values can't be read without the key.
+        let err = get_user(&db, &users_with_keyring(&[KEY_2]), "john")
+            .await
+            .expect_err("decrypted without key");
+        assert_eq!(err.code(), Code::Internal);
+
+        // Tampered values fail authentication.
+        *stored.last_mut().unwrap() ^= 0xFF;
+        db.transaction(|mut tx| {
+            let (users, stored) = (&users, &stored);
+            async move {
+                tx.set(&users.subspace.pack(&"john"), stored);
+                Ok(((), tx)) as DbResult<_, Status>
+            }
+        })
+        .await
+        .unwrap();
+
+        let err = get_user(&db, &users, "john")
+            .await
+            .expect_err("read tampered value");
+        assert_eq!(err.code(), Code::DataLoss);
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let db = Db::temporary();
+        let john = user("john", "john@example.com");
+        let jane = user("jane", "jane@example.com");
+        let mary = user("mary", "mary@example.com");
+
+        set_user(&db, &users(), john.clone()).await.unwrap();
+        set_user(&db, &users_with_keyring(&[KEY_1]), jane.clone())
+            .await
+            .unwrap();
+
+        let users = users_with_keyring(&[KEY_1, KEY_2]);
+        set_user(&db, &users, mary.clone()).await.unwrap();
+
+        // Collections sharing a name prefix are left alone.
+        let others = Collection::from_static("users_x");
+        set_user(&db, &others, john.clone()).await.unwrap();
+
+        let rotated = users.rotate_keys::<Status>(&db, 2).await.unwrap();
+        assert_eq!(rotated, 2, "should rotate plaintext and old key values");
+
+        let found = get_user(&db, &others, &john.name).await.unwrap();
+        assert_eq!(found, john, "rotated value of another collection");
+
+        for user in [&john, &jane, &mary] {
+            let stored = stored_value(&db, &users, &user.name).await;
+            assert_eq!(stored[..5], [ENCRYPTED_METADATA, 2, 0, 0, 0]);
+
+            let found = get_user(&db, &users_with_keyring(&[KEY_2]), &user.name)
+                .await
+                .unwrap();
+            assert_eq!(&found, user, "failed to decrypt rotated value");
+        }
+
+        let rotated = users.rotate_keys::<Status>(&db, 2).await.unwrap();
+        assert_eq!(rotated, 0, "rotated values twice");
+    }
+
+    async fn clear_user(db: &Db, users: &Collection<User>, name: &str) {
+        db.transaction(|mut tx| async move {
+            users
+                .clear(&mut tx, &name)
+                .await
+                .map_err(|err| err.map_abort(Status::from))?;
+
+            Ok(((), tx)) as DbResult<_, Status>
+        })
+        .await
+        .expect("failed to clear user");
+    }
+
+    #[tokio::test]
+    async fn test_change_feed() {
+        let db = Db::temporary();
+        let feed = Arc::new(ChangeFeed::from_static("changes"));
+        let users = users().with_change_feed(feed.clone(), true);
+        let john = user("john", "john@example.com");
+        let updated = user("john", "john@example.org");
+
+        set_user(&db, &users, john.clone()).await.unwrap();
+        set_user(&db, &users, updated.clone()).await.unwrap();
+        clear_user(&db, &users, "john").await;
+
+        let changes = db
+            .transaction(|tx| {
+                let feed = &feed;
+                async move {
+                    let changes = feed
+                        .read(&tx, None, 10)
+                        .await
+                        .map_err(|err| err.map_abort(Status::from))?;
+                    Ok::<_, DbError<Status>>((changes, tx))
+                }
+            })
+            .await
+            .unwrap();
+
+        let encoded = |user: &User| Some(user.encode_to_vec());
+        let records = changes
+            .into_iter()
+            .map(|change| {
+                assert_eq!(change.record.collection, "users");
+                assert_eq!(change.record.key, "john".pack_to_vec());
+                (
+                    change.record.operation(),
+                    change.record.before,
+                    change.record.after,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            vec![
+                (ChangeOperation::Set, None, encoded(&john)),
+                (ChangeOperation::Set, encoded(&john), encoded(&updated)),
+                (ChangeOperation::Clear, encoded(&updated), None),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "can't have values")]
+    fn test_encrypted_change_feed_values() {
+        let feed = Arc::new(ChangeFeed::from_static("changes"));
+        let _ = users_with_keyring(&[KEY_1]).with_change_feed(feed, true);
+    }
+
+    #[tokio::test]
+    async fn test_list_children() {
+        let db = Db::temporary();
+        let resources = Collection::from_static("resources");
+
+        db.transaction(|mut tx| {
+            let resources = &resources;
+            async move {
+                for name in [
+                    "users/jane",
+                    "users/john",
+                    "users/john/repos/linux",
+                    "users/john/repos/linux/issues/1",
+                    "users/john/repos/llvm",
+                    "users/john/keys/main",
+                    "users/johnny/repos/rust",
+                    "users/john/branches/feature%2Ffoo",
+                    "users/john/branches/feature-x",
+                ] {
+                    let resource = user(name, "");
+                    resources
+                        .set(&mut tx, &ResourceName::__new(name.to_string()), &resource)
+                        .await
+                        .map_err(|err| err.map_abort(Status::from))?;
+                }
+
+                Ok::<_, DbError<Status>>(((), tx))
+            }
+        })
+        .await
+        .unwrap();
+
+        let list = |parent: Option<&'static str>, collection_id, after, limit| {
+            let (db, resources) = (&db, &resources);
+            async move {
+                let parent = parent.map(|parent| ResourceName::__new(parent.to_string()));
+                let children = db
+                    .transaction(|tx| {
+                        let parent = parent.as_ref();
+                        async move {
+                            let children = resources
+                                .list_children(&tx, parent, collection_id, after, limit)
+                                .await
+                                .map_err(|err| err.map_abort(Status::from))?;
+                            Ok::<_, DbError<Status>>((children, tx))
+                        }
+                    })
+                    .await
+                    .unwrap();
+
+                children
+                    .into_iter()
+                    .map(|child| child.name)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(
+            list(Some("users/john"), "repos", None, 10).await,
+            ["users/john/repos/linux", "users/john/repos/llvm"]
+        );
+        assert_eq!(
+            list(Some("users/john"), "repos", None, 1).await,
+            ["users/john/repos/linux"]
+        );
+        assert_eq!(
+            list(Some("users/john"), "repos", Some("linux"), 10).await,
+            ["users/john/repos/llvm"]
+        );
+        assert_eq!(
+            list(None, "users", None, 10).await,
+            ["users/jane", "users/john"]
+        );
+        assert!(list(Some("users/jane"), "repos", None, 10).await.is_empty());
+
+        // Escaped IDs sort differently than unescaped ones.
+        assert_eq!(
+            list(Some("users/john"), "branches", None, 1).await,
+            ["users/john/branches/feature%2Ffoo"]
+        );
+        assert_eq!(
+            list(Some("users/john"), "branches", Some("feature/foo"), 10).await,
+            ["users/john/branches/feature-x"]
+        );
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let mut buf = PooledBuf::new();
+        buf.extend_from_slice(b"users/john");
+        let ptr = buf.as_ptr();
+        drop(buf);
+
+        let buf = PooledBuf::new();
+        assert!(buf.is_empty(), "pooled buffer wasn't cleared");
+        assert_eq!(buf.as_ptr(), ptr, "buffer wasn't reused");
+        drop(buf);
+
+        let mut large = PooledBuf::new();
+        large.reserve(MAX_POOLED_CAPACITY + 1);
+        let ptr = large.as_ptr();
+        drop(large);
+
+        assert_ne!(PooledBuf::new().as_ptr(), ptr, "large buffer was pooled");
+
+        set_buffer_pooling(false);
+        drop(PooledBuf::from_slice(b"users/john"));
+        assert_eq!(PooledBuf::new().capacity(), 0, "buffer was pooled");
+        set_buffer_pooling(true);
     }
 }
@@ -0,0 +1,153 @@
+//! Benchmark of the hot paths of `Collection`.
+//!
+//! Writes, reads and clears small entities, similar to the refs of a repository,
+//! in transactions of `--batch-size` operations, and reports the mean time of
+//! each operation without and with the buffer pool of `Collection`. Runs with
+//! and without the pool are interleaved, see `main`.
+//! Run it with `--fdb` to use the FoundationDB cluster configured via the
+//! `FDB_*` environment variables instead of a temporary sled database.
+use std::time::{Duration, Instant};
+
+use pl_api_status::Status;
+use pl_database::{Db, DbResult};
+use pl_database_layers_collection::{set_buffer_pooling, Collection};
+use prost::Message;
+
+#[derive(Debug, argh::FromArgs)]
+#[argh(description = "benchmark collection operations")]
+struct Args {
+    /// use FoundationDB instead of a temporary sled database.
+    #[argh(switch)]
+    fdb: bool,
+    /// how many entities to write, read and clear.
+    #[argh(option, default = "10_000")]
+    entities: usize,
+    /// how many operations to do in each transaction.
+    #[argh(option, default = "100")]
+    batch_size: usize,
+    /// how many times to read all entities.
+    #[argh(option, default = "10")]
+    read_rounds: usize,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Ref {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(bytes = "vec", tag = "2")]
+    target: Vec<u8>,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args: Args = argh::from_env();
+
+    let db = if args.fdb {
+        // SAFETY: this is the only call in the program, and the database is
+        // dropped at the end of main.
+        unsafe { Db::foundation() }
+    } else {
+        Db::temporary()
+    };
+
+    let names = (0..args.entities)
+        .map(|i| format!("refs/heads/branch-{i}"))
+        .collect::<Vec<_>>();
+
+    // Runs are interleaved as unpooled, pooled, pooled, unpooled, so that the
+    // database growing between runs affects both sides the same. The runtime is
+    // single-threaded, so the pooling setting applies to all operations.
+    let mut unpooled = [0.0; 3];
+    let mut pooled = [0.0; 3];
+    for (pooling, name) in [
+        (false, "bench/refs0"),
+        (true, "bench/refs1"),
+        (true, "bench/refs2"),
+        (false, "bench/refs3"),
+    ] {
+        set_buffer_pooling(pooling);
+        let refs = Collection::<Ref>::from_static(name);
+        let means = run(&db, &refs, &names, &args).await;
+
+        let totals = if pooling { &mut pooled } else { &mut unpooled };
+        for (total, mean) in totals.iter_mut().zip(means) {
+            *total += mean / 2.0;
+        }
+    }
+
+    println!(
+        "{:>5}  {:>9}  {:>9}  {:>6}",
+        "", "unpooled", "pooled", "change"
+    );
+    for ((op, unpooled), pooled) in ["set", "get", "clear"].iter().zip(unpooled).zip(pooled) {
+        println!(
+            "{op:>5}  {unpooled:>6.0} ns  {pooled:>6.0} ns  {:>+5.1}%",
+            (pooled / unpooled - 1.0) * 100.0
+        );
+    }
+}
+
+/// Set, get and clear all entities, returning the mean nanoseconds per operation.
+async fn run(db: &Db, refs: &Collection<Ref>, names: &[String], args: &Args) -> [f64; 3] {
+    let start = Instant::now();
+    for batch in names.chunks(args.batch_size) {
+        db.transaction(|mut tx| async move {
+            for name in batch {
+                let entity = Ref {
+                    name: name.clone(),
+                    target: vec![0xAB; 20],
+                };
+
+                refs.set(&mut tx, name, &entity)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+            }
+
+            Ok(((), tx)) as DbResult<_, Status>
+        })
+        .await
+        .expect("failed to set refs");
+    }
+    let set = mean(start.elapsed(), names.len());
+
+    let start = Instant::now();
+    for _ in 0..args.read_rounds {
+        for batch in names.chunks(args.batch_size) {
+            db.transaction(|tx| async move {
+                for name in batch {
+                    refs.get(&tx, name)
+                        .await
+                        .map_err(|err| err.map_abort(Status::from))?
+                        .expect("ref not found");
+                }
+
+                Ok(((), tx)) as DbResult<_, Status>
+            })
+            .await
+            .expect("failed to get refs");
+        }
+    }
+    let get = mean(start.elapsed(), names.len() * args.read_rounds);
+
+    let start = Instant::now();
+    for batch in names.chunks(args.batch_size) {
+        db.transaction(|mut tx| async move {
+            for name in batch {
+                refs.clear(&mut tx, name)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+            }
+
+            Ok(((), tx)) as DbResult<_, Status>
+        })
+        .await
+        .expect("failed to clear refs");
+    }
+    let clear = mean(start.elapsed(), names.len());
+
+    [set, get, clear]
+}
+
+fn mean(elapsed: Duration, ops: usize) -> f64 {
+    elapsed.as_nanos() as f64 / ops as f64
+}
@@ -0,0 +1,337 @@
+//! # Sharded counters
+//!
+//! Counters incremented by many concurrent transactions, e.g. the clone count
+//! of a repository, would conflict heavily if stored in a single key. Instead,
+//! each increment is applied to one of multiple shard keys, chosen at random,
+//! using atomic additions, which never conflict. Reads sum all shards of the
+//! counter, which is cheap, as the shards are in a single small range.
+//!
+//! Shards accumulate over time, as each of them is created on its first
+//! increment. Run [`Counter::coalesce_all`] periodically, e.g. from a background
+//! job, to merge them back into a single key.
+use std::{convert::Infallible, error::Error};
+
+use foundationdb::{
+    tuple::{Element, Subspace, TuplePack},
+    KeySelector, RangeOption,
+};
+use pl_database::{Db, DbError, DbResult, InfallibleDbResult, StorageError, Tx};
+use rand::Rng;
+
+/// Default number of shards of each counter.
+const DEFAULT_SHARDS: u32 = 16;
+
+/// A set of sharded counters, identified by a key.
+pub struct Counter {
+    subspace: Subspace,
+    shards: u32,
+}
+
+impl Counter {
+    /// Create a new set of counters with a static name.
+    ///
+    /// The name must not clash with any other layer stored in the same
+    /// database, as it is used as the prefix of all keys of the counters.
+    pub fn from_static(name: &'static str) -> Self {
+        Self {
+            subspace: Subspace::from_bytes(name.as_bytes()),
+            shards: DEFAULT_SHARDS,
+        }
+    }
+
+    /// Set the number of shards of each counter.
+    ///
+    /// More shards reduce conflicts between increments, but make reads slower.
+    /// This can be changed at any time, as reads sum all existing shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn with_shards(mut self, shards: u32) -> Self {
+        assert!(shards > 0, "counters need at least one shard");
+        self.shards = shards;
+        self
+    }
+
+    /// Add `delta` to a counter, creating it if needed.
+    ///
+    /// This never conflicts with other transactions changing the same counter.
+    pub fn add(&self, tx: &mut Tx, key: &impl TuplePack, delta: i64) {
+        let shard = rand::thread_rng().gen_range(0..self.shards);
+
+        tx.atomic_add(&self.counter_subspace(key).pack(&shard), delta);
+    }
+
+    /// Get the current value of a counter, returning zero if it doesn't exist.
+    pub async fn get(&self, tx: &Tx, key: &impl TuplePack) -> InfallibleDbResult<i64> {
+        let mut total = 0i64;
+
+        let range = RangeOption::from(self.counter_subspace(key).range());
+        let res = tx
+            .for_each_in_range(range, |_, value| {
+                total = total.wrapping_add(decode_shard(value));
+                std::future::ready(Ok(true)) as std::future::Ready<DbResult<_, Infallible>>
+            })
+            .await;
+
+        match res {
+            Ok(()) => Ok(total),
+            Err(DbError::Storage(err)) => Err(DbError::Storage(err)),
+            Err(DbError::Abort(never)) => match never {},
+        }
+    }
+
+    /// Remove a counter.
+    pub fn clear(&self, tx: &mut Tx, key: &impl TuplePack) -> InfallibleDbResult<()> {
+        let (begin, end) = self.counter_subspace(key).range();
+
+        tx.clear_range(&begin, &end)
+    }
+
+    /// Merge the shards of a counter into a single one.
+    ///
+    /// This reads all shards of the counter, meaning that it conflicts with
+    /// concurrent increments, prefer running it outside of hot paths.
+    pub async fn coalesce(&self, tx: &mut Tx, key: &impl TuplePack) -> InfallibleDbResult<()> {
+        let total = self.get(tx, key).await?;
+
+        self.clear(tx, key)?;
+        tx.set(
+            &self.counter_subspace(key).pack(&0u32),
+            &total.to_le_bytes(),
+        );
+
+        Ok(())
+    }
+
+    /// Merge the shards of all counters, returning how many counters had more
+    /// than one shard.
+    ///
+    /// Shards are read in batches of `batch_size` keys, each in its own
+    /// transaction, so this can run on large sets of counters.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the transactions fail.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch_size` isn't larger than the number of shards, as then we
+    /// may not be able to read all shards of a counter in a single batch. Counters
+    /// with more shards than a batch, e.g. after reducing the number of shards, are
+    /// skipped.
+    pub async fn coalesce_all<E>(&self, db: &Db, batch_size: usize) -> Result<usize, E>
+    where
+        E: From<StorageError> + Error,
+    {
+        assert!(
+            batch_size > self.shards as usize,
+            "batch size must be larger than the number of shards"
+        );
+
+        let (mut cursor, end) = self.subspace.range();
+        let mut coalesced = 0;
+
+        loop {
+            let (next, batch_coalesced) = db
+                .transaction(|mut tx| {
+                    let (cursor, end) = (&cursor, &end);
+                    async move {
+                        let opts = RangeOption {
+                            begin: KeySelector::first_greater_or_equal(cursor.as_slice()),
+                            end: KeySelector::first_greater_or_equal(end.as_slice()),
+                            limit: Some(batch_size),
+                            ..RangeOption::default()
+                        };
+
+                        let mut shards = vec![];
+                        tx.for_each_in_range(opts, |key, value| {
+                            shards.push((key.to_vec(), decode_shard(value)));
+                            std::future::ready(Ok(true)) as std::future::Ready<DbResult<_, E>>
+                        })
+                        .await?;
+
+                        let full_batch = shards.len() == batch_size;
+                        let mut counters = self.group_shards::<E>(&shards)?;
+
+                        // The shards of the last counter may continue in the next batch,
+                        // coalesce it there. If it filled the batch, e.g. after reducing
+                        // the number of shards, skip it instead of looping forever.
+                        let next = match counters.pop() {
+                            Some((prefix, _, _)) if full_batch && counters.is_empty() => {
+                                Some(prefix.range().1)
+                            }
+                            Some((prefix, _, _)) if full_batch => Some(prefix.bytes().to_vec()),
+                            Some(last) => {
+                                counters.push(last);
+                                None
+                            }
+                            None => None,
+                        };
+
+                        let mut batch_coalesced = 0;
+                        for (prefix, count, total) in counters {
+                            if count > 1 {
+                                let (begin, end) = prefix.range();
+                                tx.clear_range(&begin, &end)?;
+                                tx.set(&prefix.pack(&0u32), &total.to_le_bytes());
+
+                                batch_coalesced += 1;
+                            }
+                        }
+
+                        Ok(((next, batch_coalesced), tx))
+                    }
+                })
+                .await?;
+
+            coalesced += batch_coalesced;
+
+            match next {
+                Some(next) => cursor = next,
+                None => return Ok(coalesced),
+            }
+        }
+    }
+
+    /// Group consecutive shards by counter.
+    fn group_shards<E>(&self, shards: &[(Vec<u8>, i64)]) -> DbResult<Vec<CounterShards>, E> {
+        let mut counters: Vec<CounterShards> = vec![];
+
+        for (key, value) in shards {
+            let Ok((_, shard)) = self.subspace.unpack::<(Element<'_>, u32)>(key) else {
+                return Err(DbError::Storage(
+                    format!("invalid counter shard key: {key:?}").into(),
+                ));
+            };
+
+            // The shard index is the last element of the key.
+            let prefix_len = key.len() - shard.pack_to_vec().len();
+            let prefix = Subspace::from_bytes(&key[..prefix_len]);
+
+            match counters.last_mut() {
+                Some((last, count, total)) if last.bytes() == prefix.bytes() => {
+                    *count += 1;
+                    *total = total.wrapping_add(*value);
+                }
+                _ => counters.push((prefix, 1, *value)),
+            }
+        }
+
+        Ok(counters)
+    }
+
+    /// The subspace with the shards of a counter.
+    fn counter_subspace(&self, key: &impl TuplePack) -> Subspace {
+        // Pack the key as a single element, so that the shards of a counter
+        // are never inside the range of another one.
+        self.subspace.subspace(&(key,))
+    }
+}
+
+/// The subspace of a counter, its number of shards and its total.
+type CounterShards = (Subspace, usize, i64);
+
+/// Decode the value of a shard, a little-endian integer.
+///
+/// Shards are only written by atomic additions, which always store 64-bit
+/// integers, but follow their semantics for smaller or larger values anyway.
+fn decode_shard(value: &[u8]) -> i64 {
+    let mut bytes = [0; 8];
+    let len = value.len().min(8);
+    bytes[..len].copy_from_slice(&value[..len]);
+
+    i64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use pl_api_status::Status;
+
+    use super::*;
+
+    async fn add(db: &Db, counters: &Counter, key: &str, delta: i64) {
+        db.transaction(|mut tx| async move {
+            counters.add(&mut tx, &key, delta);
+            Ok(((), tx)) as DbResult<_, Status>
+        })
+        .await
+        .expect("failed to add to counter");
+    }
+
+    async fn get(db: &Db, counters: &Counter, key: &str) -> (i64, usize) {
+        db.transaction(|tx| async move {
+            let total = counters.get(&tx, &key).await?;
+
+            let mut shards = 0usize;
+            let range = RangeOption::from(counters.counter_subspace(&key).range());
+            tx.for_each_in_range(range, |_, _| {
+                shards += 1;
+                std::future::ready(Ok(true)) as std::future::Ready<DbResult<_, Status>>
+            })
+            .await?;
+
+            Ok(((total, shards), tx)) as DbResult<_, Status>
+        })
+        .await
+        .expect("failed to get counter")
+    }
+
+    #[tokio::test]
+    async fn test_counter() {
+        let db = Db::temporary();
+        let counters = Counter::from_static("counters").with_shards(4);
+
+        for _ in 0..20 {
+            add(&db, &counters, "clones", 1).await;
+        }
+        add(&db, &counters, "clones", -5).await;
+        add(&db, &counters, "objects", 7).await;
+
+        let (total, shards) = get(&db, &counters, "clones").await;
+        assert_eq!(total, 15);
+        assert!(shards > 1, "increments weren't sharded");
+
+        let (total, _) = get(&db, &counters, "objects").await;
+        assert_eq!(total, 7, "counters aren't isolated");
+
+        db.transaction(|mut tx| {
+            let counters = &counters;
+            async move {
+                counters.coalesce(&mut tx, &"clones").await?;
+                Ok(((), tx)) as DbResult<_, Status>
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(get(&db, &counters, "clones").await, (15, 1));
+
+        let (total, shards) = get(&db, &counters, "missing").await;
+        assert_eq!((total, shards), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_all() {
+        let db = Db::temporary();
+        let counters = Counter::from_static("counters").with_shards(4);
+        let keys = ["a", "b", "c", "d", "e"];
+
+        for key in keys {
+            for _ in 0..10 {
+                add(&db, &counters, key, 2).await;
+            }
+        }
+
+        // Small batches force counters to be split between them.
+        let coalesced = counters.coalesce_all::<Status>(&db, 5).await.unwrap();
+        assert!(coalesced > 0, "no counter was coalesced");
+
+        for key in keys {
+            assert_eq!(get(&db, &counters, key).await, (20, 1), "counter {key}");
+        }
+
+        let coalesced = counters.coalesce_all::<Status>(&db, 5).await.unwrap();
+        assert_eq!(coalesced, 0, "coalesced counters twice");
+    }
+}
@@ -0,0 +1,467 @@
+//! # Leases and leader election
+//!
+//! Some background workers, e.g. the garbage collector, must run in a single
+//! replica at a time. Replicas compete for a named lease, and only the holder
+//! does the work. Holders renew their lease periodically, and lose it if they
+//! don't do so before it expires, e.g. because they crashed, after which another
+//! replica can acquire it.
+//!
+//! A holder may not know that it lost its lease, e.g. if it was paused for
+//! longer than the lease duration. To prevent it from doing work concurrently
+//! with the new holder, each acquisition gets a fencing token larger than all
+//! previous ones. Check the lease with [`Leases::ensure_held`] in transactions
+//! that do the work, or send the token to external systems so that they reject
+//! requests with older tokens.
+//!
+//! Lease deadlines are set with the clock of the holder, but checked with the
+//! clock of the replica trying to acquire the lease. Replicas with a clock
+//! ahead of the holder take over leases before they expire, which only fencing
+//! tokens guard against, keep the clocks synchronized.
+use std::{
+    error::Error,
+    fmt, io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use foundationdb::tuple::Subspace;
+use pl_api_status::Status;
+use pl_database::{duration_millis, unix_millis, Db, DbError, DbResult, StorageError, Tx};
+
+/// Maximum time [`Leases::campaign`] waits before trying to acquire the lease
+/// again.
+///
+/// Campaigners sleep until the lease expires, and are woken up earlier if the
+/// holder releases it. This bounds the wait if the wake-up is lost with the
+/// database connection, or if the holder's clock is far ahead.
+const MAX_CAMPAIGN_WAIT: Duration = Duration::from_secs(30);
+
+/// A set of named leases.
+pub struct Leases {
+    subspace: Subspace,
+}
+
+/// A lease acquired by a holder.
+///
+/// Renew it with [`Leases::renew`] before it expires, e.g. every third of its
+/// duration, or it may be acquired by another holder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    name: String,
+    holder: String,
+    token: u64,
+    /// Milliseconds since the UNIX epoch.
+    deadline: u64,
+}
+
+/// The state of a lease, as stored in the database.
+struct LeaseRecord {
+    token: u64,
+    deadline: u64,
+    holder: String,
+}
+
+impl Leases {
+    /// Create a new set of leases with a static name.
+    ///
+    /// The name must not clash with any other layer stored in the same
+    /// database, as it is used as the prefix of all keys of the leases.
+    pub fn from_static(name: &'static str) -> Self {
+        Self {
+            subspace: Subspace::from_bytes(name.as_bytes()),
+        }
+    }
+
+    /// Try to acquire a lease for `ttl`, returning `None` if another holder
+    /// has it.
+    ///
+    /// If `holder` already has the lease, it is renewed instead, keeping its
+    /// fencing token. Concurrent acquisitions of the same lease conflict, so
+    /// only one of them succeeds, and the others retry their transactions.
+    pub async fn acquire(
+        &self,
+        tx: &mut Tx,
+        name: &str,
+        holder: &str,
+        ttl: Duration,
+    ) -> DbResult<Option<Lease>, LeaseError> {
+        let now = unix_millis(SystemTime::now());
+        let current = self.record(tx, name).await?;
+
+        let token = match current {
+            Some(record) if record.deadline > now && record.holder != holder => return Ok(None),
+            Some(record) if record.deadline > now => record.token,
+            Some(record) => record.token + 1,
+            None => 1,
+        };
+
+        let lease = Lease {
+            name: name.to_string(),
+            holder: holder.to_string(),
+            token,
+            deadline: now.saturating_add(duration_millis(ttl)),
+        };
+        self.write(tx, &lease);
+
+        Ok(Some(lease))
+    }
+
+    /// Wait until a lease is available and acquire it.
+    ///
+    /// Each try runs in its own transaction. Between them, this waits for the
+    /// lease to be released using a watch, or for it to expire.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the transactions fail.
+    pub async fn campaign<E>(
+        &self,
+        db: &Db,
+        name: &str,
+        holder: &str,
+        ttl: Duration,
+    ) -> Result<Lease, E>
+    where
+        E: From<StorageError> + From<LeaseError> + Error,
+    {
+        loop {
+            let (lease, watch, deadline) = db
+                .transaction(|mut tx| async move {
+                    let lease = self
+                        .acquire(&mut tx, name, holder, ttl)
+                        .await
+                        .map_err(|err| err.map_abort(E::from))?;
+                    if lease.is_some() {
+                        return Ok(((lease, None, None), tx));
+                    }
+
+                    let current = self
+                        .record(&tx, name)
+                        .await
+                        .map_err(|err| err.map_abort(E::from))?;
+
+                    let watch = tx.watch(&self.subspace.pack(&name));
+                    Ok(((None, Some(watch), current.map(|r| r.deadline)), tx))
+                })
+                .await?;
+
+            if let Some(lease) = lease {
+                return Ok(lease);
+            }
+
+            let now = unix_millis(SystemTime::now());
+            let wait = deadline.map_or(MAX_CAMPAIGN_WAIT, |deadline| {
+                Duration::from_millis(deadline.saturating_sub(now)).min(MAX_CAMPAIGN_WAIT)
+            });
+
+            if let Some(watch) = watch {
+                if let Ok(Err(DbError::Storage(err))) = tokio::time::timeout(wait, watch).await {
+                    return Err(E::from(err));
+                }
+            }
+        }
+    }
+
+    /// Extend a lease for `ttl` from now.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`LeaseError::LeaseLost`] if the lease expired, even if no other
+    /// holder acquired it since, as its holder may have stopped working on it.
+    pub async fn renew(
+        &self,
+        tx: &mut Tx,
+        lease: &mut Lease,
+        ttl: Duration,
+    ) -> DbResult<(), LeaseError> {
+        self.ensure_held(tx, lease).await?;
+
+        lease.deadline = unix_millis(SystemTime::now()).saturating_add(duration_millis(ttl));
+        self.write(tx, lease);
+
+        Ok(())
+    }
+
+    /// Release a lease, so that other holders can acquire it immediately.
+    ///
+    /// Does nothing if the lease was already lost.
+    pub async fn release(&self, tx: &mut Tx, lease: &Lease) -> DbResult<(), LeaseError> {
+        if self.ensure_held(tx, lease).await.is_ok() {
+            // Keep the token, so that the next holder gets a larger one.
+            self.write(
+                tx,
+                &Lease {
+                    deadline: 0,
+                    ..lease.clone()
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Check that a lease is still held, failing the transaction otherwise.
+    ///
+    /// This reads the lease, meaning that the transaction conflicts with any
+    /// other holder acquiring it. Use it in transactions that must only commit
+    /// while the lease is held.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`LeaseError::LeaseLost`] if the lease expired, or was acquired
+    /// by another holder.
+    pub async fn ensure_held(&self, tx: &Tx, lease: &Lease) -> DbResult<(), LeaseError> {
+        let now = unix_millis(SystemTime::now());
+
+        match self.record(tx, &lease.name).await? {
+            Some(record) if record.token == lease.token && record.deadline > now => Ok(()),
+            _ => Err(DbError::Abort(LeaseError::LeaseLost)),
+        }
+    }
+
+    /// Get the current holder of a lease and its fencing token, if the lease
+    /// is held.
+    pub async fn holder(&self, tx: &Tx, name: &str) -> DbResult<Option<(String, u64)>, LeaseError> {
+        let now = unix_millis(SystemTime::now());
+
+        match self.record(tx, name).await? {
+            Some(record) if record.deadline > now => Ok(Some((record.holder, record.token))),
+            _ => Ok(None),
+        }
+    }
+
+    async fn record(&self, tx: &Tx, name: &str) -> DbResult<Option<LeaseRecord>, LeaseError> {
+        match tx.get(&self.subspace.pack(&name)).await? {
+            Some(value) => decode_record(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&self, tx: &mut Tx, lease: &Lease) {
+        let mut value = Vec::with_capacity(16 + lease.holder.len());
+        value.extend_from_slice(&lease.token.to_le_bytes());
+        value.extend_from_slice(&lease.deadline.to_le_bytes());
+        value.extend_from_slice(lease.holder.as_bytes());
+
+        tx.set(&self.subspace.pack(&lease.name), &value);
+    }
+}
+
+impl Lease {
+    /// The name of the lease.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The holder of the lease.
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// The fencing token of the lease, larger than the ones of all previous
+    /// holders.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// When the lease expires, unless renewed.
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.deadline)
+    }
+}
+
+/// Decode a lease as `[token u64][deadline u64][holder]`, little-endian.
+fn decode_record(bytes: &[u8]) -> DbResult<LeaseRecord, LeaseError> {
+    let invalid = || {
+        DbError::Abort(LeaseError::InvalidData(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid lease in database!",
+        )))
+    };
+
+    if bytes.len() < 16 {
+        return Err(invalid());
+    }
+
+    let (numbers, holder) = bytes.split_at(16);
+    let read_u64 = |b: &[u8]| u64::from_le_bytes(b.try_into().unwrap());
+
+    Ok(LeaseRecord {
+        token: read_u64(&numbers[..8]),
+        deadline: read_u64(&numbers[8..]),
+        holder: String::from_utf8(holder.to_vec()).map_err(|_| invalid())?,
+    })
+}
+
+/// Errors returned by [`Leases`] operations.
+#[derive(Debug)]
+pub enum LeaseError {
+    /// The lease expired, and may have been acquired by another holder.
+    LeaseLost,
+    /// The data stored in the database is corrupted.
+    InvalidData(io::Error),
+}
+
+impl fmt::Display for LeaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LeaseLost => f.write_str("lease was lost"),
+            Self::InvalidData(err) => write!(f, "invalid lease data: {err}"),
+        }
+    }
+}
+
+impl Error for LeaseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidData(err) => Some(err),
+            Self::LeaseLost => None,
+        }
+    }
+}
+
+impl From<LeaseError> for Status {
+    fn from(err: LeaseError) -> Self {
+        match err {
+            LeaseError::LeaseLost => Status::aborted(err),
+            LeaseError::InvalidData(err) => Status::data_loss(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, OnceLock};
+
+    use pl_database::{run, try_run};
+    use tonic::Code;
+
+    use super::*;
+
+    const TTL: Duration = Duration::from_millis(100);
+
+    #[tokio::test]
+    async fn test_fencing() {
+        let db = Db::temporary();
+        let leases = &Leases::from_static("leases");
+
+        let first = run!(db, |mut tx| leases.acquire(&mut tx, "gc", "a", TTL))
+            .expect("failed to acquire free lease");
+        let other = run!(db, |mut tx| leases.acquire(&mut tx, "gc", "b", TTL));
+        assert_eq!(other, None, "acquired held lease");
+
+        // Acquiring again renews the lease.
+        let again = run!(db, |mut tx| leases.acquire(&mut tx, "gc", "a", TTL))
+            .expect("holder failed to acquire its lease");
+        assert_eq!(again.token(), first.token());
+
+        tokio::time::sleep(TTL).await;
+
+        let second = run!(db, |mut tx| leases.acquire(&mut tx, "gc", "b", TTL))
+            .expect("failed to acquire expired lease");
+        assert!(second.token() > first.token(), "token didn't increase");
+
+        let first = &first;
+        let err = try_run!(db, |tx| leases.ensure_held(&tx, first)).unwrap_err();
+        assert_eq!(err.code(), Code::Aborted);
+        let err =
+            try_run!(db, |mut tx| leases.renew(&mut tx, &mut first.clone(), TTL)).unwrap_err();
+        assert_eq!(err.code(), Code::Aborted);
+
+        let holder = run!(db, |tx| leases.holder(&tx, "gc"));
+        assert_eq!(holder, Some(("b".to_string(), second.token())));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_acquire() {
+        let db = &Db::temporary();
+        let leases = &Leases::from_static("leases");
+
+        let second = &OnceLock::new();
+
+        // Another holder acquires the lease between the read and the commit of
+        // the first transaction, which must retry and see it.
+        let first = db
+            .transaction(|mut tx| async move {
+                let first = leases
+                    .acquire(&mut tx, "gc", "a", TTL * 100)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+
+                if second.get().is_none() {
+                    let lease =
+                        try_run!(db, |mut tx| leases.acquire(&mut tx, "gc", "b", TTL * 100))
+                            .map_err(DbError::Abort)?;
+                    second.get_or_init(|| lease);
+                }
+
+                Ok((first, tx))
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(first, None, "both holders acquired the lease");
+        let second = second
+            .get()
+            .cloned()
+            .flatten()
+            .expect("failed to acquire free lease");
+
+        let holder = run!(db, |tx| leases.holder(&tx, "gc"));
+        assert_eq!(holder, Some(("b".to_string(), second.token())));
+    }
+
+    #[tokio::test]
+    async fn test_release() {
+        let db = Db::temporary();
+        let leases = &Leases::from_static("leases");
+
+        let first = &run!(db, |mut tx| leases.acquire(&mut tx, "gc", "a", TTL * 100)).unwrap();
+        run!(db, |mut tx| leases.release(&mut tx, first));
+
+        let holder = run!(db, |tx| leases.holder(&tx, "gc"));
+        assert_eq!(holder, None, "lease wasn't released");
+
+        let second = run!(db, |mut tx| leases.acquire(&mut tx, "gc", "b", TTL))
+            .expect("failed to acquire released lease");
+        assert!(second.token() > first.token(), "token didn't increase");
+
+        // Releasing a lost lease doesn't affect the new holder.
+        run!(db, |mut tx| leases.release(&mut tx, first));
+        let holder = run!(db, |tx| leases.holder(&tx, "gc"));
+        assert_eq!(holder, Some(("b".to_string(), second.token())));
+    }
+
+    #[tokio::test]
+    async fn test_campaign() {
+        let db = Arc::new(Db::temporary());
+        let leases = Arc::new(Leases::from_static("leases"));
+
+        let first = leases
+            .campaign::<Status>(&db, "gc", "a", Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        let campaign = tokio::spawn({
+            let (db, leases) = (db.clone(), leases.clone());
+            async move {
+                leases
+                    .campaign::<Status>(&db, "gc", "b", Duration::from_secs(60))
+                    .await
+                    .unwrap()
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!campaign.is_finished(), "acquired held lease");
+
+        let (first, leases) = (&first, &*leases);
+        run!(db, |mut tx| leases.release(&mut tx, first));
+
+        let second = tokio::time::timeout(Duration::from_secs(5), campaign)
+            .await
+            .expect("campaign didn't wake up")
+            .unwrap();
+        assert_eq!(second.holder(), "b");
+        assert!(second.token() > first.token());
+    }
+}
@@ -0,0 +1,690 @@
+//! # Durable task queue
+//!
+//! This layer stores background work, e.g. the deletion of a repository after
+//! `DeleteRepository` returns its long-running operation. Items are enqueued in
+//! the caller's transaction, meaning that the work is only scheduled if the
+//! request that created it commits.
+//!
+//! Items are ordered by priority, and then by the versionstamp of the
+//! transaction that enqueued them. Workers lease items for a visibility
+//! timeout, during which no other worker sees them. If the worker doesn't
+//! acknowledge the item before the lease expires, e.g. because it crashed,
+//! the item is delivered again. Items delivered too many times are moved to
+//! the dead-letter set, where they wait for an operator to inspect them.
+//!
+//! Lease deadlines are set with the clock of the worker holding the item, but
+//! checked with the clock of the workers looking for items. Workers with a
+//! clock ahead of the others deliver items again while they are still being
+//! processed, keep the clocks synchronized.
+use std::{
+    error::Error,
+    fmt, io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use foundationdb::{
+    tuple::{Subspace, Versionstamp},
+    RangeOption,
+};
+use pl_api_status::Status;
+use pl_database::{duration_millis, unix_millis, Db, DbError, DbResult, StorageError, Tx};
+
+/// Default number of times an item is delivered before being dead-lettered.
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// Maximum time [`Queue::poll`] waits before checking the queue again.
+///
+/// Workers are woken up when items become ready, and sleep until the earliest
+/// lease expires otherwise. This bounds the wait for leases taken after the
+/// worker went to sleep, which may expire earlier, and for wake-ups lost with
+/// the database connection.
+const MAX_POLL_WAIT: Duration = Duration::from_secs(30);
+
+/// A durable queue of items.
+pub struct Queue {
+    /// Items waiting to be leased, keyed by `(priority, id)`.
+    ready: Subspace,
+    /// Leased and delayed items, keyed by `(deadline, id)`.
+    leased: Subspace,
+    /// Items that exhausted their attempts, keyed by `id`.
+    dead: Subspace,
+    /// Changed whenever an item becomes ready, to wake up workers.
+    signal: Vec<u8>,
+    max_attempts: u32,
+}
+
+/// Priority of an item.
+///
+/// Items with higher priority are always leased before ones with lower
+/// priority, regardless of how long the latter are waiting.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    High = 0,
+    #[default]
+    Normal = 1,
+    Low = 2,
+}
+
+/// An item of the queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    /// The unique identifier of the item, the versionstamp of the transaction
+    /// that enqueued it.
+    pub id: Versionstamp,
+    /// The priority of the item.
+    pub priority: Priority,
+    /// How many times the item was leased.
+    pub attempts: u32,
+    /// The data given when enqueueing the item.
+    pub payload: Vec<u8>,
+}
+
+/// An item leased by a worker.
+///
+/// Finish the lease with [`Queue::ack`], [`Queue::retry`] or
+/// [`Queue::dead_letter`] before it expires, or the item is delivered again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    item: Item,
+    /// Milliseconds since the UNIX epoch.
+    deadline: u64,
+}
+
+impl Queue {
+    /// Create a new queue with a static name.
+    ///
+    /// The name must not clash with any other layer stored in the same
+    /// database, as it is used as the prefix of all keys of the queue.
+    pub fn from_static(name: &'static str) -> Self {
+        let root = Subspace::from_bytes(name.as_bytes());
+
+        Self {
+            ready: root.subspace(&"r"),
+            leased: root.subspace(&"l"),
+            dead: root.subspace(&"d"),
+            signal: root.pack(&"s"),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+
+    /// Set how many times an item is leased before being dead-lettered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_attempts` is zero.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        assert!(max_attempts > 0, "items need at least one attempt");
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Add an item to the queue.
+    ///
+    /// The item is only visible to workers after the transaction commits.
+    pub fn enqueue(&self, tx: &mut Tx, priority: Priority, payload: &[u8]) {
+        let id = tx.next_versionstamp();
+        let key = self.ready.pack_with_versionstamp(&(priority as u8, id));
+
+        tx.set_versionstamped_key(&key, &encode_item(priority, 0, payload));
+        tx.atomic_add(&self.signal, 1);
+    }
+
+    /// Lease the next item of the queue for `visibility_timeout`.
+    ///
+    /// Items whose lease expired are leased first, as they were already waiting
+    /// in the queue before. Those that exhausted their attempts are moved to the
+    /// dead-letter set instead. Returns `None` if no item is available.
+    ///
+    /// All workers conflict when leasing the same item, meaning that only one
+    /// of them gets it, and the others retry their transactions.
+    pub async fn lease(
+        &self,
+        tx: &mut Tx,
+        visibility_timeout: Duration,
+    ) -> DbResult<Option<Lease>, QueueError> {
+        let now = unix_millis(SystemTime::now());
+
+        let expired = RangeOption::from((
+            self.leased.range().0,
+            self.leased.pack(&(now.saturating_add(1),)),
+        ));
+        while let Some((key, value)) = first_in_range(tx, expired.clone()).await? {
+            let (_, id) = self.unpack_leased(&key)?;
+            let item = decode_item(id, &value)?;
+
+            tx.clear(&key);
+            if item.attempts >= self.max_attempts {
+                tx.set(&self.dead.pack(&(id,)), &value);
+                continue;
+            }
+
+            return Ok(Some(self.lease_item(tx, item, now, visibility_timeout)));
+        }
+
+        let Some((key, value)) = first_in_range(tx, RangeOption::from(&self.ready)).await? else {
+            return Ok(None);
+        };
+
+        let (_, id) = self
+            .ready
+            .unpack::<(u8, Versionstamp)>(&key)
+            .map_err(|_| invalid_data("invalid ready item key in database!"))?;
+        let item = decode_item(id, &value)?;
+
+        tx.clear(&key);
+        Ok(Some(self.lease_item(tx, item, now, visibility_timeout)))
+    }
+
+    /// Wait until an item is available and lease it.
+    ///
+    /// Each try runs in its own transaction. Between them, this waits for new
+    /// items using a watch, or for the earliest lease to expire.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the transactions fail.
+    pub async fn poll<E>(&self, db: &Db, visibility_timeout: Duration) -> Result<Lease, E>
+    where
+        E: From<StorageError> + From<QueueError> + Error,
+    {
+        loop {
+            let (lease, watch, deadline) = db
+                .transaction(|mut tx| async move {
+                    let lease = self
+                        .lease(&mut tx, visibility_timeout)
+                        .await
+                        .map_err(|err| err.map_abort(E::from))?;
+                    if lease.is_some() {
+                        return Ok(((lease, None, None), tx));
+                    }
+
+                    let leased = first_in_range(&tx, RangeOption::from(&self.leased))
+                        .await
+                        .map_err(|err| err.map_abort(E::from))?;
+                    let deadline = match leased {
+                        Some((key, _)) => {
+                            let (deadline, _) = self
+                                .unpack_leased(&key)
+                                .map_err(|err| err.map_abort(E::from))?;
+                            Some(deadline)
+                        }
+                        None => None,
+                    };
+
+                    let watch = tx.watch(&self.signal);
+                    Ok(((None, Some(watch), deadline), tx))
+                })
+                .await?;
+
+            if let Some(lease) = lease {
+                return Ok(lease);
+            }
+
+            let now = unix_millis(SystemTime::now());
+            let wait = deadline.map_or(MAX_POLL_WAIT, |deadline| {
+                Duration::from_millis(deadline.saturating_sub(now)).min(MAX_POLL_WAIT)
+            });
+
+            if let Some(watch) = watch {
+                if let Ok(Err(DbError::Storage(err))) = tokio::time::timeout(wait, watch).await {
+                    return Err(E::from(err));
+                }
+            }
+        }
+    }
+
+    /// Extend a lease for `visibility_timeout` from now.
+    ///
+    /// Use this to keep items that take a long time to process.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`QueueError::LeaseLost`] if the item was leased by another
+    /// worker after the lease expired.
+    pub async fn extend(
+        &self,
+        tx: &mut Tx,
+        lease: &mut Lease,
+        visibility_timeout: Duration,
+    ) -> DbResult<(), QueueError> {
+        self.take_lease(tx, lease).await?;
+
+        let deadline =
+            unix_millis(SystemTime::now()).saturating_add(duration_millis(visibility_timeout));
+        *lease = self.set_lease(tx, lease.item.clone(), deadline);
+
+        Ok(())
+    }
+
+    /// Remove a leased item from the queue, as its processing finished.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`QueueError::LeaseLost`] if the item was leased by another
+    /// worker after the lease expired.
+    pub async fn ack(&self, tx: &mut Tx, lease: &Lease) -> DbResult<(), QueueError> {
+        self.take_lease(tx, lease).await
+    }
+
+    /// Return a leased item to the queue after `delay`, as its processing failed.
+    ///
+    /// Returns `false` if the item exhausted its attempts, in which case it is
+    /// moved to the dead-letter set.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`QueueError::LeaseLost`] if the item was leased by another
+    /// worker after the lease expired.
+    pub async fn retry(
+        &self,
+        tx: &mut Tx,
+        lease: &Lease,
+        delay: Duration,
+    ) -> DbResult<bool, QueueError> {
+        self.take_lease(tx, lease).await?;
+
+        let item = &lease.item;
+        let value = encode_item(item.priority, item.attempts, &item.payload);
+
+        if item.attempts >= self.max_attempts {
+            tx.set(&self.dead.pack(&(item.id,)), &value);
+            Ok(false)
+        } else if delay.is_zero() {
+            tx.set(&self.ready.pack(&(item.priority as u8, item.id)), &value);
+            tx.atomic_add(&self.signal, 1);
+            Ok(true)
+        } else {
+            // Delayed items are stored as leases that expire after the delay,
+            // which makes them available again without any other write.
+            let deadline = unix_millis(SystemTime::now()).saturating_add(duration_millis(delay));
+            tx.set(&self.leased.pack(&(deadline, item.id)), &value);
+            Ok(true)
+        }
+    }
+
+    /// Move a leased item to the dead-letter set, as it can't be processed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`QueueError::LeaseLost`] if the item was leased by another
+    /// worker after the lease expired.
+    pub async fn dead_letter(&self, tx: &mut Tx, lease: &Lease) -> DbResult<(), QueueError> {
+        self.take_lease(tx, lease).await?;
+
+        let item = &lease.item;
+        tx.set(
+            &self.dead.pack(&(item.id,)),
+            &encode_item(item.priority, item.attempts, &item.payload),
+        );
+
+        Ok(())
+    }
+
+    /// List up to `limit` items of the dead-letter set, oldest first.
+    pub async fn dead_letters(&self, tx: &Tx, limit: usize) -> DbResult<Vec<Item>, QueueError> {
+        let opts = RangeOption {
+            limit: Some(limit),
+            ..RangeOption::from(&self.dead)
+        };
+
+        let mut items = vec![];
+        tx.for_each_in_range(opts, |key, value| {
+            let res = self
+                .dead
+                .unpack::<(Versionstamp,)>(key)
+                .map_err(|_| invalid_data("invalid dead item key in database!"))
+                .and_then(|(id,)| decode_item(id, value))
+                .map(|item| {
+                    items.push(item);
+                    true
+                });
+
+            std::future::ready(res)
+        })
+        .await?;
+
+        Ok(items)
+    }
+
+    /// Return an item of the dead-letter set to the queue, resetting its attempts.
+    ///
+    /// Returns `false` if the item isn't in the dead-letter set.
+    pub async fn requeue(&self, tx: &mut Tx, id: Versionstamp) -> DbResult<bool, QueueError> {
+        let key = self.dead.pack(&(id,));
+        let Some(value) = tx.get(&key).await? else {
+            return Ok(false);
+        };
+
+        let item = decode_item(id, &value)?;
+        tx.clear(&key);
+        tx.set(
+            &self.ready.pack(&(item.priority as u8, id)),
+            &encode_item(item.priority, 0, &item.payload),
+        );
+        tx.atomic_add(&self.signal, 1);
+
+        Ok(true)
+    }
+
+    /// Remove an item from the dead-letter set.
+    pub fn remove_dead_letter(&self, tx: &mut Tx, id: Versionstamp) {
+        tx.clear(&self.dead.pack(&(id,)));
+    }
+
+    /// Lease an item that was removed from the queue, counting a new attempt.
+    fn lease_item(&self, tx: &mut Tx, mut item: Item, now: u64, timeout: Duration) -> Lease {
+        item.attempts = item.attempts.saturating_add(1);
+
+        self.set_lease(tx, item, now.saturating_add(duration_millis(timeout)))
+    }
+
+    fn set_lease(&self, tx: &mut Tx, item: Item, deadline: u64) -> Lease {
+        tx.set(
+            &self.leased.pack(&(deadline, item.id)),
+            &encode_item(item.priority, item.attempts, &item.payload),
+        );
+
+        Lease { item, deadline }
+    }
+
+    /// Unpack the deadline and id of a leased item key.
+    fn unpack_leased(&self, key: &[u8]) -> DbResult<(u64, Versionstamp), QueueError> {
+        self.leased
+            .unpack(key)
+            .map_err(|_| invalid_data("invalid leased item key in database!"))
+    }
+
+    /// Remove the key of a lease, checking that it is still held.
+    async fn take_lease(&self, tx: &mut Tx, lease: &Lease) -> DbResult<(), QueueError> {
+        let key = self.leased.pack(&(lease.deadline, lease.item.id));
+
+        // Another worker may have leased the item again with the same deadline,
+        // check the attempts too.
+        match tx.get(&key).await? {
+            Some(value) if decode_item(lease.item.id, &value)?.attempts == lease.item.attempts => {
+                tx.clear(&key);
+                Ok(())
+            }
+            _ => Err(DbError::Abort(QueueError::LeaseLost)),
+        }
+    }
+}
+
+impl Lease {
+    /// The leased item.
+    pub fn item(&self) -> &Item {
+        &self.item
+    }
+
+    /// Take the leased item.
+    pub fn into_item(self) -> Item {
+        self.item
+    }
+
+    /// When the lease expires.
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.deadline)
+    }
+}
+
+/// Get the first key-value pair in a range.
+async fn first_in_range(
+    tx: &Tx,
+    opts: RangeOption<'_>,
+) -> DbResult<Option<(Vec<u8>, Vec<u8>)>, QueueError> {
+    let opts = RangeOption {
+        limit: Some(1),
+        ..opts
+    };
+
+    let mut first = None;
+    tx.for_each_in_range(opts, |key, value| {
+        first = Some((key.to_vec(), value.to_vec()));
+        std::future::ready(Ok(false)) as std::future::Ready<DbResult<_, QueueError>>
+    })
+    .await?;
+
+    Ok(first)
+}
+
+/// Encode an item as `[priority u8][attempts u32][payload]`, little-endian.
+fn encode_item(priority: Priority, attempts: u32, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(5 + payload.len());
+    bytes.push(priority as u8);
+    bytes.extend_from_slice(&attempts.to_le_bytes());
+    bytes.extend_from_slice(payload);
+
+    bytes
+}
+
+fn decode_item(id: Versionstamp, bytes: &[u8]) -> DbResult<Item, QueueError> {
+    let invalid = || invalid_data("invalid queue item in database!");
+
+    let (&[priority, a, b, c, d], payload) = bytes.split_at(5.min(bytes.len())) else {
+        return Err(invalid());
+    };
+    let priority = match priority {
+        0 => Priority::High,
+        1 => Priority::Normal,
+        2 => Priority::Low,
+        _ => return Err(invalid()),
+    };
+
+    Ok(Item {
+        id,
+        priority,
+        attempts: u32::from_le_bytes([a, b, c, d]),
+        payload: payload.to_vec(),
+    })
+}
+
+fn invalid_data(msg: &'static str) -> DbError<QueueError> {
+    DbError::Abort(QueueError::InvalidData(io::Error::new(
+        io::ErrorKind::InvalidData,
+        msg,
+    )))
+}
+
+/// Errors returned by [`Queue`] operations.
+#[derive(Debug)]
+pub enum QueueError {
+    /// The lease expired, and the item was leased again or removed.
+    LeaseLost,
+    /// The data stored in the database is corrupted.
+    InvalidData(io::Error),
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LeaseLost => f.write_str("lease of queue item was lost"),
+            Self::InvalidData(err) => write!(f, "invalid queue data: {err}"),
+        }
+    }
+}
+
+impl Error for QueueError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidData(err) => Some(err),
+            Self::LeaseLost => None,
+        }
+    }
+}
+
+impl From<QueueError> for Status {
+    fn from(err: QueueError) -> Self {
+        match err {
+            QueueError::LeaseLost => Status::aborted(err),
+            QueueError::InvalidData(err) => Status::data_loss(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use pl_database::{run, try_run};
+    use tonic::Code;
+
+    use super::*;
+
+    async fn enqueue(db: &Db, queue: &Queue, items: &[(Priority, &str)]) {
+        db.transaction(|mut tx| async move {
+            for (priority, payload) in items {
+                queue.enqueue(&mut tx, *priority, payload.as_bytes());
+            }
+
+            Ok(((), tx)) as DbResult<_, Status>
+        })
+        .await
+        .expect("failed to enqueue");
+    }
+
+    async fn lease(db: &Db, queue: &Queue, timeout: Duration) -> Option<Lease> {
+        try_run!(db, |mut tx| queue.lease(&mut tx, timeout)).expect("failed to lease")
+    }
+
+    #[tokio::test]
+    async fn test_priorities() {
+        let db = Db::temporary();
+        let queue = &Queue::from_static("queue");
+        let timeout = Duration::from_secs(60);
+
+        enqueue(&db, queue, &[(Priority::Normal, "a"), (Priority::Low, "b")]).await;
+        enqueue(
+            &db,
+            queue,
+            &[(Priority::High, "c"), (Priority::Normal, "d")],
+        )
+        .await;
+
+        let mut payloads = vec![];
+        while let Some(lease) = &lease(&db, queue, timeout).await {
+            assert_eq!(lease.item().attempts, 1);
+            payloads.push(String::from_utf8(lease.item().payload.clone()).unwrap());
+
+            run!(db, |mut tx| queue.ack(&mut tx, lease));
+        }
+
+        assert_eq!(payloads, ["c", "a", "d", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lease() {
+        let db = &Db::temporary();
+        let queue = &Queue::from_static("queue");
+        let timeout = Duration::from_secs(60);
+        let other = &OnceLock::<Option<Lease>>::new();
+
+        enqueue(
+            db,
+            queue,
+            &[(Priority::Normal, "a"), (Priority::Normal, "b")],
+        )
+        .await;
+
+        // Another worker leases the first item between the read and the commit
+        // of this transaction, which must retry and lease the next one.
+        let first = db
+            .transaction(|mut tx| async move {
+                let leased = queue
+                    .lease(&mut tx, timeout)
+                    .await
+                    .map_err(|err| err.map_abort(Status::from))?;
+
+                if other.get().is_none() {
+                    let _ = other.set(lease(db, queue, timeout).await);
+                }
+
+                Ok::<_, DbError<Status>>((leased, tx))
+            })
+            .await
+            .unwrap()
+            .expect("failed to lease");
+        let other = other.get().cloned().flatten().expect("failed to lease");
+
+        assert_eq!(other.item().payload, b"a");
+        assert_eq!(first.item().payload, b"b", "leased the same item twice");
+    }
+
+    #[tokio::test]
+    async fn test_visibility_timeout() {
+        let db = Db::temporary();
+        let queue = &Queue::from_static("queue");
+
+        enqueue(&db, queue, &[(Priority::Normal, "a")]).await;
+
+        let first = &lease(&db, queue, Duration::ZERO).await.unwrap();
+        let second = &lease(&db, queue, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(
+            second.item().id,
+            first.item().id,
+            "expired item not leased again"
+        );
+        assert_eq!(second.item().attempts, 2);
+
+        assert!(
+            lease(&db, queue, Duration::from_secs(60)).await.is_none(),
+            "leased item is visible"
+        );
+
+        let err = try_run!(db, |mut tx| queue.ack(&mut tx, first)).unwrap_err();
+        assert_eq!(err.code(), Code::Aborted, "expired lease acknowledged");
+
+        run!(db, |mut tx| queue.ack(&mut tx, second));
+        assert!(lease(&db, queue, Duration::ZERO).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_retry_and_dead_letter() {
+        let db = Db::temporary();
+        let queue = &Queue::from_static("queue").with_max_attempts(2);
+        let timeout = Duration::from_secs(60);
+
+        enqueue(&db, queue, &[(Priority::Normal, "a")]).await;
+
+        let lease1 = &lease(&db, queue, timeout).await.unwrap();
+        let retried = run!(db, |mut tx| queue.retry(&mut tx, lease1, Duration::ZERO));
+        assert!(retried);
+
+        let lease2 = &lease(&db, queue, timeout).await.unwrap();
+        assert_eq!(lease2.item().attempts, 2);
+        let retried = run!(db, |mut tx| queue.retry(&mut tx, lease2, Duration::ZERO));
+        assert!(!retried, "item retried after exhausting its attempts");
+
+        assert!(lease(&db, queue, timeout).await.is_none());
+
+        let dead = run!(db, |tx| queue.dead_letters(&tx, 10));
+        assert_eq!(dead, [lease2.item().clone()]);
+
+        let id = dead[0].id;
+        assert!(run!(db, |mut tx| queue.requeue(&mut tx, id)));
+        assert!(run!(db, |tx| queue.dead_letters(&tx, 10)).is_empty());
+
+        let lease3 = &lease(&db, queue, timeout).await.unwrap();
+        assert_eq!(lease3.item().attempts, 1, "attempts not reset");
+
+        // Delayed retries are not visible until the delay passes.
+        let delay = Duration::from_millis(50);
+        run!(db, |mut tx| queue.retry(&mut tx, lease3, delay));
+        assert!(lease(&db, queue, timeout).await.is_none());
+
+        let lease4 = queue.poll::<Status>(&db, timeout).await.unwrap();
+        assert_eq!(lease4.item().id, id);
+    }
+
+    #[tokio::test]
+    async fn test_poll() {
+        let db = &Db::temporary();
+        let queue = &Queue::from_static("queue");
+
+        let (lease, ()) = tokio::join!(queue.poll::<Status>(db, Duration::from_secs(60)), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            enqueue(db, queue, &[(Priority::Normal, "a")]).await;
+        });
+
+        assert_eq!(lease.unwrap().item().payload, b"a");
+    }
+}
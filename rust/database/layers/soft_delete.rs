@@ -0,0 +1,667 @@
+//! # Soft delete
+//!
+//! Deleting some resources by accident is costly, e.g. a repository with its
+//! whole history. This layer wraps a [`Collection`] to implement [AIP-164]:
+//! deleting an entity only marks it with a tombstone, hiding it from reads
+//! but keeping it until its retention expires. Meanwhile, it can be listed
+//! with [`SoftDeleteCollection::range_with_deleted`], the `show_deleted` flag
+//! of list requests, and restored with [`SoftDeleteCollection::undelete`]. A
+//! purge job removes the expired ones later, in batches.
+//!
+//! Soft-deleted entities stay in the wrapped collection, so they keep their
+//! unique index values, are re-encrypted by [`SoftDeleteCollection::rotate_keys`]
+//! and their keys can't be reused until they are purged. The wrapped collection
+//! isn't exposed, as its reads would return them. Tombstones are stored in
+//! an [`ExpiryIndex`], in the layer subspace of the collection: by entity key,
+//! to filter reads, and by expiration time, so that purges find expired
+//! tombstones without scanning the whole collection. Always write the entities
+//! through [`SoftDeleteCollection`], or both get out of sync.
+//!
+//! Retention uses the clock of the processes, keep them synchronized.
+//!
+//! [AIP-164]: https://google.aip.dev/164
+use std::{
+    error::Error,
+    fmt, io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use foundationdb::{
+    tuple::{Subspace, TuplePack},
+    RangeOption,
+};
+use pl_api_resource_name::ResourceName;
+use pl_api_status::Status;
+use pl_database::{unix_millis, Db, DbError, DbResult, StorageError, Tx};
+use pl_database_layers_changefeed::ChangeOperation;
+use pl_database_layers_collection::{Collection, CollectionError, ExpiryIndex, PackedKey};
+use prost::Message;
+
+/// A collection whose deletes can be undone until their retention expires.
+pub struct SoftDeleteCollection<E> {
+    collection: Collection<E>,
+    /// How long soft-deleted entities are kept.
+    retention: Duration,
+    /// Tombstones of the soft-deleted entities, expiring when they can be purged.
+    tombstones: ExpiryIndex,
+}
+
+/// When a soft-deleted entity was deleted, and when it will be purged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tombstone {
+    /// When the entity was deleted.
+    pub delete_time: SystemTime,
+    /// When the entity can be purged.
+    pub expire_time: SystemTime,
+}
+
+impl<E> SoftDeleteCollection<E> {
+    /// Wrap a collection, keeping deleted entities for `retention`.
+    pub fn new(collection: Collection<E>, retention: Duration) -> Self {
+        Self {
+            retention,
+            tombstones: ExpiryIndex::new(&collection.layer_subspace("softDelete")),
+            collection,
+        }
+    }
+
+    /// The subspace with the keys of the entities, see [`Collection::subspace`].
+    ///
+    /// Use it to build the ranges given to [`Self::range`].
+    pub fn subspace(&self) -> &Subspace {
+        self.collection.subspace()
+    }
+
+    /// Get the tombstone of an entity, if it is soft-deleted.
+    async fn tombstone(
+        &self,
+        tx: &Tx,
+        packed: &[u8],
+    ) -> DbResult<Option<Tombstone>, CollectionError> {
+        match self.tombstones.get(tx, packed).await? {
+            Some(value) => decode_tombstone(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the tombstone of an entity.
+    fn clear_tombstone(&self, tx: &mut Tx, packed: &[u8], tombstone: &Tombstone) {
+        self.tombstones
+            .clear(tx, packed, unix_millis(tombstone.expire_time));
+    }
+}
+
+impl<E> SoftDeleteCollection<E>
+where
+    E: Message + Default,
+{
+    /// Get an entity, returning `None` if it isn't present or soft-deleted.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::get`] apply here.
+    pub async fn get(&self, tx: &Tx, key: &impl TuplePack) -> DbResult<Option<E>, CollectionError> {
+        let packed = key.pack_to_vec();
+
+        if self.tombstone(tx, &packed).await?.is_some() {
+            return Ok(None);
+        }
+
+        self.collection.get(tx, &PackedKey(&packed)).await
+    }
+
+    /// Get a soft-deleted entity and its tombstone, returning `None` if it
+    /// isn't present or not deleted.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::get`] apply here, and an error is
+    /// returned if the tombstone is corrupted.
+    pub async fn get_deleted(
+        &self,
+        tx: &Tx,
+        key: &impl TuplePack,
+    ) -> DbResult<Option<(E, Tombstone)>, CollectionError> {
+        let packed = key.pack_to_vec();
+
+        let Some(tombstone) = self.tombstone(tx, &packed).await? else {
+            return Ok(None);
+        };
+        let entity = self.collection.get(tx, &PackedKey(&packed)).await?;
+
+        Ok(entity.map(|entity| (entity, tombstone)))
+    }
+
+    /// Get a range of entities, skipping soft-deleted ones.
+    ///
+    /// Keys are read from [`Self::subspace`], as in [`Collection::range`].
+    /// Soft-deleted entities don't count against the
+    /// limit of `opts`, the range continues until enough live entities are found.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::range`] apply here.
+    pub async fn range(&self, tx: &Tx, opts: RangeOption<'_>) -> DbResult<Vec<E>, CollectionError> {
+        let entities = self.range_entries(tx, opts, false).await?;
+
+        Ok(entities.into_iter().map(|(entity, _)| entity).collect())
+    }
+
+    /// Get a range of entities, including soft-deleted ones with their tombstone.
+    ///
+    /// This implements the `show_deleted` flag of [AIP-164] list requests, the
+    /// range is given as in [`Self::range`].
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::range`] apply here.
+    ///
+    /// [AIP-164]: https://google.aip.dev/164
+    pub async fn range_with_deleted(
+        &self,
+        tx: &Tx,
+        opts: RangeOption<'_>,
+    ) -> DbResult<Vec<(E, Option<Tombstone>)>, CollectionError> {
+        self.range_entries(tx, opts, true).await
+    }
+
+    /// Read a range of entities with their tombstones, skipping the soft-deleted
+    /// ones unless `show_deleted` is set.
+    async fn range_entries(
+        &self,
+        tx: &Tx,
+        opts: RangeOption<'_>,
+        show_deleted: bool,
+    ) -> DbResult<Vec<(E, Option<Tombstone>)>, CollectionError> {
+        self.tombstones
+            .range(&self.collection, tx, opts, |_, entity, tombstone| {
+                let tombstone = tombstone.map(decode_tombstone).transpose()?;
+
+                Ok((show_deleted || tombstone.is_none()).then_some((entity, tombstone)))
+            })
+            .await
+    }
+
+    /// List the direct children of `parent` in the `collection_id` collection,
+    /// skipping soft-deleted ones.
+    ///
+    /// Children are listed as in [`Collection::list_children`], and soft-deleted
+    /// ones don't count against `limit`.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::list_children`] apply here.
+    pub async fn list_children(
+        &self,
+        tx: &Tx,
+        parent: Option<&ResourceName>,
+        collection_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<E>, CollectionError> {
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+
+        let (children, range) = self.collection.children_range(parent, collection_id, after);
+        let opts = RangeOption {
+            limit: Some(limit),
+            ..RangeOption::from(range)
+        };
+
+        self.tombstones
+            .range(&self.collection, tx, opts, |key, entity, tombstone| {
+                let is_child = children.unpack::<String>(key).is_ok();
+
+                Ok((is_child && tombstone.is_none()).then_some(entity))
+            })
+            .await
+    }
+
+    /// Set the value of an entity.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SoftDeleteError::Deleted`] if the entity is soft-deleted, it
+    /// must be restored or purged first. The errors of [`Collection::set`] also
+    /// apply here.
+    pub async fn set(
+        &self,
+        tx: &mut Tx,
+        key: &impl TuplePack,
+        value: &E,
+    ) -> DbResult<(), SoftDeleteError> {
+        let packed = key.pack_to_vec();
+
+        let tombstone = self
+            .tombstone(tx, &packed)
+            .await
+            .map_err(|err| err.map_abort(SoftDeleteError::from))?;
+        if tombstone.is_some() {
+            return Err(DbError::Abort(SoftDeleteError::Deleted {
+                collection: self.collection.name(),
+            }));
+        }
+
+        self.collection
+            .set(tx, &PackedKey(&packed), value)
+            .await
+            .map_err(|err| err.map_abort(SoftDeleteError::from))
+    }
+
+    /// Soft-delete an entity, keeping it until its retention expires.
+    ///
+    /// Deleting an entity that isn't present or already deleted does nothing,
+    /// the retention of deleted entities isn't extended.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Self::get`] apply here.
+    pub async fn clear(&self, tx: &mut Tx, key: &impl TuplePack) -> DbResult<(), CollectionError> {
+        let packed = key.pack_to_vec();
+
+        let Some(entity) = self.get(tx, &PackedKey(&packed)).await? else {
+            return Ok(());
+        };
+
+        let delete_time = unix_millis(SystemTime::now());
+        let expiry = delete_time.saturating_add(self.retention.as_millis() as u64);
+
+        let mut tombstone = Vec::with_capacity(16);
+        tombstone.extend_from_slice(&delete_time.to_le_bytes());
+        tombstone.extend_from_slice(&expiry.to_le_bytes());
+
+        self.tombstones.set(tx, &packed, expiry, &tombstone);
+
+        self.collection.record_layer_change(
+            tx,
+            &PackedKey(&packed),
+            ChangeOperation::SoftDelete,
+            Some(&entity),
+            None,
+        );
+
+        Ok(())
+    }
+
+    /// Restore a soft-deleted entity, returning `None` if it isn't present or
+    /// not deleted.
+    ///
+    /// The entity is restored as it was when deleted, with the same etag.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Self::get_deleted`] apply here.
+    pub async fn undelete(
+        &self,
+        tx: &mut Tx,
+        key: &impl TuplePack,
+    ) -> DbResult<Option<E>, CollectionError> {
+        let packed = key.pack_to_vec();
+
+        let Some((entity, tombstone)) = self.get_deleted(tx, &PackedKey(&packed)).await? else {
+            return Ok(None);
+        };
+
+        self.clear_tombstone(tx, &packed, &tombstone);
+        self.collection.record_layer_change(
+            tx,
+            &PackedKey(&packed),
+            ChangeOperation::Undelete,
+            None,
+            Some(&entity),
+        );
+
+        Ok(Some(entity))
+    }
+
+    /// Permanently remove an entity, be it live or soft-deleted.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::clear`] apply here, and an error is
+    /// returned if the tombstone is corrupted.
+    pub async fn purge(&self, tx: &mut Tx, key: &impl TuplePack) -> DbResult<(), CollectionError> {
+        let packed = key.pack_to_vec();
+
+        if let Some(tombstone) = self.tombstone(tx, &packed).await? {
+            self.clear_tombstone(tx, &packed, &tombstone);
+        }
+
+        self.collection.clear(tx, &PackedKey(&packed)).await
+    }
+
+    /// Permanently remove the soft-deleted entities whose retention expired,
+    /// returning how many were removed.
+    ///
+    /// Entities are removed in batches of `batch_size`, each in its own
+    /// transaction, so this can run on large collections.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the transactions fail.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch_size` is zero.
+    pub async fn purge_expired<Err>(&self, db: &Db, batch_size: usize) -> Result<usize, Err>
+    where
+        Err: From<StorageError> + From<CollectionError> + Error,
+    {
+        self.tombstones
+            .clear_expired(&self.collection, db, batch_size)
+            .await
+    }
+
+    /// Re-encrypt the entities of the wrapped collection, including the
+    /// soft-deleted ones, see [`Collection::rotate_keys`].
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::rotate_keys`] apply here.
+    ///
+    /// # Panics
+    ///
+    /// The same panics of [`Collection::rotate_keys`] apply here.
+    pub async fn rotate_keys<Err>(&self, db: &Db, batch_size: usize) -> Result<usize, Err>
+    where
+        Err: From<StorageError> + From<CollectionError> + Error,
+    {
+        self.collection.rotate_keys(db, batch_size).await
+    }
+}
+
+/// Decode a tombstone, stored as its delete and expiration times, in
+/// milliseconds since the Unix epoch.
+fn decode_tombstone(value: &[u8]) -> DbResult<Tombstone, CollectionError> {
+    let Ok(bytes) = <[u8; 16]>::try_from(value) else {
+        return Err(DbError::Abort(CollectionError::InvalidData(
+            io::Error::new(io::ErrorKind::InvalidData, "invalid tombstone in database!"),
+        )));
+    };
+
+    let read_time =
+        |b: &[u8]| UNIX_EPOCH + Duration::from_millis(u64::from_le_bytes(b.try_into().unwrap()));
+
+    Ok(Tombstone {
+        delete_time: read_time(&bytes[..8]),
+        expire_time: read_time(&bytes[8..]),
+    })
+}
+
+/// Errors returned by [`SoftDeleteCollection::set`].
+#[derive(Debug)]
+pub enum SoftDeleteError {
+    /// The entity is soft-deleted, it must be restored or purged before being
+    /// written again.
+    Deleted {
+        /// The name of the collection.
+        collection: &'static str,
+    },
+    /// The wrapped collection failed.
+    Collection(CollectionError),
+}
+
+impl fmt::Display for SoftDeleteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Deleted { collection } => write!(f, "entity of {collection} is soft-deleted"),
+            Self::Collection(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for SoftDeleteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Deleted { .. } => None,
+            Self::Collection(err) => err.source(),
+        }
+    }
+}
+
+impl From<CollectionError> for SoftDeleteError {
+    fn from(err: CollectionError) -> Self {
+        Self::Collection(err)
+    }
+}
+
+impl From<SoftDeleteError> for Status {
+    fn from(err: SoftDeleteError) -> Self {
+        match err {
+            SoftDeleteError::Deleted { .. } => Status::already_exists(err),
+            SoftDeleteError::Collection(err) => err.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use pl_database::{run, try_run};
+    use pl_database_layers_changefeed::ChangeFeed;
+    use tonic::Code;
+
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Repository {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(string, tag = "2")]
+        url: String,
+    }
+
+    fn repositories(retention: Duration) -> SoftDeleteCollection<Repository> {
+        let collection = Collection::from_static("repos")
+            .with_unique_index("url", |repo: &Repository| Some(repo.url.clone()));
+
+        SoftDeleteCollection::new(collection, retention)
+    }
+
+    fn repository(name: &str, url: &str) -> Repository {
+        Repository {
+            name: name.to_string(),
+            url: url.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete() {
+        let db = Db::temporary();
+        let retention = Duration::from_secs(3600);
+        let repos = &repositories(retention);
+        let docs = &repository("docs", "example.com/docs");
+        let www = &repository("www", "example.com/www");
+
+        run!(db, |mut tx| repos.set(&mut tx, &"docs", docs));
+        run!(db, |mut tx| repos.set(&mut tx, &"www", www));
+        run!(db, |mut tx| repos.clear(&mut tx, &"docs"));
+
+        let found = run!(db, |tx| repos.get(&tx, &"docs"));
+        assert_eq!(found, None, "soft-deleted entity is visible");
+
+        let (deleted, tombstone) = run!(db, |tx| repos.get_deleted(&tx, &"docs")).unwrap();
+        assert_eq!(&deleted, docs);
+        assert_eq!(tombstone.delete_time + retention, tombstone.expire_time);
+
+        let subspace = repos.subspace();
+        let all = run!(db, |tx| repos.range(&tx, RangeOption::from(subspace)));
+        assert_eq!(all, vec![www.clone()]);
+        let all = run!(db, |tx| repos
+            .range_with_deleted(&tx, RangeOption::from(subspace)));
+        assert_eq!(
+            all,
+            vec![(docs.clone(), Some(tombstone)), (www.clone(), None)]
+        );
+
+        let err = try_run!(db, |mut tx| repos.set(&mut tx, &"docs", docs))
+            .expect_err("overwrote soft-deleted entity");
+        assert_eq!(err.code(), Code::AlreadyExists);
+        let err = try_run!(db, |mut tx| repos.set(
+            &mut tx,
+            &"blog",
+            &repository("blog", "example.com/docs")
+        ))
+        .expect_err("reused index value of soft-deleted entity");
+        assert_eq!(err.code(), Code::AlreadyExists);
+
+        let restored = run!(db, |mut tx| repos.undelete(&mut tx, &"docs"));
+        assert_eq!(restored.as_ref(), Some(docs));
+        let restored = run!(db, |mut tx| repos.undelete(&mut tx, &"docs"));
+        assert_eq!(restored, None, "undeleted entity twice");
+
+        let found = run!(db, |tx| repos.get(&tx, &"docs"));
+        assert_eq!(found.as_ref(), Some(docs));
+        run!(db, |mut tx| repos.set(&mut tx, &"docs", docs));
+    }
+
+    #[tokio::test]
+    async fn test_range() {
+        let db = Db::temporary();
+        let repos = &repositories(Duration::from_secs(3600));
+
+        for (idx, id) in ["a", "b", "c", "d", "e", "f"].into_iter().enumerate() {
+            run!(db, |mut tx| repos.set(&mut tx, &id, &repository(id, id)));
+            if idx % 2 == 0 {
+                run!(db, |mut tx| repos.clear(&mut tx, &id));
+            }
+        }
+
+        // Soft-deleted entities don't count against the limit.
+        let subspace = repos.subspace();
+        let opts = &RangeOption {
+            limit: Some(2),
+            ..RangeOption::from(subspace)
+        };
+        let page = run!(db, |tx| repos.range(&tx, opts.clone()));
+        assert_eq!(page, vec![repository("b", "b"), repository("d", "d")]);
+
+        let opts = &RangeOption {
+            limit: Some(2),
+            reverse: true,
+            ..RangeOption::from(subspace)
+        };
+        let page = run!(db, |tx| repos.range(&tx, opts.clone()));
+        assert_eq!(page, vec![repository("f", "f"), repository("d", "d")]);
+
+        let page = run!(db, |tx| repos.range_with_deleted(&tx, opts.clone()));
+        let names = page
+            .iter()
+            .map(|(repo, tombstone)| (repo.name.as_str(), tombstone.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![("f", false), ("e", true)]);
+    }
+
+    #[tokio::test]
+    async fn test_list_children() {
+        let db = Db::temporary();
+        let repos = &repositories(Duration::from_secs(3600));
+
+        for name in [
+            "users/john/repos/a",
+            "users/john/repos/b",
+            "users/john/repos/b/issues/1",
+            "users/john/repos/c",
+        ] {
+            let key = &ResourceName::__new(name.to_string());
+            run!(db, |mut tx| repos.set(
+                &mut tx,
+                key,
+                &repository(name, name)
+            ));
+        }
+        let b = &ResourceName::__new("users/john/repos/b".to_string());
+        run!(db, |mut tx| repos.clear(&mut tx, b));
+
+        // Soft-deleted children don't count against the limit.
+        let john = &ResourceName::__new("users/john".to_string());
+        let children = run!(db, |tx| repos.list_children(
+            &tx,
+            Some(john),
+            "repos",
+            None,
+            2
+        ));
+        let names = children
+            .iter()
+            .map(|repo| repo.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["users/john/repos/a", "users/john/repos/c"]);
+
+        let children = run!(db, |tx| repos.list_children(
+            &tx,
+            Some(john),
+            "repos",
+            Some("a"),
+            10
+        ));
+        assert_eq!(
+            children,
+            [repository("users/john/repos/c", "users/john/repos/c")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired() {
+        let db = Db::temporary();
+        let repos = &repositories(Duration::ZERO);
+
+        for id in ["a", "b", "c"] {
+            run!(db, |mut tx| repos.set(&mut tx, &id, &repository(id, id)));
+            run!(db, |mut tx| repos.clear(&mut tx, &id));
+        }
+
+        let purged = repos.purge_expired::<Status>(&db, 2).await.unwrap();
+        assert_eq!(purged, 3);
+
+        let deleted = run!(db, |tx| repos.get_deleted(&tx, &"a"));
+        assert_eq!(deleted, None, "entity wasn't purged");
+
+        // Keys and index values of purged entities can be reused.
+        run!(db, |mut tx| repos.set(&mut tx, &"a", &repository("a", "b")));
+
+        let purged = repos.purge_expired::<Status>(&db, 2).await.unwrap();
+        assert_eq!(purged, 0, "purged entities twice");
+    }
+
+    #[tokio::test]
+    async fn test_change_feed() {
+        let db = Db::temporary();
+        let feed = Arc::new(ChangeFeed::from_static("changes"));
+        let collection = Collection::from_static("repos").with_change_feed(feed.clone(), true);
+        let repos = &SoftDeleteCollection::new(collection, Duration::from_secs(3600));
+        let docs = &repository("docs", "example.com/docs");
+
+        run!(db, |mut tx| repos.set(&mut tx, &"docs", docs));
+        run!(db, |mut tx| repos.clear(&mut tx, &"docs"));
+        run!(db, |mut tx| repos.undelete(&mut tx, &"docs"));
+        run!(db, |mut tx| repos.clear(&mut tx, &"docs"));
+        run!(db, |mut tx| repos.purge(&mut tx, &"docs"));
+
+        let feed = &feed;
+        let changes = run!(db, |tx| feed.read(&tx, None, 10));
+        let encoded = Some(docs.encode_to_vec());
+        let records = changes
+            .into_iter()
+            .map(|change| {
+                let record = change.record;
+                (record.operation(), record.before, record.after)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            vec![
+                (ChangeOperation::Set, None, encoded.clone()),
+                (ChangeOperation::SoftDelete, encoded.clone(), None),
+                (ChangeOperation::Undelete, None, encoded.clone()),
+                (ChangeOperation::SoftDelete, encoded.clone(), None),
+                (ChangeOperation::Clear, encoded, None),
+            ]
+        );
+
+        let deleted = run!(db, |tx| repos.get_deleted(&tx, &"docs"));
+        assert_eq!(deleted, None, "purged entity is still deleted");
+    }
+}
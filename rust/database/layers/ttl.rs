@@ -0,0 +1,400 @@
+//! # Expiring entities
+//!
+//! Some entities are only useful for a limited time, e.g. page tokens, upload
+//! sessions or idempotency records. This layer wraps a [`Collection`], storing
+//! an expiration time with each entity. Expired entities are hidden from reads
+//! as soon as they expire, and deleted later, in batches, by a sweeper that
+//! runs in the background.
+//!
+//! Expiration times are stored in an [`ExpiryIndex`], in the layer subspace of
+//! the collection: by entity key, to filter reads, and by time, so that the
+//! sweeper finds expired entities without scanning the whole collection.
+//! Always write the entities through [`TtlCollection`], or both get out of sync.
+//!
+//! Expiration uses the clock of the processes, keep them synchronized.
+use std::{
+    convert::Infallible,
+    error::Error,
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use foundationdb::{tuple::TuplePack, RangeOption};
+use pl_database::{unix_millis, Db, DbError, DbResult, StorageError, Tx};
+use pl_database_layers_collection::{Collection, CollectionError, ExpiryIndex, PackedKey};
+use prost::Message;
+
+/// A collection whose entities expire.
+pub struct TtlCollection<E> {
+    collection: Collection<E>,
+    /// Expiration times of the entities that have one.
+    expiries: ExpiryIndex,
+}
+
+impl<E> TtlCollection<E> {
+    /// Wrap a collection, making its entities expire.
+    pub fn new(collection: Collection<E>) -> Self {
+        Self {
+            expiries: ExpiryIndex::new(&collection.layer_subspace("ttl")),
+            collection,
+        }
+    }
+
+    /// The wrapped collection.
+    ///
+    /// Entities read through it ignore expiration times, use it only for
+    /// operations that need the raw entities, e.g. [`Collection::watch`].
+    pub fn collection(&self) -> &Collection<E> {
+        &self.collection
+    }
+
+    /// Get the expiration time of an entity, if it has one.
+    async fn expiry(&self, tx: &Tx, packed: &[u8]) -> DbResult<Option<u64>, CollectionError> {
+        match self.expiries.get(tx, packed).await? {
+            Some(value) => decode_expiry(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the expiration time of an entity.
+    async fn set_expiry(
+        &self,
+        tx: &mut Tx,
+        packed: &[u8],
+        expiry: u64,
+    ) -> DbResult<(), CollectionError> {
+        self.clear_expiry(tx, packed).await?;
+        self.expiries.set(tx, packed, expiry, &expiry.to_le_bytes());
+
+        Ok(())
+    }
+
+    /// Remove the expiration time of an entity, if it has one.
+    async fn clear_expiry(&self, tx: &mut Tx, packed: &[u8]) -> DbResult<(), CollectionError> {
+        if let Some(previous) = self.expiry(tx, packed).await? {
+            self.expiries.clear(tx, packed, previous);
+        }
+
+        Ok(())
+    }
+}
+
+impl<E> TtlCollection<E>
+where
+    E: Message + Default,
+{
+    /// Get an entity, returning `None` if it isn't present or already expired.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::get`] apply here.
+    pub async fn get(&self, tx: &Tx, key: &impl TuplePack) -> DbResult<Option<E>, CollectionError> {
+        let packed = key.pack_to_vec();
+
+        if self.expiry(tx, &packed).await?.is_some_and(is_expired) {
+            return Ok(None);
+        }
+
+        self.collection.get(tx, &PackedKey(&packed)).await
+    }
+
+    /// Get the expiration time of an entity, returning `None` if it isn't
+    /// present, already expired or never expires.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database operation fails or if the stored
+    /// expiration time is corrupted.
+    pub async fn expire_time(
+        &self,
+        tx: &Tx,
+        key: &impl TuplePack,
+    ) -> DbResult<Option<SystemTime>, CollectionError> {
+        let expiry = self.expiry(tx, &key.pack_to_vec()).await?;
+
+        Ok(expiry
+            .filter(|&expiry| !is_expired(expiry))
+            .map(|expiry| UNIX_EPOCH + Duration::from_millis(expiry)))
+    }
+
+    /// Get a range of entities, skipping expired ones.
+    ///
+    /// Keys are read from [`Collection::subspace`] of the wrapped collection, as
+    /// in [`Collection::range`]. Expired entities don't count against the limit
+    /// of `opts`, the range continues until enough live entities are found.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::range`] apply here.
+    pub async fn range(&self, tx: &Tx, opts: RangeOption<'_>) -> DbResult<Vec<E>, CollectionError> {
+        self.expiries
+            .range(&self.collection, tx, opts, |_, entity, expiry| {
+                let expired = match expiry {
+                    Some(value) => is_expired(decode_expiry(value)?),
+                    None => false,
+                };
+
+                Ok((!expired).then_some(entity))
+            })
+            .await
+    }
+
+    /// Set the value of an entity, expiring it at `expire_time`.
+    ///
+    /// This replaces both the value and the expiration time of an existing
+    /// entity, even if it already expired.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::set`] apply here.
+    pub async fn set(
+        &self,
+        tx: &mut Tx,
+        key: &impl TuplePack,
+        value: &E,
+        expire_time: SystemTime,
+    ) -> DbResult<(), CollectionError> {
+        let packed = key.pack_to_vec();
+
+        self.collection.set(tx, &PackedKey(&packed), value).await?;
+        self.set_expiry(tx, &packed, unix_millis(expire_time)).await
+    }
+
+    /// Change when an entity expires, returning `false` if it isn't present or
+    /// already expired.
+    ///
+    /// Use this to extend the life of an entity without rewriting it, e.g. to
+    /// renew a lease.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Self::get`] apply here.
+    pub async fn set_expire_time(
+        &self,
+        tx: &mut Tx,
+        key: &impl TuplePack,
+        expire_time: SystemTime,
+    ) -> DbResult<bool, CollectionError> {
+        if self.get(tx, key).await?.is_none() {
+            return Ok(false);
+        }
+
+        self.set_expiry(tx, &key.pack_to_vec(), unix_millis(expire_time))
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Clear an entity and its expiration time.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Collection::clear`] apply here.
+    pub async fn clear(&self, tx: &mut Tx, key: &impl TuplePack) -> DbResult<(), CollectionError> {
+        let packed = key.pack_to_vec();
+
+        self.collection.clear(tx, &PackedKey(&packed)).await?;
+        self.clear_expiry(tx, &packed).await
+    }
+
+    /// Delete the expired entities, returning how many were deleted.
+    ///
+    /// Entities are deleted in batches of `batch_size`, each in its own
+    /// transaction, so this can run on large collections.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the transactions fail.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch_size` is zero.
+    pub async fn sweep<Err>(&self, db: &Db, batch_size: usize) -> Result<usize, Err>
+    where
+        Err: From<StorageError> + From<CollectionError> + Error,
+    {
+        self.expiries
+            .clear_expired(&self.collection, db, batch_size)
+            .await
+    }
+
+    /// Run [`Self::sweep`] every `interval`, only returning on errors.
+    ///
+    /// # Errors
+    ///
+    /// The same errors of [`Self::sweep`] apply here.
+    ///
+    /// # Panics
+    ///
+    /// The same panics of [`Self::sweep`] apply here.
+    pub async fn run_sweeper<Err>(
+        &self,
+        db: &Db,
+        batch_size: usize,
+        interval: Duration,
+    ) -> Result<Infallible, Err>
+    where
+        Err: From<StorageError> + From<CollectionError> + Error,
+    {
+        loop {
+            self.sweep::<Err>(db, batch_size).await?;
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+/// Decode an expiration time, in milliseconds since the Unix epoch.
+fn decode_expiry(value: &[u8]) -> DbResult<u64, CollectionError> {
+    let Ok(bytes) = value.try_into() else {
+        return Err(DbError::Abort(CollectionError::InvalidData(
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid expiration time in database!",
+            ),
+        )));
+    };
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn is_expired(expiry: u64) -> bool {
+    expiry <= unix_millis(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use pl_api_status::Status;
+    use pl_database::run;
+
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Session {
+        #[prost(string, tag = "1")]
+        upload_id: String,
+    }
+
+    fn session(upload_id: &str) -> Session {
+        Session {
+            upload_id: upload_id.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expiration() {
+        let db = Db::temporary();
+        let sessions = &TtlCollection::new(Collection::from_static("sessions"));
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(3600);
+
+        run!(db, |mut tx| sessions.set(
+            &mut tx,
+            &"a",
+            &session("a"),
+            later
+        ));
+        run!(db, |mut tx| sessions.set(&mut tx, &"b", &session("b"), now));
+
+        let a = run!(db, |tx| sessions.get(&tx, &"a"));
+        assert_eq!(a, Some(session("a")));
+        let b = run!(db, |tx| sessions.get(&tx, &"b"));
+        assert_eq!(b, None, "expired entity is visible");
+
+        let expire_time = run!(db, |tx| sessions.expire_time(&tx, &"a")).unwrap();
+        let expected = later.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        assert_eq!(
+            expire_time.duration_since(UNIX_EPOCH).unwrap().as_millis(),
+            expected
+        );
+
+        let renewed = run!(db, |mut tx| sessions.set_expire_time(&mut tx, &"b", later));
+        assert!(!renewed, "renewed expired entity");
+
+        // Setting an expired entity again brings it back.
+        run!(db, |mut tx| sessions.set(
+            &mut tx,
+            &"b",
+            &session("b2"),
+            later
+        ));
+        let b = run!(db, |tx| sessions.get(&tx, &"b"));
+        assert_eq!(b, Some(session("b2")));
+
+        let renewed = run!(db, |mut tx| sessions.set_expire_time(&mut tx, &"a", now));
+        assert!(renewed);
+        let a = run!(db, |tx| sessions.get(&tx, &"a"));
+        assert_eq!(a, None, "entity didn't expire after renewal");
+
+        run!(db, |mut tx| sessions.clear(&mut tx, &"b"));
+        let b = run!(db, |tx| sessions.expire_time(&tx, &"b"));
+        assert_eq!(b, None);
+    }
+
+    #[tokio::test]
+    async fn test_range() {
+        let db = Db::temporary();
+        let sessions = &TtlCollection::new(Collection::from_static("sessions"));
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(3600);
+
+        for (idx, id) in ["a", "b", "c", "d", "e", "f"].into_iter().enumerate() {
+            let expire_time = if idx % 2 == 0 { now } else { later };
+            run!(db, |mut tx| sessions.set(
+                &mut tx,
+                &id,
+                &session(id),
+                expire_time
+            ));
+        }
+
+        let subspace = sessions.collection().subspace();
+        let all = run!(db, |tx| sessions.range(&tx, RangeOption::from(subspace)));
+        assert_eq!(all, vec![session("b"), session("d"), session("f")]);
+
+        // Expired entities don't count against the limit.
+        let opts = &RangeOption {
+            limit: Some(2),
+            ..RangeOption::from(subspace)
+        };
+        let page = run!(db, |tx| sessions.range(&tx, opts.clone()));
+        assert_eq!(page, vec![session("b"), session("d")]);
+
+        let opts = &RangeOption {
+            limit: Some(2),
+            reverse: true,
+            ..RangeOption::from(subspace)
+        };
+        let page = run!(db, |tx| sessions.range(&tx, opts.clone()));
+        assert_eq!(page, vec![session("f"), session("d")]);
+    }
+
+    #[tokio::test]
+    async fn test_sweep() {
+        let db = Db::temporary();
+        let sessions = &TtlCollection::new(Collection::from_static("sessions"));
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(3600);
+
+        for id in 0..7u32 {
+            let expire_time = if id < 5 { now } else { later };
+            run!(db, |mut tx| sessions.set(
+                &mut tx,
+                &id,
+                &session("x"),
+                expire_time
+            ));
+        }
+
+        let swept = sessions.sweep::<Status>(&db, 2).await.unwrap();
+        assert_eq!(swept, 5);
+
+        let subspace = sessions.collection().subspace();
+        let remaining = run!(db, |tx| sessions
+            .collection()
+            .range(&tx, RangeOption::from(subspace)));
+        assert_eq!(remaining.len(), 2, "expired entities weren't deleted");
+
+        let swept = sessions.sweep::<Status>(&db, 2).await.unwrap();
+        assert_eq!(swept, 0);
+    }
+}
@@ -1,13 +1,19 @@
-use std::{future::Future, ops::Deref, path::Path};
-
-use foundationdb::{future::FdbSlice, RangeOption};
+use std::{
+    future::Future,
+    ops::Deref,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use foundationdb::{future::FdbSlice, tuple::Versionstamp, RangeOption};
 use futures_util::TryStreamExt;
-use pl_database_error::StorageError;
 use pl_database_storages_foundationdb::{FdbDatabase, FdbTransaction};
 use pl_database_storages_sled::{SledDatabase, SledTransaction};
 use sled::IVec;
 
-pub use pl_database_error::{DbError, DbResult, InfallibleDbResult};
+pub use pl_database_error::{DbError, DbResult, InfallibleDbResult, StorageError};
 
 /// An abstract key-value database.
 ///
@@ -74,24 +80,38 @@ impl Db {
             DbInner::Embedded(sled_db) => {
                 sled_db
                     .transaction(move |tx| {
-                        let fut = f(Tx(TxInner::Embedded(tx)));
+                        let fut = f(Tx::new(TxInner::Embedded(Box::new(tx))));
 
                         async move {
-                            let (val, Tx(TxInner::Embedded(tx))) = fut.await? else {
+                            let (
+                                val,
+                                Tx {
+                                    inner: TxInner::Embedded(tx),
+                                    ..
+                                },
+                            ) = fut.await?
+                            else {
                                 unreachable!("invalid transaction type in sled database");
                             };
 
-                            Ok((val, tx))
+                            Ok((val, *tx))
                         }
                     })
                     .await
             }
             DbInner::Fdb(fdb) => {
                 fdb.transact(move |tx| {
-                    let fut = f(Tx(TxInner::Fdb(tx)));
+                    let fut = f(Tx::new(TxInner::Fdb(tx)));
 
                     async move {
-                        let (val, Tx(TxInner::Fdb(tx))) = fut.await? else {
+                        let (
+                            val,
+                            Tx {
+                                inner: TxInner::Fdb(tx),
+                                ..
+                            },
+                        ) = fut.await?
+                        else {
                             unreachable!("invalid transaction type in fdb database");
                         };
 
@@ -110,17 +130,29 @@ impl Db {
 /// developers should just pass it to the layer they're using. The API of
 /// this type is implemented doing the least amount of of work possible,
 /// causing it to be highly complicated.
-pub struct Tx(TxInner);
+pub struct Tx {
+    inner: TxInner,
+    /// User version of the next incomplete versionstamp, `None` after all of
+    /// them were used.
+    user_version: Option<u16>,
+}
 
 enum TxInner {
-    Embedded(SledTransaction),
+    Embedded(Box<SledTransaction>),
     Fdb(FdbTransaction),
 }
 
 impl Tx {
+    fn new(inner: TxInner) -> Self {
+        Self {
+            inner,
+            user_version: Some(0),
+        }
+    }
+
     /// Get a value of a key from the database and pass it to the given closure.
     pub async fn get(&self, key: &[u8]) -> InfallibleDbResult<Option<IBytes>> {
-        let bytes = match &self.0 {
+        let bytes = match &self.inner {
             TxInner::Embedded(sled_tx) => sled_tx.get(key)?.map(IBytes::embedded),
             TxInner::Fdb(fdb_tx) => fdb_tx.get(key).await?.map(IBytes::foundation),
         };
@@ -134,7 +166,7 @@ impl Tx {
         Fut: Future<Output = DbResult<bool, E>>,
         E: std::error::Error,
     {
-        match &self.0 {
+        match &self.inner {
             TxInner::Embedded(sled_tx) => Self::sled_for_each_in_range(sled_tx, &opts, f).await,
             TxInner::Fdb(fdb_tx) => Self::fdb_for_each_in_range(fdb_tx, opts, f).await,
         }
@@ -240,7 +272,7 @@ impl Tx {
     /// If the key wasn't present in the database, it will be added. If it was, its
     /// value will be replaced.
     pub fn set(&mut self, key: &[u8], value: &[u8]) {
-        match &mut self.0 {
+        match &mut self.inner {
             TxInner::Embedded(sled_tx) => sled_tx.set(key, value),
             TxInner::Fdb(fdb_tx) => fdb_tx.set(key, value),
         }
@@ -250,11 +282,103 @@ impl Tx {
     ///
     /// If the key didn't exist, nothing will be done.
     pub fn clear(&mut self, key: &[u8]) {
-        match &mut self.0 {
+        match &mut self.inner {
             TxInner::Embedded(sled_tx) => sled_tx.clear(key),
             TxInner::Fdb(fdb_tx) => fdb_tx.clear(key),
         }
     }
+
+    /// Clear all keys in the range `[begin, end)` from the database.
+    pub fn clear_range(&mut self, begin: &[u8], end: &[u8]) -> InfallibleDbResult<()> {
+        match &mut self.inner {
+            TxInner::Embedded(sled_tx) => sled_tx.clear_range(begin, end)?,
+            TxInner::Fdb(fdb_tx) => fdb_tx.clear_range(begin, end),
+        }
+
+        Ok(())
+    }
+
+    /// Atomically add `delta` to the little-endian integer stored in a key.
+    ///
+    /// Missing keys are treated as zero, and the result is stored as a 64-bit
+    /// integer. Concurrent additions to the same key don't conflict, which makes
+    /// this suitable for counters.
+    pub fn atomic_add(&mut self, key: &[u8], delta: i64) {
+        match &mut self.inner {
+            TxInner::Embedded(sled_tx) => sled_tx.atomic_add(key, delta),
+            TxInner::Fdb(fdb_tx) => fdb_tx.atomic_add(key, delta),
+        }
+    }
+
+    /// Get a new incomplete versionstamp.
+    ///
+    /// Each call returns a versionstamp with a greater user version, so that
+    /// multiple keys written by the same transaction are unique and ordered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called more than 65536 times in the same transaction.
+    pub fn next_versionstamp(&mut self) -> Versionstamp {
+        let user_version = self
+            .user_version
+            .expect("too many versionstamps in a single transaction");
+        self.user_version = user_version.checked_add(1);
+
+        Versionstamp::incomplete(user_version)
+    }
+
+    /// Set the value of a key containing an incomplete versionstamp.
+    ///
+    /// The key must be built with `Subspace::pack_with_versionstamp`, using a
+    /// versionstamp from [`Self::next_versionstamp`]. On commit, the versionstamp
+    /// is replaced by one greater than the ones of all previously committed
+    /// transactions. Reads in this transaction don't see the key.
+    pub fn set_versionstamped_key(&mut self, key: &[u8], value: &[u8]) {
+        match &mut self.inner {
+            TxInner::Embedded(sled_tx) => sled_tx.set_versionstamped_key(key, value),
+            TxInner::Fdb(fdb_tx) => fdb_tx.set_versionstamped_key(key, value),
+        }
+    }
+
+    /// Set a value containing an incomplete versionstamp.
+    ///
+    /// The value must end with the little-endian 32-bit offset of the versionstamp
+    /// inside it, which must come from [`Self::next_versionstamp`]. On commit, the
+    /// versionstamp is completed as in [`Self::set_versionstamped_key`]. Reading
+    /// the key in this transaction fails, as its value isn't known yet.
+    pub fn set_versionstamped_value(&mut self, key: &[u8], value: &[u8]) {
+        match &mut self.inner {
+            TxInner::Embedded(sled_tx) => sled_tx.set_versionstamped_value(key, value),
+            TxInner::Fdb(fdb_tx) => fdb_tx.set_versionstamped_value(key, value),
+        }
+    }
+
+    /// Watch a key for changes.
+    ///
+    /// The returned future resolves once the value of the key changes after the
+    /// transaction commits, so it must be returned from the transaction and
+    /// awaited outside of it. Watches may resolve spuriously, re-read the key
+    /// to check what changed.
+    pub fn watch(&self, key: &[u8]) -> Watch {
+        match &self.inner {
+            TxInner::Embedded(sled_tx) => Watch(Box::pin(sled_tx.watch(key))),
+            TxInner::Fdb(fdb_tx) => Watch(Box::pin(fdb_tx.watch(key))),
+        }
+    }
+}
+
+/// A future resolved when a watched key changes.
+///
+/// See [`Tx::watch`] for more info.
+#[must_use = "watches do nothing unless awaited"]
+pub struct Watch(Pin<Box<dyn Future<Output = InfallibleDbResult<()>> + Send>>);
+
+impl Future for Watch {
+    type Output = InfallibleDbResult<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
 }
 
 /// A buffer from the database.
@@ -286,6 +410,52 @@ enum IBytesBuf {
     Fdb(FdbSlice),
 }
 
+/// Get the milliseconds since the Unix epoch of `time`, as layers store
+/// deadlines and expiration times.
+///
+/// Times before the epoch are converted to 0.
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, duration_millis)
+}
+
+/// Get the milliseconds of `duration`, saturating if they don't fit a `u64`.
+pub fn duration_millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Run a single operation in a transaction, returning its result.
+///
+/// The operation is an expression using the transaction, which returns a
+/// future of a [`DbResult`]. Its abort errors are converted into a `Status`,
+/// which must be in scope. The layers use this in their tests.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! try_run {
+    ($db:expr, |$tx:ident| $op:expr) => {
+        $db.transaction(|$tx| async move {
+            let res = $op.await.map_err(|err| err.map_abort(Status::from))?;
+            Ok::<_, $crate::DbError<Status>>((res, $tx))
+        })
+        .await
+    };
+    ($db:expr, |mut $tx:ident| $op:expr) => {
+        $db.transaction(|mut $tx| async move {
+            let res = $op.await.map_err(|err| err.map_abort(Status::from))?;
+            Ok::<_, $crate::DbError<Status>>((res, $tx))
+        })
+        .await
+    };
+}
+
+/// Like [`try_run!`], but panics if the transaction fails.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! run {
+    ($($args:tt)*) => {
+        $crate::try_run!($($args)*).unwrap()
+    };
+}
+
 #[cfg(test)]
 mod tests {
     use pl_api_status::{Status, StatusOr};
@@ -317,4 +487,27 @@ mod tests {
         })
         .await
     }
+
+    #[tokio::test]
+    async fn test_next_versionstamp() -> StatusOr<()> {
+        let db = Db::temporary();
+
+        db.transaction(|mut tx| {
+            Box::pin(async move {
+                let mut last = None;
+                for _ in 0..=u16::MAX {
+                    last = Some(tx.next_versionstamp());
+                }
+                assert_eq!(last.map(|stamp| stamp.user_version()), Some(u16::MAX));
+
+                let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    tx.next_versionstamp()
+                }));
+                assert!(res.is_err(), "got more than 65536 versionstamps");
+
+                Ok(((), tx)) as DbResult<_, Status>
+            })
+        })
+        .await
+    }
 }
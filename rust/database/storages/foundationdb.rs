@@ -9,9 +9,10 @@ use std::future::Future;
 
 use foundationdb::{
     future::{FdbSlice, FdbValues},
+    options::MutationType,
     FdbError, RangeOption, Transaction,
 };
-use futures_util::{Stream, TryStreamExt};
+use futures_util::{Stream, TryFutureExt, TryStreamExt};
 use pl_database_error::{DbError, DbResult, InfallibleDbResult, StorageError};
 
 /// A database interface into a [FoundationDB] cluster.
@@ -195,6 +196,49 @@ impl FdbTransaction {
     pub fn clear(&mut self, key: &[u8]) {
         self.0.clear(key)
     }
+
+    /// Remove all keys in the range `[begin, end)` from the database.
+    pub fn clear_range(&mut self, begin: &[u8], end: &[u8]) {
+        self.0.clear_range(begin, end)
+    }
+
+    /// Atomically add `delta` to the little-endian integer stored in a key.
+    ///
+    /// Missing keys are treated as zero. This doesn't add a read conflict on
+    /// the key, meaning that concurrent additions don't conflict.
+    pub fn atomic_add(&mut self, key: &[u8], delta: i64) {
+        self.0
+            .atomic_op(key, &delta.to_le_bytes(), MutationType::Add)
+    }
+
+    /// Set the value of a key containing an incomplete versionstamp.
+    ///
+    /// The key must end with the little-endian 32-bit offset of the versionstamp,
+    /// as returned by `Subspace::pack_with_versionstamp`.
+    pub fn set_versionstamped_key(&mut self, key: &[u8], value: &[u8]) {
+        self.0
+            .atomic_op(key, value, MutationType::SetVersionstampedKey)
+    }
+
+    /// Set a value containing an incomplete versionstamp.
+    ///
+    /// The value must end with the little-endian 32-bit offset of the
+    /// versionstamp inside it. Reading the key in the same transaction fails.
+    pub fn set_versionstamped_value(&mut self, key: &[u8], value: &[u8]) {
+        self.0
+            .atomic_op(key, value, MutationType::SetVersionstampedValue)
+    }
+
+    /// Watch a key for changes.
+    ///
+    /// The returned future resolves after the transaction commits, once the
+    /// value of the key differs from the one seen by the transaction.
+    pub fn watch(
+        &self,
+        key: &[u8],
+    ) -> impl Future<Output = InfallibleDbResult<()>> + Send + 'static {
+        self.0.watch(key).map_err(fdb_error_to_db_error)
+    }
 }
 
 fn fdb_error_to_db_error<E>(err: FdbError) -> DbError<E> {
@@ -0,0 +1,122 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::Bound,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use sled::IVec;
+
+/// A range of keys, as given to [`sled::Tree::range`].
+pub(crate) type KeyRange = (Bound<IVec>, Bound<IVec>);
+
+/// Detects conflicts between the transactions of a database.
+///
+/// Like in FoundationDB, transactions record the keys they read, and conflict
+/// if another transaction wrote to any of them after they started. Commits are
+/// serialized, so a transaction that doesn't conflict can't be affected by
+/// another one committing at the same time.
+#[derive(Default)]
+pub(crate) struct Conflicts(Mutex<CommitLog>);
+
+#[derive(Default)]
+pub(crate) struct CommitLog {
+    /// Version of the last commit.
+    version: u64,
+    /// Read versions of running transactions, with how many use each.
+    running: BTreeMap<u64, usize>,
+    /// Keys written by each commit, while a running transaction may need them.
+    commits: VecDeque<(u64, Vec<IVec>)>,
+}
+
+/// The version of the database seen by a running transaction.
+///
+/// Commits after this version are kept until it is dropped, as they may
+/// conflict with the transaction.
+pub(crate) struct ReadVersion {
+    conflicts: Arc<Conflicts>,
+    version: u64,
+}
+
+impl Conflicts {
+    /// Start a transaction at the last committed version.
+    pub(crate) fn begin(self: &Arc<Self>) -> ReadVersion {
+        let mut log = self.lock();
+        let version = log.version;
+        *log.running.entry(version).or_default() += 1;
+
+        ReadVersion {
+            conflicts: self.clone(),
+            version,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CommitLog> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl CommitLog {
+    /// Check if any commit after `read_version` wrote to the `reads` ranges.
+    pub(crate) fn conflicts(&self, read_version: &ReadVersion, reads: &[KeyRange]) -> bool {
+        self.commits
+            .iter()
+            .skip_while(|(version, _)| *version <= read_version.version)
+            .flat_map(|(_, writes)| writes)
+            .any(|key| reads.iter().any(|range| contains(range, key)))
+    }
+
+    /// Record the keys written by a commit.
+    pub(crate) fn commit(&mut self, writes: Vec<IVec>) {
+        self.version += 1;
+        if !writes.is_empty() {
+            self.commits.push_back((self.version, writes));
+        }
+    }
+
+    fn finish(&mut self, read_version: u64) {
+        if let Some(count) = self.running.get_mut(&read_version) {
+            *count -= 1;
+            if *count == 0 {
+                self.running.remove(&read_version);
+            }
+        }
+
+        // Only transactions that started before a commit can conflict with it.
+        let oldest = self.running.keys().next().copied().unwrap_or(self.version);
+        while self
+            .commits
+            .front()
+            .is_some_and(|(version, _)| *version <= oldest)
+        {
+            self.commits.pop_front();
+        }
+    }
+}
+
+impl ReadVersion {
+    /// Lock the log of the database to commit the transaction.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, CommitLog> {
+        self.conflicts.lock()
+    }
+}
+
+impl Drop for ReadVersion {
+    fn drop(&mut self) {
+        self.conflicts.lock().finish(self.version);
+    }
+}
+
+fn contains((begin, end): &KeyRange, key: &IVec) -> bool {
+    let after_begin = match begin {
+        Bound::Included(begin) => key >= begin,
+        Bound::Excluded(begin) => key > begin,
+        Bound::Unbounded => true,
+    };
+    let before_end = match end {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    };
+
+    after_begin && before_end
+}
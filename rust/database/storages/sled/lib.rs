@@ -12,11 +12,14 @@
 //!
 //! Although not recommended, the backend also makes spinning-up a small
 //! deployment of PL, as there is no need to fiddle with FoundationDB clusters.
-use std::{future::Future, path::Path};
+use std::{future::Future, path::Path, sync::Arc};
 
 use pl_database_error::{DbError, InfallibleDbResult, StorageError};
 use sled::{Config, Db};
 
+use self::conflicts::Conflicts;
+
+mod conflicts;
 mod transaction;
 
 #[doc(inline)]
@@ -31,6 +34,7 @@ pub use self::transaction::{SledRange, SledTransaction};
 /// [`sled`]: https://docs.rs/sled
 pub struct SledDatabase {
     db: Db,
+    conflicts: Arc<Conflicts>,
 }
 
 /// Openning methods.
@@ -46,7 +50,10 @@ impl SledDatabase {
             panic!("failed to open sled database at {}:\n{err}", path.display())
         });
 
-        Self { db }
+        Self {
+            db,
+            conflicts: Arc::default(),
+        }
     }
 
     /// Creates a temporary isolated database.
@@ -66,38 +73,45 @@ impl SledDatabase {
             .open()
             .unwrap_or_else(|err| panic!("failed to open temp sled database:\n{err}"));
 
-        Self { db }
+        Self {
+            db,
+            conflicts: Arc::default(),
+        }
     }
 }
 
 impl SledDatabase {
     /// Executes a future inside a transaction.
     ///
-    /// The behavior of the transaction given as argument is the same
-    /// as a FoundatioDB's transaction where all reads are snapshot reads,
-    /// i.e. the transaction is not stricty serializable. This is a limitation
-    /// of Sled, as it is wasn't designed with this purpose in mind.
+    /// Like in FoundationDB, the transaction conflicts with the ones that wrote
+    /// to the keys it read after it started, in which case the closure is
+    /// called again. Reads see the latest committed values instead of a
+    /// snapshot, but a transaction that saw a newer value always conflicts, so
+    /// transactions are still serializable.
     pub async fn transaction<T, E, F, Fut>(&self, f: F) -> Result<T, E>
     where
         F: Fn(SledTransaction) -> Fut,
         Fut: Future<Output = Result<(T, SledTransaction), DbError<E>>>,
         E: From<StorageError> + std::error::Error,
     {
-        let tx = SledTransaction::new((*self.db).clone());
+        loop {
+            let tx = SledTransaction::new((*self.db).clone(), &self.conflicts);
 
-        // No sled error is transient, no need for retry.
-        let fut = async {
-            let (val, tx) = f(tx).await?;
+            // No sled error is transient, only conflicts are retried.
+            let fut = async {
+                let (val, tx) = f(tx).await?;
 
-            sled_res_to_db_res(tx.commit().await)?;
+                let committed = sled_res_to_db_res(tx.commit().await)?;
 
-            Ok(val)
-        };
+                Ok(committed.then_some(val))
+            };
 
-        match fut.await {
-            Ok(val) => Ok(val),
-            Err(DbError::Abort(err)) => Err(err),
-            Err(DbError::Storage(err)) => Err(E::from(err)),
+            match fut.await {
+                Ok(Some(val)) => return Ok(val),
+                Ok(None) => continue,
+                Err(DbError::Abort(err)) => return Err(err),
+                Err(DbError::Storage(err)) => return Err(E::from(err)),
+            }
         }
     }
 }
@@ -1,9 +1,16 @@
-use std::{collections::HashMap, ops::Bound};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Mutex},
+};
 
 use foundationdb::{KeySelector, RangeOption};
-use pl_database_error::InfallibleDbResult;
+use pl_database_error::{DbError, InfallibleDbResult};
 use sled::{Batch, IVec, Tree};
 
+use crate::conflicts::{Conflicts, KeyRange, ReadVersion};
+
 /// A transaction in a Sled tree.
 ///
 /// See [`SledDatabase::transaction`] for more info.
@@ -12,16 +19,49 @@ use sled::{Batch, IVec, Tree};
 pub struct SledTransaction {
     tree: Tree,
     batch: HashMap<IVec, Option<IVec>>,
+    /// Atomic additions to keys not in `batch`, applied on commit.
+    adds: HashMap<IVec, i64>,
+    /// Keys with an incomplete versionstamp, completed on commit.
+    versionstamped: Vec<(IVec, IVec)>,
+    /// Values with an incomplete versionstamp, by key, completed on commit.
+    versionstamped_values: HashMap<IVec, IVec>,
+    /// Ranges read by the transaction, checked for conflicts on commit.
+    reads: Mutex<Vec<KeyRange>>,
+    read_version: ReadVersion,
 }
 
-pub type SledRange<'t> = Box<dyn Iterator<Item = InfallibleDbResult<(IVec, IVec)>> + 't>;
+pub type SledRange<'t> = Box<dyn Iterator<Item = InfallibleDbResult<(IVec, IVec)>> + Send + 't>;
+
+/// Key of the last versionstamp assigned to a transaction.
+///
+/// Like in FoundationDB, keys starting with `0xFF` are reserved to the system.
+const VERSIONSTAMP_KEY: &[u8] = b"\xff/versionstamp";
 
 impl SledTransaction {
     /// Get a value of a key from the tree.
+    ///
+    /// Like in FoundationDB, reading a key set with
+    /// [`Self::set_versionstamped_value`] in this transaction fails, as its value
+    /// is only known on commit.
     pub fn get(&self, key: &[u8]) -> InfallibleDbResult<Option<IVec>> {
+        if self.versionstamped_values.contains_key(key) {
+            return Err(DbError::Storage(
+                format!("read of a versionstamped value in its transaction: {key:?}").into(),
+            ));
+        }
+
+        self.add_read(Bound::Included(key), Bound::Included(key));
+
         match self.batch.get(key) {
             Some(v) => Ok(v.clone()),
-            None => crate::sled_res_to_db_res(self.tree.get(key)),
+            None => {
+                let value = crate::sled_res_to_db_res(self.tree.get(key))?;
+
+                match self.adds.get(key) {
+                    Some(delta) => Ok(Some(add_le(value.as_deref(), *delta))),
+                    None => Ok(value),
+                }
+            }
         }
     }
 
@@ -35,8 +75,17 @@ impl SledTransaction {
             }
         }
 
-        let begin = bound_from_selector(&opts.begin);
+        // Begin selectors resolve to the first key of the range. FoundationDB
+        // encodes first_greater_or_equal as `(key, or_equal: false, offset: 1)`
+        // and first_greater_than as `(key, or_equal: true, offset: 1)`, so a
+        // positive offset flips the bound given by `or_equal`.
+        let begin = match bound_from_selector(&opts.begin) {
+            Bound::Included(k) if opts.begin.offset() > 0 => Bound::Excluded(k),
+            Bound::Excluded(k) if opts.begin.offset() > 0 => Bound::Included(k),
+            bound => bound,
+        };
         let end = bound_from_selector(&opts.end);
+        self.add_read(begin, end);
 
         let range = self
             .tree
@@ -57,36 +106,228 @@ impl SledTransaction {
         };
         let range = range.filter_map(read_writes);
 
-        let iter: SledRange = match (opts.limit, opts.reverse) {
-            (None, true) => Box::new(range.rev()),
-            (None, false) => Box::new(range),
-            (Some(n), true) => Box::new(range.rev().take(n)),
-            (Some(n), false) => Box::new(range.take(n)),
-        };
+        // Keys added in this transaction are not in the tree yet. If there are any
+        // in the range, we need to merge them with the tree ones.
+        let mut added = self
+            .batch
+            .iter()
+            .filter_map(|(k, v)| Some((k, v.as_ref()?)))
+            .filter(|(k, _)| (begin, end).contains(&&k[..]))
+            .peekable();
+        // The same applies to atomic additions, which may also create keys.
+        let mut atomic_adds = self
+            .adds
+            .iter()
+            .filter(|(k, _)| (begin, end).contains(&&k[..]))
+            .peekable();
+
+        if added.peek().is_some() || atomic_adds.peek().is_some() {
+            let mut merged = BTreeMap::new();
+
+            for res in range {
+                match res {
+                    Ok((k, v)) => {
+                        merged.insert(k, v);
+                    }
+                    Err(err) => return Box::new(std::iter::once(Err(err))),
+                }
+            }
+
+            merged.extend(added.map(|(k, v)| (k.clone(), v.clone())));
+
+            for (k, delta) in atomic_adds {
+                let value = add_le(merged.get(k).map(|v| &v[..]), *delta);
+                merged.insert(k.clone(), value);
+            }
 
-        iter
+            return apply_range_options(merged.into_iter().map(Ok), opts);
+        }
+
+        apply_range_options(range, opts)
     }
 
     /// Set the value associated with a given key.
     ///
     /// If the key was already present, its value will be overriden.
     pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.adds.remove(key);
+        self.versionstamped_values.remove(key);
         self.batch.insert(key.into(), Some(value.into()));
     }
 
     /// Remove a key from the database.
     pub fn clear(&mut self, key: &[u8]) {
+        self.adds.remove(key);
+        self.versionstamped_values.remove(key);
         self.batch.insert(key.into(), None);
     }
 
-    pub(crate) fn new(tree: Tree) -> Self {
+    /// Atomically add `delta` to the little-endian integer stored in a key.
+    ///
+    /// Like FoundationDB's `ADD` mutation, missing keys are treated as zero, and
+    /// the result is stored as a 64-bit integer. Additions to keys not written
+    /// in this transaction are applied on commit, without conflicting with other
+    /// transactions doing additions to the same key.
+    pub fn atomic_add(&mut self, key: &[u8], delta: i64) {
+        match self.batch.get_mut(key) {
+            Some(value) => *value = Some(add_le(value.as_deref(), delta)),
+            None => {
+                let pending = self.adds.entry(key.into()).or_default();
+                *pending = pending.wrapping_add(delta);
+            }
+        }
+    }
+
+    /// Set the value of a key containing an incomplete versionstamp.
+    ///
+    /// The key must end with the little-endian 32-bit offset of the versionstamp,
+    /// as in FoundationDB's `SET_VERSIONSTAMPED_KEY` mutation. Versionstamps are
+    /// assigned on commit from a counter stored in the database, and the key is
+    /// not visible to reads in this transaction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the offset doesn't point to 10 bytes inside the key.
+    pub fn set_versionstamped_key(&mut self, key: &[u8], value: &[u8]) {
+        assert!(
+            has_versionstamp_offset(key),
+            "invalid versionstamp offset in key: {key:?}"
+        );
+
+        self.versionstamped.push((key.into(), value.into()));
+    }
+
+    /// Set a value containing an incomplete versionstamp.
+    ///
+    /// The value must end with the little-endian 32-bit offset of the
+    /// versionstamp, as in FoundationDB's `SET_VERSIONSTAMPED_VALUE` mutation.
+    /// The versionstamp is assigned on commit, as in
+    /// [`Self::set_versionstamped_key`], and reading the key in this
+    /// transaction fails until it is written again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the offset doesn't point to 10 bytes inside the value.
+    pub fn set_versionstamped_value(&mut self, key: &[u8], value: &[u8]) {
+        assert!(
+            has_versionstamp_offset(value),
+            "invalid versionstamp offset in value: {value:?}"
+        );
+
+        self.adds.remove(key);
+        self.batch.remove(key);
+        self.versionstamped_values.insert(key.into(), value.into());
+    }
+
+    /// Watch a key for changes.
+    ///
+    /// The returned future resolves when the key is next written, which may
+    /// happen before this transaction commits. Unlike FoundationDB, writes to
+    /// the key by this transaction also resolve the future.
+    pub fn watch(
+        &self,
+        key: &[u8],
+    ) -> impl Future<Output = InfallibleDbResult<()>> + Send + 'static {
+        let mut subscriber = self.tree.watch_prefix(key);
+        let key = IVec::from(key);
+
+        async move {
+            // The subscriber sees all keys with the given prefix.
+            while let Some(event) = (&mut subscriber).await {
+                if event.key() == &key {
+                    break;
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Remove all keys in the range `[begin, end)` from the database.
+    pub fn clear_range(&mut self, begin: &[u8], end: &[u8]) -> InfallibleDbResult<()> {
+        for key in self.tree.range(begin..end).keys() {
+            let key = crate::sled_res_to_db_res(key)?;
+            self.batch.insert(key, None);
+        }
+
+        // Also remove keys that were only written in this transaction.
+        for (key, value) in self.batch.iter_mut() {
+            if begin <= &**key && &**key < end {
+                *value = None;
+            }
+        }
+
+        for key in self.adds.keys().chain(self.versionstamped_values.keys()) {
+            if begin <= &**key && &**key < end {
+                self.batch.insert(key.clone(), None);
+            }
+        }
+        self.adds
+            .retain(|key, _| !(begin <= &**key && &**key < end));
+        self.versionstamped_values
+            .retain(|key, _| !(begin <= &**key && &**key < end));
+
+        Ok(())
+    }
+
+    pub(crate) fn new(tree: Tree, conflicts: &Arc<Conflicts>) -> Self {
         Self {
             tree,
             batch: HashMap::default(),
+            adds: HashMap::default(),
+            versionstamped: Vec::default(),
+            versionstamped_values: HashMap::default(),
+            reads: Mutex::default(),
+            read_version: conflicts.begin(),
         }
     }
 
-    pub(crate) async fn commit(self) -> Result<(), sled::Error> {
+    /// Record that the transaction read a range, so that it conflicts with
+    /// writes to it.
+    fn add_read(&self, begin: Bound<&[u8]>, end: Bound<&[u8]>) {
+        let to_owned = |bound: Bound<&[u8]>| match bound {
+            Bound::Included(key) => Bound::Included(IVec::from(key)),
+            Bound::Excluded(key) => Bound::Excluded(IVec::from(key)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        self.reads
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push((to_owned(begin), to_owned(end)));
+    }
+
+    /// Commit the transaction, returning `false` without writing anything if
+    /// it conflicts with another transaction.
+    pub(crate) async fn commit(self) -> Result<bool, sled::Error> {
+        let tree = self.tree.clone();
+        if !self.apply()? {
+            return Ok(false);
+        }
+
+        // Now, match FoundationDB behavior and flush everyting to disk.
+        tree.flush_async().await?;
+
+        Ok(true)
+    }
+
+    /// Write the changes of the transaction to the tree, unless it conflicts.
+    fn apply(self) -> Result<bool, sled::Error> {
+        let reads = self
+            .reads
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner());
+        let mut writes: Vec<IVec> = self.batch.keys().chain(self.adds.keys()).cloned().collect();
+
+        // Commits are serialized, so that a transaction that doesn't conflict
+        // can't be affected by another one committing at the same time. This
+        // also ensures that a transaction is never visible before another one
+        // with a smaller versionstamp, which FoundationDB never allows.
+        let mut log = self.read_version.lock();
+        if log.conflicts(&self.read_version, &reads) {
+            return Ok(false);
+        }
+
         // First, apply all accumulated writes.
         let mut batch = Batch::default();
         for (k, v) in self.batch {
@@ -97,12 +338,89 @@ impl SledTransaction {
             }
         }
 
-        self.tree.apply_batch(batch)?;
+        if self.versionstamped.is_empty() && self.versionstamped_values.is_empty() {
+            self.tree.apply_batch(batch)?;
+        } else {
+            let version = self
+                .tree
+                .update_and_fetch(VERSIONSTAMP_KEY, |value| Some(add_le(value, 1)))?
+                .expect("versionstamp counter was not written");
+            let version = i64::from_le_bytes(version[..].try_into().unwrap()) as u64;
+
+            // Use the same layout of FoundationDB's versionstamps, the commit
+            // version followed by the batch order.
+            let mut stamp = [0; 10];
+            stamp[..8].copy_from_slice(&version.to_be_bytes());
+
+            for (key, value) in self.versionstamped {
+                let key = complete_versionstamp(&key, &stamp);
+                writes.push(key.clone());
+                batch.insert(key, value);
+            }
+            for (key, value) in self.versionstamped_values {
+                writes.push(key.clone());
+                batch.insert(key, complete_versionstamp(&value, &stamp));
+            }
 
-        // Now, match FoundationDB behavior and flush everyting to disk.
-        self.tree.flush_async().await?;
+            self.tree.apply_batch(batch)?;
+        }
 
-        Ok(())
+        // Apply additions key by key, so that concurrent ones aren't lost.
+        for (k, delta) in self.adds {
+            self.tree
+                .update_and_fetch(k, |value| Some(add_le(value, delta)))?;
+        }
+
+        log.commit(writes);
+
+        Ok(true)
+    }
+}
+
+/// Add `delta` to a little-endian integer, returning the result as a 64-bit one.
+///
+/// Follows the `ADD` mutation of FoundationDB: missing values are zero, and
+/// values larger than 8 bytes are truncated.
+fn add_le(value: Option<&[u8]>, delta: i64) -> IVec {
+    let mut bytes = [0; 8];
+    if let Some(value) = value {
+        let len = value.len().min(8);
+        bytes[..len].copy_from_slice(&value[..len]);
+    }
+
+    let sum = i64::from_le_bytes(bytes).wrapping_add(delta);
+    IVec::from(&sum.to_le_bytes())
+}
+
+/// Check that `bytes` end with the offset of 10 bytes of versionstamp inside them.
+fn has_versionstamp_offset(bytes: &[u8]) -> bool {
+    bytes.len() >= 4 && {
+        let (bytes, offset) = bytes.split_at(bytes.len() - 4);
+        let offset = u32::from_le_bytes(offset.try_into().unwrap()) as usize;
+        offset.checked_add(10).is_some_and(|end| end <= bytes.len())
+    }
+}
+
+/// Replace the incomplete versionstamp of a key or value, removing its offset.
+fn complete_versionstamp(bytes: &[u8], stamp: &[u8; 10]) -> IVec {
+    let (bytes, offset) = bytes.split_at(bytes.len() - 4);
+    let offset = u32::from_le_bytes(offset.try_into().unwrap()) as usize;
+
+    let mut bytes = bytes.to_vec();
+    bytes[offset..offset + 10].copy_from_slice(stamp);
+
+    bytes.into()
+}
+
+fn apply_range_options<'t, I>(range: I, opts: &RangeOption<'_>) -> SledRange<'t>
+where
+    I: DoubleEndedIterator<Item = InfallibleDbResult<(IVec, IVec)>> + Send + 't,
+{
+    match (opts.limit, opts.reverse) {
+        (None, true) => Box::new(range.rev()),
+        (None, false) => Box::new(range),
+        (Some(n), true) => Box::new(range.rev().take(n)),
+        (Some(n), false) => Box::new(range.take(n)),
     }
 }
 
@@ -126,7 +444,7 @@ mod tests {
         db.insert(b"foo/2", b"2").unwrap();
         db.insert(b"bar/1", b"1").unwrap();
 
-        let mut tx = SledTransaction::new((*db).clone());
+        let mut tx = SledTransaction::new((*db).clone(), &Arc::default());
 
         // Test get from the tree.
         let foo_1 = tx.get(b"foo/1").expect("failed to get foo/1");
@@ -159,10 +477,76 @@ mod tests {
             assert!(range.next().is_none(), "unnexpected element in range");
         }
 
+        // Test range from the tree and the batch.
+        {
+            let mut range = tx.get_range(&RangeOption {
+                begin: KeySelector::first_greater_or_equal(&b"bar/"[..]),
+                end: KeySelector::last_less_than(&b"bar0"[..]),
+                ..Default::default()
+            });
+
+            assert_eq!(
+                range.next().unwrap().unwrap(),
+                (IVec::from("bar/1"), IVec::from(b"1")),
+                "failed to get bar/1 from range"
+            );
+            assert_eq!(
+                range.next().unwrap().unwrap(),
+                (IVec::from("bar/2"), IVec::from(b"2")),
+                "failed to get bar/2 from range"
+            );
+            assert!(range.next().is_none(), "unnexpected element in range");
+        }
+
         // Test remove
         tx.clear(b"foo/2");
         let foo_2 = tx.get(b"foo/2").expect("failed to get foo/2");
         assert_eq!(foo_2, None, "expected foo/2 to be deleted");
+
+        // Test range remove, from both the tree and the batch.
+        tx.set(b"bar/3", b"3");
+        tx.clear_range(b"bar/", b"bar0")
+            .expect("failed to clear bar/");
+        for key in [&b"bar/1"[..], b"bar/2", b"bar/3"] {
+            let value = tx.get(key).expect("failed to get bar/");
+            assert_eq!(value, None, "expected bar/ to be deleted");
+        }
+        let foo_1 = tx.get(b"foo/1").expect("failed to get foo/1");
+        assert_eq!(foo_1, Some(IVec::from("1")), "cleared key out of range");
+    }
+
+    #[test]
+    fn test_range_begin() {
+        let db = temp_db();
+        db.insert(b"foo/1", b"1").unwrap();
+        db.insert(b"foo/2", b"2").unwrap();
+        db.insert(b"foo/3", b"3").unwrap();
+
+        let tx = SledTransaction::new((*db).clone(), &Arc::default());
+        let keys = |begin| {
+            tx.get_range(&RangeOption {
+                begin,
+                end: KeySelector::first_greater_or_equal(&b"foo/3"[..]),
+                ..Default::default()
+            })
+            .map(|res| res.unwrap().0)
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            keys(KeySelector::first_greater_or_equal(&b"foo/1"[..])),
+            [IVec::from("foo/1"), IVec::from("foo/2")],
+            "first_greater_or_equal must include its key"
+        );
+        assert_eq!(
+            keys(KeySelector::first_greater_than(&b"foo/1"[..])),
+            [IVec::from("foo/2")],
+            "first_greater_than must exclude its key"
+        );
+        assert_eq!(
+            keys(KeySelector::first_greater_or_equal(&b"foo/"[..])),
+            [IVec::from("foo/1"), IVec::from("foo/2")],
+        );
     }
 
     #[tokio::test]
@@ -173,12 +557,12 @@ mod tests {
         db.insert(b"foo/2", b"2").unwrap();
         db.insert(b"bar/1", b"1").unwrap();
 
-        let mut tx = SledTransaction::new((*db).clone());
+        let mut tx = SledTransaction::new((*db).clone(), &Arc::default());
 
         tx.set(b"bar/2", b"2");
         tx.clear(b"bar/1");
 
-        tx.commit().await.expect("failed to commit");
+        assert!(tx.commit().await.expect("failed to commit"));
 
         let bar_2 = db.get("bar/2").expect("failed to get bar/2");
         assert_eq!(bar_2, Some(IVec::from(b"2")));
@@ -186,4 +570,159 @@ mod tests {
         let bar_1 = db.get("bar/1").expect("failed to get bar/2");
         assert_eq!(bar_1, None);
     }
+
+    #[tokio::test]
+    async fn test_atomic_add() {
+        let db = temp_db();
+        db.insert(b"count/1", &5i64.to_le_bytes()).unwrap();
+
+        let conflicts = Arc::default();
+        let mut tx1 = SledTransaction::new((*db).clone(), &conflicts);
+        let mut tx2 = SledTransaction::new((*db).clone(), &conflicts);
+
+        tx1.atomic_add(b"count/1", 2);
+        tx1.atomic_add(b"count/2", 1);
+        tx2.atomic_add(b"count/1", 3);
+
+        // Transactions see their own additions.
+        let count_1 = tx1.get(b"count/1").expect("failed to get count/1");
+        assert_eq!(count_1, Some(IVec::from(&7i64.to_le_bytes())));
+
+        let range = tx1
+            .get_range(&RangeOption::from((b"count/".to_vec(), b"count0".to_vec())))
+            .map(|res| res.map(|(_, v)| v))
+            .collect::<InfallibleDbResult<Vec<_>>>()
+            .expect("failed to get count range");
+        assert_eq!(
+            range,
+            vec![
+                IVec::from(&7i64.to_le_bytes()),
+                IVec::from(&1i64.to_le_bytes())
+            ]
+        );
+
+        // Concurrent additions are not lost.
+        assert!(tx1.commit().await.expect("failed to commit"));
+        assert!(tx2.commit().await.expect("failed to commit"));
+
+        let count_1 = db.get(b"count/1").expect("failed to get count/1");
+        assert_eq!(count_1, Some(IVec::from(&10i64.to_le_bytes())));
+    }
+
+    #[tokio::test]
+    async fn test_conflicts() {
+        let db = temp_db();
+        let conflicts = Arc::default();
+        let new_tx = || SledTransaction::new((*db).clone(), &conflicts);
+
+        let mut reader = new_tx();
+        let mut range_reader = new_tx();
+        let mut blind = new_tx();
+
+        reader.get(b"foo/1").expect("failed to get foo/1");
+        reader.set(b"bar/1", b"1");
+        range_reader
+            .get_range(&RangeOption::from((b"foo/".to_vec(), b"foo0".to_vec())))
+            .for_each(drop);
+        range_reader.set(b"bar/2", b"2");
+        blind.set(b"bar/3", b"3");
+
+        let mut writer = new_tx();
+        writer.set(b"foo/1", b"1");
+        assert!(writer.commit().await.expect("failed to commit"));
+
+        // Transactions that read a key written after they started conflict.
+        assert!(!reader.commit().await.expect("failed to commit"));
+        assert!(!range_reader.commit().await.expect("failed to commit"));
+        assert!(blind.commit().await.expect("failed to commit"));
+
+        for (key, expected) in [(b"bar/1", None), (b"bar/2", None), (b"bar/3", Some("3"))] {
+            let value = db.get(key).expect("failed to get bar/");
+            assert_eq!(value, expected.map(IVec::from), "wrong value of {key:?}");
+        }
+
+        // Transactions started after the write see it, and don't conflict.
+        let mut reader = new_tx();
+        reader.get(b"foo/1").expect("failed to get foo/1");
+        reader.set(b"bar/1", b"1");
+        assert!(reader.commit().await.expect("failed to commit"));
+    }
+
+    #[tokio::test]
+    async fn test_versionstamped_key() {
+        let db = temp_db();
+
+        let mut stamps = vec![];
+        for value in [b"1", b"2"] {
+            let mut tx = SledTransaction::new((*db).clone(), &Arc::default());
+
+            // Prefix, incomplete versionstamp, suffix and the offset of the versionstamp.
+            let mut key = b"log/".to_vec();
+            key.extend_from_slice(&[0xFF; 10]);
+            key.extend_from_slice(b"/a");
+            key.extend_from_slice(&4u32.to_le_bytes());
+            tx.set_versionstamped_key(&key, value);
+
+            assert_eq!(
+                tx.get_range(&RangeOption::from((b"log/".to_vec(), b"log0".to_vec())))
+                    .count(),
+                stamps.len(),
+                "versionstamped key visible before commit"
+            );
+
+            assert!(tx.commit().await.expect("failed to commit"));
+
+            let (key, stored) = db.scan_prefix(b"log/").last().unwrap().unwrap();
+            assert_eq!(key.len(), 16);
+            assert_eq!(&key[14..], b"/a");
+            assert_eq!(stored, IVec::from(value));
+            stamps.push(key[4..14].to_vec());
+        }
+
+        assert!(stamps[0] < stamps[1], "versionstamps must increase");
+    }
+
+    #[tokio::test]
+    async fn test_versionstamped_value() {
+        let db = temp_db();
+
+        let mut stamps = vec![];
+        for _ in 0..2 {
+            let mut tx = SledTransaction::new((*db).clone(), &Arc::default());
+
+            // Prefix, incomplete versionstamp and the offset of the versionstamp.
+            let mut value = b"v".to_vec();
+            value.extend_from_slice(&[0xFF; 10]);
+            value.extend_from_slice(&1u32.to_le_bytes());
+            tx.set_versionstamped_value(b"rev", &value);
+
+            assert!(tx.get(b"rev").is_err(), "read incomplete versionstamp");
+
+            assert!(tx.commit().await.expect("failed to commit"));
+
+            let stored = db.get(b"rev").unwrap().unwrap();
+            assert_eq!(stored.len(), 11);
+            assert_eq!(stored[0], b'v');
+            stamps.push(stored[1..].to_vec());
+        }
+
+        assert!(stamps[0] < stamps[1], "versionstamps must increase");
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let db = temp_db();
+
+        let tx = SledTransaction::new((*db).clone(), &Arc::default());
+        let watch = tx.watch(b"signal");
+
+        // Writes to other keys with the same prefix don't resolve the watch.
+        db.insert(b"signal/other", b"1").unwrap();
+        db.insert(b"signal", b"1").unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), watch)
+            .await
+            .expect("watch didn't resolve")
+            .expect("watch failed");
+    }
 }
@@ -295,8 +295,10 @@ def aliases(
 _NORMAL_DEPENDENCIES = {
     "third-party": {
         _COMMON_CONDITION: {
+            "aes-gcm": "@crates_vendor__aes-gcm-0.10.3//:aes_gcm",
             "argh": "@crates_vendor__argh-0.1.12//:argh",
             "bytes": "@crates_vendor__bytes-1.5.0//:bytes",
+            "crc32c": "@crates_vendor__crc32c-0.6.4//:crc32c",
             "foundationdb": "@crates_vendor__foundationdb-0.7.0//:foundationdb",
             "futures-lite": "@crates_vendor__futures-lite-1.13.0//:futures_lite",
             "futures-util": "@crates_vendor__futures-util-0.3.28//:futures_util",
@@ -305,10 +307,12 @@ _NORMAL_DEPENDENCIES = {
             "http": "@crates_vendor__http-0.2.9//:http",
             "http-body": "@crates_vendor__http-body-0.4.5//:http_body",
             "hyper": "@crates_vendor__hyper-0.14.27//:hyper",
+            "lz4_flex": "@crates_vendor__lz4_flex-0.10.0//:lz4_flex",
             "prost": "@crates_vendor__prost-0.12.1//:prost",
             "prost-build": "@crates_vendor__prost-build-0.12.1//:prost_build",
             "prost-reflect": "@crates_vendor__prost-reflect-0.12.0//:prost_reflect",
             "prost-types": "@crates_vendor__prost-types-0.12.1//:prost_types",
+            "rand": "@crates_vendor__rand-0.8.5//:rand",
             "sled": "@crates_vendor__sled-0.34.7//:sled",
             "smallvec": "@crates_vendor__smallvec-1.11.1//:smallvec",
             "tokio": "@crates_vendor__tokio-1.32.0//:tokio",
@@ -318,6 +322,7 @@ _NORMAL_DEPENDENCIES = {
             "tonic-types": "@crates_vendor__tonic-types-0.10.1//:tonic_types",
             "tower": "@crates_vendor__tower-0.4.13//:tower",
             "uuid": "@crates_vendor__uuid-1.4.1//:uuid",
+            "zstd": "@crates_vendor__zstd-0.12.4//:zstd",
         },
     },
 }
@@ -390,6 +395,7 @@ _BUILD_PROC_MACRO_ALIASES = {
 }
 
 _CONDITIONS = {
+    "aarch64-linux-android": [],
     "aarch64-pc-windows-gnullvm": [],
     "aarch64-unknown-linux-gnu": ["@rules_rust//rust/platform:aarch64-unknown-linux-gnu"],
     "arm-unknown-linux-gnueabi": ["@rules_rust//rust/platform:arm-unknown-linux-gnueabi"],
@@ -399,11 +405,14 @@ _CONDITIONS = {
     "cfg(all(not(rustix_use_libc), not(miri), target_os = \"linux\", target_endian = \"little\", any(target_arch = \"arm\", all(target_arch = \"aarch64\", target_pointer_width = \"64\"), target_arch = \"riscv64\", all(rustix_use_experimental_asm, target_arch = \"powerpc64\"), all(rustix_use_experimental_asm, target_arch = \"mips\"), all(rustix_use_experimental_asm, target_arch = \"mips32r6\"), all(rustix_use_experimental_asm, target_arch = \"mips64\"), all(rustix_use_experimental_asm, target_arch = \"mips64r6\"), target_arch = \"x86\", all(target_arch = \"x86_64\", target_pointer_width = \"64\"))))": ["@rules_rust//rust/platform:aarch64-unknown-linux-gnu", "@rules_rust//rust/platform:arm-unknown-linux-gnueabi", "@rules_rust//rust/platform:armv7-unknown-linux-gnueabi", "@rules_rust//rust/platform:i686-unknown-linux-gnu", "@rules_rust//rust/platform:x86_64-unknown-linux-gnu"],
     "cfg(all(not(windows), any(rustix_use_libc, miri, not(all(target_os = \"linux\", target_endian = \"little\", any(target_arch = \"arm\", all(target_arch = \"aarch64\", target_pointer_width = \"64\"), target_arch = \"riscv64\", all(rustix_use_experimental_asm, target_arch = \"powerpc64\"), all(rustix_use_experimental_asm, target_arch = \"mips\"), all(rustix_use_experimental_asm, target_arch = \"mips32r6\"), all(rustix_use_experimental_asm, target_arch = \"mips64\"), all(rustix_use_experimental_asm, target_arch = \"mips64r6\"), target_arch = \"x86\", all(target_arch = \"x86_64\", target_pointer_width = \"64\")))))))": ["@rules_rust//rust/platform:powerpc-unknown-linux-gnu"],
     "cfg(all(target_arch = \"aarch64\", target_env = \"msvc\", not(windows_raw_dylib)))": [],
+    "cfg(all(target_arch = \"aarch64\", target_os = \"linux\"))": ["@rules_rust//rust/platform:aarch64-unknown-linux-gnu"],
+    "cfg(all(target_arch = \"aarch64\", target_vendor = \"apple\"))": [],
     "cfg(all(target_arch = \"wasm32\", not(target_os = \"wasi\")))": [],
     "cfg(all(target_arch = \"x86\", target_env = \"gnu\", not(windows_raw_dylib)))": ["@rules_rust//rust/platform:i686-unknown-linux-gnu"],
     "cfg(all(target_arch = \"x86\", target_env = \"msvc\", not(windows_raw_dylib)))": [],
     "cfg(all(target_arch = \"x86_64\", target_env = \"gnu\", not(target_abi = \"llvm\"), not(windows_raw_dylib)))": ["@rules_rust//rust/platform:x86_64-unknown-linux-gnu"],
     "cfg(all(target_arch = \"x86_64\", target_env = \"msvc\", not(windows_raw_dylib)))": [],
+    "cfg(any(target_arch = \"aarch64\", target_arch = \"x86_64\", target_arch = \"x86\"))": ["@rules_rust//rust/platform:aarch64-unknown-linux-gnu", "@rules_rust//rust/platform:i686-unknown-linux-gnu", "@rules_rust//rust/platform:x86_64-unknown-linux-gnu"],
     "cfg(any(target_os = \"linux\", target_os = \"android\", target_os = \"windows\", target_os = \"macos\", target_os = \"ios\", target_os = \"freebsd\", target_os = \"openbsd\", target_os = \"netbsd\", target_os = \"dragonfly\", target_os = \"solaris\", target_os = \"illumos\", target_os = \"fuchsia\", target_os = \"redox\", target_os = \"cloudabi\", target_os = \"haiku\", target_os = \"vxworks\", target_os = \"emscripten\", target_os = \"wasi\"))": ["@rules_rust//rust/platform:aarch64-unknown-linux-gnu", "@rules_rust//rust/platform:arm-unknown-linux-gnueabi", "@rules_rust//rust/platform:armv7-unknown-linux-gnueabi", "@rules_rust//rust/platform:i686-unknown-linux-gnu", "@rules_rust//rust/platform:powerpc-unknown-linux-gnu", "@rules_rust//rust/platform:x86_64-unknown-linux-gnu"],
     "cfg(any(target_os = \"linux\", target_os = \"macos\", target_os = \"windows\"))": ["@rules_rust//rust/platform:aarch64-unknown-linux-gnu", "@rules_rust//rust/platform:arm-unknown-linux-gnueabi", "@rules_rust//rust/platform:armv7-unknown-linux-gnueabi", "@rules_rust//rust/platform:i686-unknown-linux-gnu", "@rules_rust//rust/platform:powerpc-unknown-linux-gnu", "@rules_rust//rust/platform:x86_64-unknown-linux-gnu"],
     "cfg(any(unix, target_os = \"wasi\"))": ["@rules_rust//rust/platform:aarch64-unknown-linux-gnu", "@rules_rust//rust/platform:arm-unknown-linux-gnueabi", "@rules_rust//rust/platform:armv7-unknown-linux-gnueabi", "@rules_rust//rust/platform:i686-unknown-linux-gnu", "@rules_rust//rust/platform:powerpc-unknown-linux-gnu", "@rules_rust//rust/platform:x86_64-unknown-linux-gnu"],
@@ -451,6 +460,36 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.adler-1.0.2.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__aead-0.5.2",
+        sha256 = "d122413f284cf2d62fb1b7db97e02edb8cda96d769b16e443a4f6195e35662b0",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/aead/0.5.2/download"],
+        strip_prefix = "aead-0.5.2",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.aead-0.5.2.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "crates_vendor__aes-0.8.3",
+        sha256 = "ac1f845298e95f983ff1944b728ae08b8cebab80d684f0a832ed0fc74dfa27e2",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/aes/0.8.3/download"],
+        strip_prefix = "aes-0.8.3",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.aes-0.8.3.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "crates_vendor__aes-gcm-0.10.3",
+        sha256 = "831010a0f742e1209b3bcea8fab6a8e149051ba6099432c8cb2cc117dec3ead1",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/aes-gcm/0.10.3/download"],
+        strip_prefix = "aes-gcm-0.10.3",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.aes-gcm-0.10.3.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__ahash-0.7.6",
@@ -751,6 +790,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.cfg-if-1.0.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__cipher-0.4.4",
+        sha256 = "773f3b9af64447d2ce9850330c473515014aa235e6a783b02db81ff39e4a3dad",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/cipher/0.4.4/download"],
+        strip_prefix = "cipher-0.4.4",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.cipher-0.4.4.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__clang-sys-1.6.1",
@@ -791,6 +840,26 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.concurrent-queue-1.2.4.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__cpufeatures-0.2.9",
+        sha256 = "a17b76ff3a4162b0b27f354a0c87015ddad39d35f9c0c36607a3bdd175dde1f1",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/cpufeatures/0.2.9/download"],
+        strip_prefix = "cpufeatures-0.2.9",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.cpufeatures-0.2.9.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "crates_vendor__crc32c-0.6.4",
+        sha256 = "d8f48d60e5b4d2c53d5c2b1d8a58c849a70ae5e5509b08a48d047e3b65714a74",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/crc32c/0.6.4/download"],
+        strip_prefix = "crc32c-0.6.4",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.crc32c-0.6.4.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__crc32fast-1.3.2",
@@ -861,6 +930,26 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.crossbeam-utils-0.8.16.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__crypto-common-0.1.6",
+        sha256 = "1bfb12502f3fc46cca1bb51ac28df9d618d813cdc3d2f25b9fe775a34af26bb3",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/crypto-common/0.1.6/download"],
+        strip_prefix = "crypto-common-0.1.6",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.crypto-common-0.1.6.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "crates_vendor__ctr-0.9.2",
+        sha256 = "0369ee1ad671834580515889b80f2ea915f23b8be8d0daa4bbaf2ac5c7590835",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/ctr/0.9.2/download"],
+        strip_prefix = "ctr-0.9.2",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.ctr-0.9.2.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__difflib-0.4.0",
@@ -1171,6 +1260,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.fxhash-0.2.1.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__generic-array-0.14.7",
+        sha256 = "85649ca51fd72272d7821adaf274ad91c288277713d9c18820d8499a7ff69e9a",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/generic-array/0.14.7/download"],
+        strip_prefix = "generic-array-0.14.7",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.generic-array-0.14.7.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__getrandom-0.2.10",
@@ -1181,6 +1280,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.getrandom-0.2.10.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__ghash-0.5.0",
+        sha256 = "d930750de5717d2dd0b8c0d42c076c0e884c81a73e6cab859bbd2339c71e3e40",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/ghash/0.5.0/download"],
+        strip_prefix = "ghash-0.5.0",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.ghash-0.5.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__gimli-0.28.0",
@@ -1361,6 +1470,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.indexmap-2.0.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__inout-0.1.3",
+        sha256 = "a0c10553d664a4d0bcff9f4215d0aac67a639cc68ef660840afe309b807bc9f5",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/inout/0.1.3/download"],
+        strip_prefix = "inout-0.1.3",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.inout-0.1.3.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__instant-0.1.12",
@@ -1411,6 +1530,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.itoa-1.0.9.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__jobserver-0.1.26",
+        sha256 = "936cfd212a0155903bcbc060e316fb6cc7cbf2e1907329391ebadc1fe0ce77c2",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/jobserver/0.1.26/download"],
+        strip_prefix = "jobserver-0.1.26",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.jobserver-0.1.26.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__js-sys-0.3.64",
@@ -1531,6 +1660,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.logos-derive-0.13.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__lz4_flex-0.10.0",
+        sha256 = "8b8c72594ac26bfd34f2d99dfced2edfaddfe8a476e3ff2ca0eb293d925c4f83",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/lz4_flex/0.10.0/download"],
+        strip_prefix = "lz4_flex-0.10.0",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.lz4_flex-0.10.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__matchit-0.7.3",
@@ -1711,6 +1850,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.once_cell-1.18.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__opaque-debug-0.3.0",
+        sha256 = "624a8340c38c1b80fd549087862da4ba43e08858af025b236e509b6649fc13d5",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/opaque-debug/0.3.0/download"],
+        strip_prefix = "opaque-debug-0.3.0",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.opaque-debug-0.3.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__os_str_bytes-6.5.1",
@@ -1831,6 +1980,26 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.pin-utils-0.1.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__pkg-config-0.3.27",
+        sha256 = "26072860ba924cbfa98ea39c8c19b4dd6a4a25423dbdf219c1eca91aa0cf6964",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/pkg-config/0.3.27/download"],
+        strip_prefix = "pkg-config-0.3.27",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.pkg-config-0.3.27.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "crates_vendor__polyval-0.6.1",
+        sha256 = "d52cff9d1d4dee5fe6d03729099f4a310a41179e0a10dbf542039873f2e826fb",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/polyval/0.6.1/download"],
+        strip_prefix = "polyval-0.6.1",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.polyval-0.6.1.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__ppv-lite86-0.2.17",
@@ -2071,6 +2240,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.rustc-hash-1.1.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__rustc_version-0.4.0",
+        sha256 = "bfa0f585226d2e68097d4f95d113b15b83a82e819ab25717ec0590d9584ef366",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/rustc_version/0.4.0/download"],
+        strip_prefix = "rustc_version-0.4.0",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.rustc_version-0.4.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__rustix-0.38.14",
@@ -2111,6 +2290,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.scopeguard-1.2.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__semver-1.0.19",
+        sha256 = "ad977052201c6de01a8ef2aa3378c4bd23217a056337d1d6da40468d267a4fb0",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/semver/1.0.19/download"],
+        strip_prefix = "semver-1.0.19",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.semver-1.0.19.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__serde-1.0.188",
@@ -2251,6 +2440,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.strsim-0.10.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__subtle-2.5.0",
+        sha256 = "81cdd64d312baedb58e21336b31bc043b77e01cc99033ce76ef539f78e965ebc",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/subtle/2.5.0/download"],
+        strip_prefix = "subtle-2.5.0",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.subtle-2.5.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__syn-1.0.109",
@@ -2481,6 +2680,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.try-lock-0.2.4.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__twox-hash-1.6.3",
+        sha256 = "97fee6b57c6a41524a810daee9286c02d7752c4253064d0b05472833a438f675",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/twox-hash/1.6.3/download"],
+        strip_prefix = "twox-hash-1.6.3",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.twox-hash-1.6.3.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__typenum-1.17.0",
@@ -2501,6 +2710,16 @@ def crate_repositories():
         build_file = Label("@patch-labs//third-party/crates:BUILD.unicode-ident-1.0.12.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "crates_vendor__universal-hash-0.5.1",
+        sha256 = "fc1de2c688dc15305988b563c3854064043356019f97a4b46276fe734c4f07ea",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/universal-hash/0.5.1/download"],
+        strip_prefix = "universal-hash-0.5.1",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.universal-hash-0.5.1.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "crates_vendor__uuid-1.4.1",
@@ -2750,3 +2969,33 @@ def crate_repositories():
         strip_prefix = "xml-rs-0.8.18",
         build_file = Label("@patch-labs//third-party/crates:BUILD.xml-rs-0.8.18.bazel"),
     )
+
+    maybe(
+        http_archive,
+        name = "crates_vendor__zstd-0.12.4",
+        sha256 = "1a27595e173641171fc74a1232b7b1c7a7cb6e18222c11e9dfb9888fa424c53c",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/zstd/0.12.4/download"],
+        strip_prefix = "zstd-0.12.4",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.zstd-0.12.4.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "crates_vendor__zstd-safe-6.0.6",
+        sha256 = "ee98ffd0b48ee95e6c5168188e44a54550b1564d9d530ee21d5f0eaed1069581",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/zstd-safe/6.0.6/download"],
+        strip_prefix = "zstd-safe-6.0.6",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.zstd-safe-6.0.6.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "crates_vendor__zstd-sys-2.0.8-zstd.1.5.5",
+        sha256 = "5556e6ee25d32df2586c098bbfa278803692a20d0ab9565e049480d52707ec8c",
+        type = "tar.gz",
+        urls = ["https://crates.io/api/v1/crates/zstd-sys/2.0.8+zstd.1.5.5/download"],
+        strip_prefix = "zstd-sys-2.0.8+zstd.1.5.5",
+        build_file = Label("@patch-labs//third-party/crates:BUILD.zstd-sys-2.0.8+zstd.1.5.5.bazel"),
+    )